@@ -49,17 +49,19 @@ pub async fn get_operator_range_by_seed(
     Ok(_0)
 }
 
+/// The range `[start, end)` of an operator.
 pub async fn get_range_by_address(
     contract: OperatorRangeContract,
     query_addr: Address,
-) -> Result<u64> {
+) -> Result<(u128, u128)> {
     let OperatorRangeManager::operatorRangesReturn { start, end } =
         contract.operatorRanges(query_addr).call().await?;
 
-    debug!("Operator {:?} ragnes: {:?}-{:?}", query_addr, start, end);
-    let threshold = start - end;
-    let th: u64 = threshold.try_into()?;
-    Ok(th)
+    debug!("Operator {:?} ranges: {:?}-{:?}", query_addr, start, end);
+    if end < start {
+        eyre::bail!("operator range end {end} before start {start}");
+    }
+    Ok((start.try_into()?, end.try_into()?))
 }
 
 
@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+
+use der::{Decode as _, Encode as _};
+use p384::ecdsa::{signature::Verifier as _, DerSignature, Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use x509_cert::Certificate;
+
+/// COSE algorithm identifier of ECDSA w/ SHA-384, the only one NSM signs with.
+pub const COSE_ALG_ES384: i64 = -35;
+
+/// `ecdsa-with-SHA384`, used by every certificate of the nitro attestation PKI.
+const ECDSA_WITH_SHA384: der::asn1::ObjectIdentifier =
+    der::asn1::ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.3");
+
+/// Attestation document as emitted by the nitro secure module, mirrors
+/// `aws_nitro_enclaves_nsm_api::api::AttestationDoc` field by field so that the same CBOR payload
+/// decodes into either of them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttestationDoc {
+    pub module_id: String,
+    pub digest: String,
+    pub timestamp: u64,
+    pub pcrs: BTreeMap<usize, ByteBuf>,
+    pub certificate: ByteBuf,
+    pub cabundle: Vec<ByteBuf>,
+    pub public_key: Option<ByteBuf>,
+    pub user_data: Option<ByteBuf>,
+    pub nonce: Option<ByteBuf>,
+}
+
+/// Untagged COSE_Sign1 structure: protected header, unprotected header, payload, signature.
+type CoseSign1 = (ByteBuf, BTreeMap<i64, serde_cbor::Value>, ByteBuf, ByteBuf);
+
+fn protected_header() -> anyhow::Result<Vec<u8>> {
+    // header label 1 = alg
+    Ok(serde_cbor::to_vec(&BTreeMap::from([(
+        1i64,
+        COSE_ALG_ES384,
+    )]))?)
+}
+
+/// The `Sig_structure` of RFC 8152 section 4.4 that is actually signed.
+fn sig_structure(protected: &[u8], payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+    Ok(serde_cbor::to_vec(&(
+        "Signature1",
+        ByteBuf::from(protected),
+        ByteBuf::new(),
+        ByteBuf::from(payload),
+    ))?)
+}
+
+/// Wraps `doc` into a COSE_Sign1 envelope signed by `signing_key`, which is expected to be the key
+/// of `doc.certificate`.
+pub fn sign_document(
+    doc: &AttestationDoc,
+    signing_key: &p384::ecdsa::SigningKey,
+) -> anyhow::Result<Vec<u8>> {
+    use p384::ecdsa::signature::Signer as _;
+    let protected = protected_header()?;
+    let payload = serde_cbor::to_vec(doc)?;
+    let signature: Signature = signing_key.sign(&sig_structure(&protected, &payload)?);
+    let sign1: CoseSign1 = (
+        ByteBuf::from(protected),
+        BTreeMap::new(),
+        ByteBuf::from(payload),
+        ByteBuf::from(signature.to_bytes().to_vec()),
+    );
+    Ok(serde_cbor::to_vec(&sign1)?)
+}
+
+fn verifying_key(cert: &Certificate) -> anyhow::Result<VerifyingKey> {
+    let spki = &cert.tbs_certificate.subject_public_key_info;
+    let Some(key) = spki.subject_public_key.as_bytes() else {
+        anyhow::bail!("malformed subject public key")
+    };
+    Ok(VerifyingKey::from_sec1_bytes(key)?)
+}
+
+fn verify_issued_by(cert: &Certificate, issuer: &Certificate) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        cert.signature_algorithm.oid == ECDSA_WITH_SHA384,
+        "unsupported certificate signature algorithm {}",
+        cert.signature_algorithm.oid
+    );
+    anyhow::ensure!(
+        cert.tbs_certificate.issuer == issuer.tbs_certificate.subject,
+        "certificate issuer mismatch"
+    );
+    let Some(signature) = cert.signature.as_bytes() else {
+        anyhow::bail!("malformed certificate signature")
+    };
+    let signature = DerSignature::from_bytes(signature)?;
+    verifying_key(issuer)?.verify(&cert.tbs_certificate.to_der()?, &signature)?;
+    Ok(())
+}
+
+fn verify_validity(cert: &Certificate, now: u64) -> anyhow::Result<()> {
+    let validity = &cert.tbs_certificate.validity;
+    let (not_before, not_after) = (
+        validity.not_before.to_unix_duration().as_secs(),
+        validity.not_after.to_unix_duration().as_secs(),
+    );
+    anyhow::ensure!(
+        (not_before..=not_after).contains(&now),
+        "certificate not valid at {now}, validity {not_before}..={not_after}"
+    );
+    Ok(())
+}
+
+/// Parses a COSE_Sign1 attestation document, validates its certificate chain up to `root_cert`
+/// (DER) at unix time `now` in seconds, and checks the document signature.
+///
+/// `root_cert` is `AWS_ROOT_CERT` for documents coming out of a real enclave, or the
+/// `MockSecureModule::root_certificate` of the mock module that produced them.
+pub fn verify_document(
+    document: &[u8],
+    root_cert: &[u8],
+    now: u64,
+) -> anyhow::Result<AttestationDoc> {
+    let (protected, _, payload, signature) = serde_cbor::from_slice::<CoseSign1>(document)?;
+    let header = serde_cbor::from_slice::<BTreeMap<i64, serde_cbor::Value>>(&protected)?;
+    anyhow::ensure!(
+        header.get(&1) == Some(&serde_cbor::Value::Integer(COSE_ALG_ES384.into())),
+        "unsupported COSE algorithm"
+    );
+    let doc = serde_cbor::from_slice::<AttestationDoc>(&payload)?;
+
+    let root = Certificate::from_der(root_cert)?;
+    let mut chain = doc
+        .cabundle
+        .iter()
+        .map(|cert| Certificate::from_der(cert))
+        .collect::<Result<Vec<_>, _>>()?;
+    anyhow::ensure!(!chain.is_empty(), "empty cabundle");
+    // the bundle starts with the root itself, which is trusted by identity rather than signature
+    anyhow::ensure!(
+        chain.remove(0) == root,
+        "cabundle does not start with the trusted root"
+    );
+    chain.push(Certificate::from_der(&doc.certificate)?);
+    verify_validity(&root, now)?;
+    let mut issuer = &root;
+    for cert in &chain {
+        verify_issued_by(cert, issuer)?;
+        verify_validity(cert, now)?;
+        issuer = cert
+    }
+
+    let signature = Signature::from_slice(&signature)?;
+    verifying_key(issuer)?.verify(&sig_structure(&protected, &payload)?, &signature)?;
+    Ok(doc)
+}
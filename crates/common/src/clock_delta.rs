@@ -0,0 +1,193 @@
+//! Compact encoding of `OrdinaryClock`s of many keys, for the wire to the clock enclave.
+//!
+//! A clock is sent as the entries it changed from a base clock the receiver has already, named by
+//! its SHA-256, the one the enclave attests. The entries are LEB128 varints: their count, then per
+//! entry the key, as the gap from the previous key minus one, and the value. A clock of keys
+//! `0..n` takes two bytes per entry as long as its values are below 128, and a tick of it one
+//! entry.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crypto::canonical::CanonicalDigest as _,
+    ordinary_clock::{KeyId, OrdinaryClock},
+};
+
+/// A clock as the entries changed from the clock of SHA-256 `base`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClockDelta {
+    pub base: [u8; 32],
+    #[serde(with = "serde_bytes")]
+    pub entries: Vec<u8>,
+}
+
+/// The base of a clock sent in full.
+pub fn genesis_hash() -> [u8; 32] {
+    OrdinaryClock::default().canonical_digest()
+}
+
+impl ClockDelta {
+    /// The entries of `clock` that differ from `base`. `None` if `clock` lacks a key of `base`,
+    /// which no update of `base` does.
+    pub fn new(clock: &OrdinaryClock, base: &OrdinaryClock) -> Option<Self> {
+        if base.0.keys().any(|id| !clock.0.contains_key(id)) {
+            return None;
+        }
+        let changed = clock
+            .0
+            .iter()
+            .filter(|(id, n)| base.0.get(id) != Some(n))
+            .map(|(id, n)| (*id, *n))
+            .collect::<Vec<_>>();
+        Some(Self {
+            base: base.canonical_digest(),
+            entries: encode_entries(changed.into_iter()),
+        })
+    }
+
+    /// All of `clock`, from the genesis clock.
+    pub fn full(clock: &OrdinaryClock) -> Self {
+        Self {
+            base: genesis_hash(),
+            entries: encode_entries(clock.0.iter().map(|(id, n)| (*id, *n))),
+        }
+    }
+
+    /// Whether the clock is its base.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() || self.entries == [0]
+    }
+
+    /// The clock, given `base` of hash `self.base`, which is up to the caller to look up.
+    pub fn apply(&self, base: &OrdinaryClock) -> anyhow::Result<OrdinaryClock> {
+        let mut clock = base.clone();
+        clock.0.extend(decode_entries(&self.entries)?);
+        Ok(clock)
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8)
+}
+
+fn read_varint(bytes: &mut &[u8]) -> anyhow::Result<u64> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        let Some((byte, rest)) = bytes.split_first() else {
+            anyhow::bail!("truncated varint")
+        };
+        *bytes = rest;
+        let bits = u64::from(byte & 0x7f);
+        anyhow::ensure!(bits << shift >> shift == bits, "varint overflows");
+        n |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    anyhow::bail!("varint too long")
+}
+
+/// Encodes entries in strictly increasing key order, e.g. of a `BTreeMap`.
+pub fn encode_entries(entries: impl ExactSizeIterator<Item = (KeyId, u32)>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(1 + 2 * entries.len());
+    write_varint(&mut buf, entries.len() as _);
+    let mut prev = None;
+    for (id, n) in entries {
+        let gap = match prev {
+            Some(prev) => {
+                debug_assert!(id > prev);
+                id - prev - 1
+            }
+            None => id,
+        };
+        write_varint(&mut buf, gap);
+        write_varint(&mut buf, n.into());
+        prev = Some(id)
+    }
+    buf
+}
+
+pub fn decode_entries(mut bytes: &[u8]) -> anyhow::Result<Vec<(KeyId, u32)>> {
+    if bytes.is_empty() {
+        return Ok(Vec::new());
+    }
+    let len = read_varint(&mut bytes)?;
+    // at least two bytes an entry, before allocating for all of them
+    anyhow::ensure!(len <= bytes.len() as u64 / 2, "more entries than bytes");
+    let mut entries = Vec::with_capacity(len as _);
+    let mut prev: Option<KeyId> = None;
+    for _ in 0..len {
+        let gap = read_varint(&mut bytes)?;
+        let id = match prev {
+            Some(prev) => prev
+                .checked_add(gap)
+                .and_then(|id| id.checked_add(1))
+                .ok_or(anyhow::anyhow!("key overflows"))?,
+            None => gap,
+        };
+        let n = u32::try_from(read_varint(&mut bytes)?)?;
+        entries.push((id, n));
+        prev = Some(id)
+    }
+    anyhow::ensure!(bytes.is_empty(), "trailing bytes");
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(entries: impl IntoIterator<Item = (KeyId, u32)>) -> OrdinaryClock {
+        OrdinaryClock(entries.into_iter().collect())
+    }
+
+    #[test]
+    fn delta_of_update() -> anyhow::Result<()> {
+        let base = clock((0..1 << 16).map(|id| (id, 0)));
+        let ticked = base.update([clock([(7, 3), (1 << 20, 1)])].iter(), 9);
+
+        let delta = ClockDelta::new(&ticked, &base).unwrap();
+        assert_eq!(delta.base, base.canonical_digest());
+        assert_eq!(
+            decode_entries(&delta.entries)?,
+            [(7, 3), (9, 1), (1 << 20, 1)]
+        );
+        assert_eq!(delta.apply(&base)?, ticked);
+        assert!(ClockDelta::new(&ticked, &ticked).unwrap().is_empty());
+        assert!(ClockDelta::new(&base, &ticked).is_none());
+
+        let full = ClockDelta::full(&base);
+        assert_eq!(full.base, genesis_hash());
+        assert_eq!(full.entries.len(), 3 + 2 * (1 << 16));
+        assert_eq!(full.apply(&OrdinaryClock::default())?, base);
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_entries() {
+        let entries = encode_entries([(0, 1), (u64::MAX, u32::MAX)].into_iter());
+        assert_eq!(
+            decode_entries(&entries).unwrap(),
+            [(0, 1), (u64::MAX, u32::MAX)]
+        );
+        assert!(decode_entries(&entries[..entries.len() - 1]).is_err());
+        assert!(decode_entries(&[entries.as_slice(), &[0]].concat()).is_err());
+        // a value past u32, a key past u64, and a count of more entries than bytes
+        assert!(decode_entries(&encode_u64s(&[1, 0, 1 << 32])).is_err());
+        assert!(decode_entries(&encode_u64s(&[2, u64::MAX, 0, 0, 0])).is_err());
+        assert!(decode_entries(&encode_u64s(&[1 << 40])).is_err());
+        assert!(decode_entries(&[0xff; 11]).is_err());
+    }
+
+    fn encode_u64s(ns: &[u64]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for n in ns {
+            write_varint(&mut buf, *n)
+        }
+        buf
+    }
+}
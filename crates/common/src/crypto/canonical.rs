@@ -0,0 +1,89 @@
+//! Documented encodings of what is hashed for attestation and signing, for verifiers outside of
+//! Rust to reproduce the digests. `DigestHash` goes through `std::hash::Hash`, whose output is
+//! neither specified nor stable across Rust versions and platforms, so it should not be used for
+//! anything that leaves the process.
+//!
+//! Two layouts are in use, and the SHA-256 of the encoding is the digest in both:
+//!
+//! * `OrdinaryClock`: the number of entries as u64, then every entry in increasing key order as
+//!   the key as u64 followed by the value as u32, all little endian. The empty clock is the
+//!   eight zero bytes of its count.
+//! * Everything else, e.g. `InferenceCommitment`, `PromptReq`, `AnswerResp` or the chat messages
+//!   of a question's `prompt_hash`: `bincode::options()` of the serde representation. Struct
+//!   fields and tuple members are encoded in declaration order without names. Unsigned integers, `usize` included, are varints: one byte
+//!   below 251, otherwise the byte 251, 252, 253 or 254 followed by the value as a little endian
+//!   u16, u32, u64 or u128. Signed integers are zigzag encoded first. Lengths of strings, byte
+//!   arrays, sequences and maps are varints followed by the items, strings as UTF-8. `bool` and the
+//!   tag of `Option` (0 for `None`, 1 followed by the value) are one byte, the variant of an enum
+//!   is a varint of its index followed by its fields, and floats are their IEEE 754 bits in little
+//!   endian.
+//!
+//! The operator signs the `TEECredential` of `verifier` it calls answers back with over the
+//! SHA-256 of its `tee_attestation`, the base64 of the document, taken as bytes as is.
+//!
+//! Test vectors are in `crates/common/fixtures/canonical_clocks.json`,
+//! `crates/llm_types/fixtures/canonical.json` and `crates/verifier/fixtures/tee_credentials.json`,
+//! as the JSON of the value, the hex of its encoding and the hex of its digest.
+
+use bincode::Options as _;
+use serde::Serialize;
+use sha2::{Digest as _, Sha256};
+
+pub trait CanonicalDigest {
+    /// Appends the canonical encoding of `self` to `buf`.
+    fn encode_canonical(&self, buf: &mut Vec<u8>);
+
+    fn canonical_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_canonical(&mut buf);
+        buf
+    }
+
+    /// SHA-256 of the canonical encoding.
+    fn canonical_digest(&self) -> [u8; 32] {
+        Sha256::digest(self.canonical_bytes()).into()
+    }
+}
+
+/// The `bincode::options()` layout, for `CanonicalDigest` of serde types.
+pub fn encode_bincode<T: Serialize + ?Sized>(value: &T, buf: &mut Vec<u8>) {
+    // only fails for sequences of unknown length, which derived `Serialize` does not produce
+    bincode::options()
+        .serialize_into(buf, value)
+        .expect("canonical encoding")
+}
+
+/// SHA-256 of the `bincode::options()` layout, for values without a type of their own, e.g. a
+/// tuple of what is signed.
+pub fn bincode_digest<T: Serialize + ?Sized>(value: &T) -> [u8; 32] {
+    let mut buf = Vec::new();
+    encode_bincode(value, &mut buf);
+    Sha256::digest(buf).into()
+}
+
+/// A test vector of a fixture file.
+#[derive(Debug, serde::Deserialize)]
+pub struct Fixture<T> {
+    pub name: String,
+    pub value: T,
+    /// Hex canonical encoding of `value`.
+    pub encoding: String,
+    /// Hex canonical digest of `value`.
+    pub digest: String,
+}
+
+impl<T: CanonicalDigest> Fixture<T> {
+    pub fn check(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            hex::encode(self.value.canonical_bytes()) == self.encoding,
+            "encoding of {} does not match",
+            self.name
+        );
+        anyhow::ensure!(
+            hex::encode(self.value.canonical_digest()) == self.digest,
+            "digest of {} does not match",
+            self.name
+        );
+        Ok(())
+    }
+}
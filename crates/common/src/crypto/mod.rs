@@ -1,2 +1,3 @@
+pub mod canonical;
 pub mod core;
 pub mod recovery;
\ No newline at end of file
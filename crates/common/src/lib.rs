@@ -1,7 +1,12 @@
 pub mod types;
 pub mod ordinary_clock;
+pub mod clock_delta;
 pub mod crypto;
 pub mod nitro_secure;
+pub mod mock_secure;
+pub mod attestation;
+pub mod pcr_policy;
+pub mod transport;
 
 pub fn add(left: usize, right: usize) -> usize {
     left + right
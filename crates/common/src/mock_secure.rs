@@ -0,0 +1,179 @@
+use std::{collections::BTreeMap, str::FromStr, time::Duration};
+
+use der::Encode as _;
+use p384::ecdsa::{DerSignature, SigningKey};
+use rand::rngs::OsRng;
+use serde_bytes::ByteBuf;
+use x509_cert::{
+    builder::{Builder as _, CertificateBuilder, Profile},
+    name::Name,
+    serial_number::SerialNumber,
+    spki::SubjectPublicKeyInfoOwned,
+    time::Validity,
+};
+
+use crate::{
+    attestation::{sign_document, AttestationDoc},
+    nitro_secure::SecureModule,
+};
+
+/// Length of a PCR value, NSM measures with SHA-384.
+pub const PCR_LENGTH: usize = 48;
+
+const VALIDITY: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// Software stand-in of the nitro secure module, for running enclave handlers outside of an
+/// enclave (tests, local development).
+///
+/// Every instance generates its own throwaway CA, and signs attestation documents with a leaf
+/// certificate issued by it. Documents verify against `root_certificate()` instead of the AWS
+/// root, so they are worthless outside of the process that trusts this exact instance.
+#[derive(Debug)]
+pub struct MockSecureModule {
+    signing_key: SigningKey,
+    certificate: Vec<u8>,
+    root_certificate: Vec<u8>,
+    pcrs: BTreeMap<u16, Vec<u8>>,
+}
+
+impl MockSecureModule {
+    /// A mock module with PCR0-2 all zero, like an enclave run in debug mode.
+    pub fn new() -> anyhow::Result<Self> {
+        Self::with_pcrs((0..3).map(|index| (index, vec![0; PCR_LENGTH])))
+    }
+
+    pub fn with_pcrs(pcrs: impl IntoIterator<Item = (u16, Vec<u8>)>) -> anyhow::Result<Self> {
+        let root_key = SigningKey::random(&mut OsRng);
+        let root_name = Name::from_str("CN=mock.nitro-enclaves")?;
+        let root = CertificateBuilder::new(
+            Profile::Root,
+            SerialNumber::from(1u32),
+            Validity::from_now(VALIDITY)?,
+            root_name.clone(),
+            SubjectPublicKeyInfoOwned::from_key(*root_key.verifying_key())?,
+            &root_key,
+        )?
+        .build::<DerSignature>()?;
+
+        let signing_key = SigningKey::random(&mut OsRng);
+        let certificate = CertificateBuilder::new(
+            Profile::Leaf {
+                issuer: root_name,
+                enable_key_agreement: false,
+                enable_key_encipherment: false,
+            },
+            SerialNumber::from(2u32),
+            Validity::from_now(VALIDITY)?,
+            Name::from_str("CN=mock-enclave.nitro-enclaves")?,
+            SubjectPublicKeyInfoOwned::from_key(*signing_key.verifying_key())?,
+            &root_key,
+        )?
+        .build::<DerSignature>()?;
+
+        Ok(Self {
+            signing_key,
+            certificate: certificate.to_der()?,
+            root_certificate: root.to_der()?,
+            pcrs: pcrs.into_iter().collect(),
+        })
+    }
+}
+
+impl MockSecureModule {
+    fn attest(&self, user_data: Vec<u8>, public_key: Option<Vec<u8>>) -> anyhow::Result<Vec<u8>> {
+        let doc = AttestationDoc {
+            module_id: "mock-enclave".into(),
+            digest: "SHA384".into(),
+            timestamp: std::time::SystemTime::UNIX_EPOCH.elapsed()?.as_millis() as _,
+            pcrs: self
+                .pcrs
+                .iter()
+                .map(|(index, pcr)| (*index as _, ByteBuf::from(pcr.clone())))
+                .collect(),
+            certificate: ByteBuf::from(self.certificate.clone()),
+            cabundle: vec![ByteBuf::from(self.root_certificate.clone())],
+            public_key: public_key.map(ByteBuf::from),
+            user_data: Some(ByteBuf::from(user_data)),
+            nonce: None,
+        };
+        sign_document(&doc, &self.signing_key)
+    }
+}
+
+impl SecureModule for MockSecureModule {
+    fn process_attestation(&self, user_data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.attest(user_data, None)
+    }
+
+    fn process_key_attestation(
+        &self,
+        user_data: Vec<u8>,
+        public_key: Vec<u8>,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.attest(user_data, Some(public_key))
+    }
+
+    fn describe_pcr(&self, index: u16) -> anyhow::Result<Vec<u8>> {
+        self.pcrs
+            .get(&index)
+            .cloned()
+            .ok_or(anyhow::format_err!("PCR{index} is not configured"))
+    }
+
+    fn root_certificate(&self) -> &[u8] {
+        &self.root_certificate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation::verify_document;
+
+    fn now() -> u64 {
+        std::time::SystemTime::UNIX_EPOCH
+            .elapsed()
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn attest_and_verify() -> anyhow::Result<()> {
+        let pcr0 = vec![0x42; PCR_LENGTH];
+        let nsm = MockSecureModule::with_pcrs([(0, pcr0.clone())])?;
+        let document = nsm.process_attestation(b"hello".to_vec())?;
+        let doc = verify_document(&document, nsm.root_certificate(), now())?;
+        assert_eq!(
+            doc.user_data.as_deref().map(|data| &data[..]),
+            Some(&b"hello"[..])
+        );
+        assert_eq!(doc.pcrs.get(&0).map(|pcr| pcr.to_vec()), Some(pcr0));
+        assert_eq!(nsm.describe_pcr(0)?, vec![0x42; PCR_LENGTH]);
+        assert!(nsm.describe_pcr(1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_foreign_root() -> anyhow::Result<()> {
+        let nsm = MockSecureModule::new()?;
+        let other = MockSecureModule::new()?;
+        let document = nsm.process_attestation(Default::default())?;
+        assert!(verify_document(&document, other.root_certificate(), now()).is_err());
+        // certificates are not valid yet
+        assert!(verify_document(&document, nsm.root_certificate(), 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_tampered_payload() -> anyhow::Result<()> {
+        let nsm = MockSecureModule::new()?;
+        let mut document = nsm.process_attestation(b"hello".to_vec())?;
+        let offset = document
+            .windows(5)
+            .position(|window| window == b"hello")
+            .unwrap();
+        document[offset] = b'j';
+        assert!(verify_document(&document, nsm.root_certificate(), now()).is_err());
+        Ok(())
+    }
+}
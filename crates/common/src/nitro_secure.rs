@@ -3,12 +3,14 @@ use std::{future::Future, pin::Pin};
 use tokio::sync::mpsc::UnboundedSender;
 use tracing::*;
 
+use crate::transport::{read_frame, write_frame, Address, Listener};
+
 /// HandleCallbackFn is running handler behind in vsock.
 /// params: input_buf, nsm (nitro secure module), pcrs, write_sender(reply sender)
 pub type HandleFn = Arc<
     dyn Fn(
             Vec<u8>,
-            Arc<NitroSecureModule>,
+            Arc<dyn SecureModule>,
             [Vec<u8>; 3],
             UnboundedSender<Vec<u8>>,
         ) -> Pin<Box<dyn Future<Output = Result<(), anyhow::Error>> + Send>>
@@ -16,6 +18,26 @@ pub type HandleFn = Arc<
         + Sync,
 >;
 
+/// Attestation backend of an enclave service. `NitroSecureModule` talks to the real `/dev/nsm`,
+/// `MockSecureModule` signs with a local test CA so handlers also run on ordinary machines.
+pub trait SecureModule: std::fmt::Debug + Send + Sync + 'static {
+    /// Returns a COSE_Sign1 attestation document whose `user_data` is the given bytes.
+    fn process_attestation(&self, user_data: Vec<u8>) -> anyhow::Result<Vec<u8>>;
+
+    /// Same as `process_attestation`, with `public_key` as the `public_key` of the document, to
+    /// vouch for a key generated inside the enclave.
+    fn process_key_attestation(
+        &self,
+        user_data: Vec<u8>,
+        public_key: Vec<u8>,
+    ) -> anyhow::Result<Vec<u8>>;
+
+    fn describe_pcr(&self, index: u16) -> anyhow::Result<Vec<u8>>;
+
+    /// DER encoded root certificate that the attestation documents of this module chain up to.
+    fn root_certificate(&self) -> &[u8];
+}
+
 #[derive(Debug)]
 pub struct NitroSecureModule(pub i32);
 
@@ -27,28 +49,117 @@ impl NitroSecureModule {
         Ok(Self(fd))
     }
 
-    pub fn process_attestation(&self, user_data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    pub async fn run(addr: Address, handler: HandleFn) -> anyhow::Result<()> {
+        serve(Arc::new(Self::new()?), addr, handler).await
+    }
+}
+
+/// Accepts connections on `addr` and feeds every length-prefixed request to `handler`,
+/// attesting with `nsm`.
+pub async fn serve(
+    nsm: Arc<dyn SecureModule>,
+    addr: Address,
+    handler: HandleFn,
+) -> anyhow::Result<()> {
+    use tokio::sync::mpsc::unbounded_channel;
+
+    let pcrs = [
+        nsm.describe_pcr(0)?,
+        nsm.describe_pcr(1)?,
+        nsm.describe_pcr(2)?,
+    ];
+
+    let listener = Listener::bind(&addr).await?;
+    info!("listening on {addr}");
+    loop {
+        let (mut read_half, mut write_half) = listener.accept().await?.into_split();
+        let (write_sender, mut write_receiver) = unbounded_channel::<Vec<_>>();
+
+        let mut write_session = tokio::spawn(async move {
+            while let Some(buf) = write_receiver.recv().await {
+                write_frame(&mut write_half, &buf).await?
+            }
+            anyhow::Ok(())
+        });
+        let nsm = nsm.clone();
+        let pcrs = pcrs.clone();
+        let handler = handler.clone();
+        let mut read_session = tokio::spawn(async move {
+            loop {
+                let buf = match read_frame(&mut read_half).await {
+                    Ok(buf) => buf,
+                    Err(err) => {
+                        warn!("{err}");
+                        return anyhow::Ok(());
+                    }
+                };
+                let nsm_clone = nsm.clone();
+                let pcrs_clone = pcrs.clone();
+                let write_sender = write_sender.clone();
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handler(buf, nsm_clone, pcrs_clone, write_sender).await {
+                        eprintln!("Error: {:?}", err);
+                    }
+                });
+            }
+        });
+        loop {
+            let result = tokio::select! {
+                result = &mut read_session, if !read_session.is_finished() => result,
+                result = &mut write_session, if !write_session.is_finished() => result,
+                else => break,
+            };
+            if let Err(err) = result.map_err(Into::into).and_then(std::convert::identity) {
+                warn!("{err}")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "nitro-enclaves")]
+impl NitroSecureModule {
+    fn attest(&self, user_data: Vec<u8>, public_key: Option<Vec<u8>>) -> anyhow::Result<Vec<u8>> {
         use aws_nitro_enclaves_nsm_api::api::Request::Attestation;
         // some silly code to avoid explicitly mention `serde_bytes::ByteBuf`
         let mut request = Attestation {
             user_data: Some(Default::default()),
             nonce: None,
-            public_key: None,
+            public_key: public_key.as_ref().map(|_| Default::default()),
         };
         let Attestation {
             user_data: Some(buf),
+            public_key: key_buf,
             ..
         } = &mut request
         else {
             unreachable!()
         };
         buf.extend(user_data);
+        if let (Some(key_buf), Some(public_key)) = (key_buf, public_key) {
+            key_buf.extend(public_key)
+        }
         match aws_nitro_enclaves_nsm_api::driver::nsm_process_request(self.0, request) {
             aws_nitro_enclaves_nsm_api::api::Response::Attestation { document } => Ok(document),
             aws_nitro_enclaves_nsm_api::api::Response::Error(err) => anyhow::bail!("{err:?}"),
             _ => anyhow::bail!("unimplemented"),
         }
     }
+}
+
+#[cfg(feature = "nitro-enclaves")]
+impl SecureModule for NitroSecureModule {
+    fn process_attestation(&self, user_data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.attest(user_data, None)
+    }
+
+    fn process_key_attestation(
+        &self,
+        user_data: Vec<u8>,
+        public_key: Vec<u8>,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.attest(user_data, Some(public_key))
+    }
 
     fn describe_pcr(&self, index: u16) -> anyhow::Result<Vec<u8>> {
         use aws_nitro_enclaves_nsm_api::api::Request::DescribePCR;
@@ -60,90 +171,8 @@ impl NitroSecureModule {
         }
     }
 
-    pub async fn run(port: u32, handler: HandleFn) -> anyhow::Result<()> {
-        use std::os::fd::AsRawFd;
-
-        use nix::sys::socket::{
-            bind, listen, socket, AddressFamily, Backlog, SockFlag, SockType, VsockAddr,
-        };
-        use tokio::{
-            io::{AsyncReadExt as _, AsyncWriteExt as _},
-            sync::mpsc::unbounded_channel,
-        };
-
-        let nsm = std::sync::Arc::new(Self::new()?);
-        let pcrs = [
-            nsm.describe_pcr(0)?,
-            nsm.describe_pcr(1)?,
-            nsm.describe_pcr(2)?,
-        ];
-
-        let socket_fd = socket(
-            AddressFamily::Vsock,
-            SockType::Stream,
-            SockFlag::empty(),
-            None,
-        )?;
-        bind(socket_fd.as_raw_fd(), &VsockAddr::new(0xFFFFFFFF, port))?;
-        // theoretically this is the earliest point to entering Tokio world, but i don't want to go
-        // unsafe with `FromRawFd`, and Tokio don't have a `From<OwnedFd>` yet
-        listen(&socket_fd, Backlog::new(64)?)?;
-        let socket = std::os::unix::net::UnixListener::from(socket_fd);
-        socket.set_nonblocking(true)?;
-        let socket = tokio::net::UnixListener::from_std(socket)?;
-
-        loop {
-            let (stream, _) = socket.accept().await?;
-            let (mut read_half, mut write_half) = stream.into_split();
-            let (write_sender, mut write_receiver) = unbounded_channel::<Vec<_>>();
-
-            let mut write_session = tokio::spawn(async move {
-                while let Some(buf) = write_receiver.recv().await {
-                    write_half.write_u64_le(buf.len() as _).await?;
-                    write_half.write_all(&buf).await?;
-                }
-                anyhow::Ok(())
-            });
-            let nsm = nsm.clone();
-            let pcrs = pcrs.clone();
-            let handler = handler.clone(); 
-            let mut read_session = tokio::spawn(async move {
-                loop {
-                    let task = async {
-                        let len = read_half.read_u64_le().await?;
-                        let mut buf = vec![0; len as _];
-                        read_half.read_exact(&mut buf).await?;
-                        anyhow::Ok(buf)
-                    };
-                    let buf = match task.await {
-                        Ok(buf) => buf,
-                        Err(err) => {
-                            warn!("{err}");
-                            return anyhow::Ok(());
-                        }
-                    };
-                    let nsm_clone = nsm.clone();
-                    let pcrs_clone = pcrs.clone();
-                    let write_sender = write_sender.clone();
-                    let handler = handler.clone();
-                    tokio::spawn(async move {
-                        if let Err(err) = handler(buf, nsm_clone, pcrs_clone, write_sender).await {
-                            eprintln!("Error: {:?}", err);
-                        }
-                    });
-                }
-            });
-            loop {
-                let result = tokio::select! {
-                    result = &mut read_session, if !read_session.is_finished() => result,
-                    result = &mut write_session, if !write_session.is_finished() => result,
-                    else => break,
-                };
-                if let Err(err) = result.map_err(Into::into).and_then(std::convert::identity) {
-                    warn!("{err}")
-                }
-            }
-        }
+    fn root_certificate(&self) -> &[u8] {
+        &aws_nitro_enclaves_attestation::AWS_ROOT_CERT[..]
     }
 }
 
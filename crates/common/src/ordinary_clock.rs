@@ -1,7 +1,7 @@
 use std::{cmp::Ordering, collections::{BTreeMap, HashMap}};
 use serde::{Deserialize, Serialize};
-use sha2::{Sha256, Digest};
-use bincode::Options;
+
+use crate::crypto::canonical::CanonicalDigest;
 
 pub trait Clock: PartialOrd + Clone + Send + Sync + 'static {
     fn reduce(&self) -> LamportClock;
@@ -61,14 +61,22 @@ impl OrdinaryClock {
         updated
     }
 
+    /// Same as `canonical_digest`, what the VLC enclave attests.
     pub fn calculate_sha256(&self) -> [u8; 32] {
-        let mut hasher = Sha256::new();
-        let data = bincode::options().serialize(&self.0).expect("Failed to serialize data");
-        // Update the hasher with the JSON string
-        hasher.update(data);
+        self.canonical_digest()
+    }
+}
 
-        // Calculate the hash & return bytes
-        hasher.finalize().into()
+/// The fixed layout of `crypto::canonical`. It is the one `DigestHash` happens to produce on 64-bit
+/// platforms, which clocks used to be attested by.
+impl CanonicalDigest for OrdinaryClock {
+    fn encode_canonical(&self, buf: &mut Vec<u8>) {
+        buf.reserve(8 + 12 * self.0.len());
+        buf.extend_from_slice(&(self.0.len() as u64).to_le_bytes());
+        for (id, n) in &self.0 {
+            buf.extend_from_slice(&id.to_le_bytes());
+            buf.extend_from_slice(&n.to_le_bytes())
+        }
     }
 }
 
@@ -126,7 +134,7 @@ mod tests {
     use rand::rngs::OsRng;
     use futures::future::join_all;
     use tokio::runtime::Builder;
-    use crate::crypto::{core::DigestHash, recovery::{recover_public_key, sign_message_recover_pk, verify_secp256k1_recovery_pk_bytes}};
+    use crate::crypto::{canonical::Fixture, core::DigestHash, recovery::{recover_public_key, sign_message_recover_pk, verify_secp256k1_recovery_pk_bytes}};
 
 
     #[test]
@@ -145,6 +153,22 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn canonical_fixtures() -> anyhow::Result<()> {
+        let fixtures: Vec<Fixture<OrdinaryClock>> =
+            serde_json::from_str(include_str!("../fixtures/canonical_clocks.json"))?;
+        for fixture in &fixtures {
+            fixture.check()?;
+            // digests of clocks attested before the canonical encoding stay valid
+            #[cfg(target_pointer_width = "64")]
+            assert_eq!(
+                fixture.value.canonical_digest(),
+                fixture.value.sha256().to_fixed_bytes()
+            );
+        }
+        Ok(())
+    }
+
     #[tokio::test]
     async fn stress_raw_update() -> anyhow::Result<()> {
         for size in (0..=12).step_by(2).map(|n| 1 << n) {
@@ -0,0 +1,168 @@
+use std::{collections::BTreeMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha384};
+
+use crate::attestation::AttestationDoc;
+
+/// PCRs every policy has to pin: the enclave image, the kernel and bootstrap, and the
+/// application.
+pub const REQUIRED_PCRS: [usize; 3] = [0, 1, 2];
+
+/// The PCR measuring the certificate an enclave image was signed with.
+pub const SIGNING_CERT_PCR: usize = 8;
+
+/// The measurements a document is trusted with, as a JSON file of hex allowlists keyed by PCR
+/// index:
+///
+/// ```json
+/// { "pcrs": { "0": ["<hex>"], "1": ["<hex>"], "2": ["<hex>", "<hex of the next image>"] } }
+/// ```
+///
+/// A PCR matches if its value is any of its list, more than one image can be trusted at once.
+/// PCRs not in the policy are not checked. With `signing_certs` (hex DER) set, PCR8 must also
+/// be the measurement of one of them, i.e. the image must be signed by one of them.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct PcrPolicy {
+    pub pcrs: BTreeMap<usize, Vec<String>>,
+    #[serde(default)]
+    pub signing_certs: Vec<String>,
+}
+
+/// PCR8 of an image signed with `cert` (DER), extended from zero like every PCR:
+/// `SHA-384(0^48 || SHA-384(cert))`.
+pub fn signing_cert_pcr(cert: &[u8]) -> Vec<u8> {
+    Sha384::new()
+        .chain_update([0; 48])
+        .chain_update(Sha384::digest(cert))
+        .finalize()
+        .to_vec()
+}
+
+impl PcrPolicy {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let policy = serde_json::from_slice::<Self>(&std::fs::read(path)?)
+            .map_err(|err| anyhow::anyhow!("malformed policy {}: {err}", path.display()))?;
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    /// Trusts exactly the PCRs of `doc`, e.g. to pin a known good enclave.
+    pub fn of(doc: &AttestationDoc) -> Self {
+        Self {
+            pcrs: doc
+                .pcrs
+                .iter()
+                .map(|(index, value)| (*index, vec![hex::encode(value)]))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Trusts exactly PCR0-2 as given, e.g. the enclave's own ones.
+    pub fn exact(pcrs: &[Vec<u8>; 3]) -> Self {
+        Self {
+            pcrs: REQUIRED_PCRS
+                .into_iter()
+                .zip(pcrs)
+                .map(|(index, value)| (index, vec![hex::encode(value)]))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for index in REQUIRED_PCRS {
+            anyhow::ensure!(
+                self.pcrs
+                    .get(&index)
+                    .is_some_and(|allowed| !allowed.is_empty()),
+                "policy does not pin PCR{index}"
+            )
+        }
+        for (index, allowed) in &self.pcrs {
+            for value in allowed {
+                anyhow::ensure!(
+                    hex::decode(value).is_ok(),
+                    "PCR{index} value {value} is not hex"
+                )
+            }
+        }
+        for cert in &self.signing_certs {
+            anyhow::ensure!(hex::decode(cert).is_ok(), "signing cert is not hex");
+        }
+        Ok(())
+    }
+
+    /// Checks the PCRs of a verified document, the error names the first one that failed.
+    pub fn check(&self, doc: &AttestationDoc) -> anyhow::Result<()> {
+        for (index, allowed) in &self.pcrs {
+            let Some(value) = doc.pcrs.get(index) else {
+                anyhow::bail!("PCR{index} missing from document")
+            };
+            let value = hex::encode(value);
+            anyhow::ensure!(
+                allowed
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(&value)),
+                "PCR{index} {value} not allowed by policy"
+            )
+        }
+        if !self.signing_certs.is_empty() {
+            let Some(value) = doc.pcrs.get(&SIGNING_CERT_PCR) else {
+                anyhow::bail!("PCR{SIGNING_CERT_PCR} missing from document")
+            };
+            anyhow::ensure!(
+                self.signing_certs.iter().any(|cert| {
+                    hex::decode(cert).is_ok_and(|cert| signing_cert_pcr(&cert) == **value)
+                }),
+                "PCR{SIGNING_CERT_PCR} {} not of a trusted signing cert",
+                hex::encode(value)
+            )
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_bytes::ByteBuf;
+
+    fn doc(pcrs: impl IntoIterator<Item = (usize, Vec<u8>)>) -> AttestationDoc {
+        AttestationDoc {
+            module_id: Default::default(),
+            digest: "SHA384".into(),
+            timestamp: 0,
+            pcrs: pcrs
+                .into_iter()
+                .map(|(index, value)| (index, ByteBuf::from(value)))
+                .collect(),
+            certificate: Default::default(),
+            cabundle: Default::default(),
+            public_key: None,
+            user_data: None,
+            nonce: None,
+        }
+    }
+
+    #[test]
+    fn signing_cert() -> anyhow::Result<()> {
+        let own = [vec![0; 48], vec![1; 48], vec![2; 48]];
+        let mut policy = PcrPolicy::exact(&own);
+        policy.validate()?;
+        let signed = (0..3).zip(own.clone()).chain([(8, signing_cert_pcr(b"cert"))]);
+        policy.check(&doc(signed.clone()))?;
+
+        policy.signing_certs.push(hex::encode(b"cert"));
+        policy.validate()?;
+        policy.check(&doc(signed))?;
+        let err = policy.check(&doc((0..3).zip(own.clone()))).unwrap_err();
+        assert_eq!(err.to_string(), "PCR8 missing from document");
+        let other = (0..3).zip(own).chain([(8, signing_cert_pcr(b"other"))]);
+        let err = policy.check(&doc(other)).unwrap_err();
+        assert!(err.to_string().starts_with("PCR8 "));
+        Ok(())
+    }
+}
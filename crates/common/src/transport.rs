@@ -0,0 +1,313 @@
+use std::{fmt, path::PathBuf, str::FromStr};
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    sync::mpsc::{UnboundedReceiver, UnboundedSender},
+};
+
+/// `VMADDR_CID_ANY`, what an enclave binds to.
+pub const VSOCK_CID_ANY: u32 = 0xFFFFFFFF;
+
+/// Endpoint of an enclave service, written as `vsock://<cid>:<port>` (`any` as cid when
+/// listening), `tcp://<host>:<port>` or `unix://<path>`.
+///
+/// Only vsock reaches into a real nitro enclave, the other two are for running the enclave
+/// services as plain processes during development.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Vsock { cid: u32, port: u32 },
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Address {
+    pub fn vsock(cid: u32, port: u32) -> Self {
+        Self::Vsock { cid, port }
+    }
+}
+
+impl FromStr for Address {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((scheme, rest)) = s.split_once("://") else {
+            anyhow::bail!("missing scheme in address {s:?}")
+        };
+        match scheme {
+            "vsock" => {
+                let Some((cid, port)) = rest.split_once(':') else {
+                    anyhow::bail!("expect vsock://<cid>:<port>, got {s:?}")
+                };
+                let cid = if cid == "any" {
+                    VSOCK_CID_ANY
+                } else {
+                    cid.parse()?
+                };
+                Ok(Self::Vsock {
+                    cid,
+                    port: port.parse()?,
+                })
+            }
+            "tcp" => Ok(Self::Tcp(rest.into())),
+            "unix" => {
+                anyhow::ensure!(!rest.is_empty(), "empty unix socket path");
+                Ok(Self::Unix(rest.into()))
+            }
+            _ => anyhow::bail!("unsupported scheme {scheme:?}"),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Vsock { cid, port } if *cid == VSOCK_CID_ANY => write!(f, "vsock://any:{port}"),
+            Self::Vsock { cid, port } => write!(f, "vsock://{cid}:{port}"),
+            Self::Tcp(addr) => write!(f, "tcp://{addr}"),
+            Self::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+pub type ReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+pub type WriteHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Connected stream of any of the supported transports. Vsock streams are driven as unix
+/// streams, tokio has no native vsock support.
+#[derive(Debug)]
+pub enum Stream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Stream {
+    pub fn into_split(self) -> (ReadHalf, WriteHalf) {
+        match self {
+            Self::Unix(stream) => {
+                let (read_half, write_half) = stream.into_split();
+                (Box::new(read_half), Box::new(write_half))
+            }
+            Self::Tcp(stream) => {
+                let (read_half, write_half) = stream.into_split();
+                (Box::new(read_half), Box::new(write_half))
+            }
+        }
+    }
+}
+
+pub async fn connect(addr: &Address) -> anyhow::Result<Stream> {
+    match addr {
+        Address::Vsock { cid, port } => {
+            use nix::sys::socket::{connect, socket, AddressFamily, SockFlag, SockType, VsockAddr};
+            use std::os::fd::AsRawFd;
+
+            let fd = socket(
+                AddressFamily::Vsock,
+                SockType::Stream,
+                SockFlag::empty(),
+                None,
+            )?;
+            // this one is blocking, but should be instant, hopefully
+            {
+                let _span = tracing::debug_span!("connect").entered();
+                connect(fd.as_raw_fd(), &VsockAddr::new(*cid, *port))?
+            }
+            let stream = std::os::unix::net::UnixStream::from(fd);
+            stream.set_nonblocking(true)?;
+            Ok(Stream::Unix(UnixStream::from_std(stream)?))
+        }
+        Address::Tcp(addr) => {
+            let stream = TcpStream::connect(addr).await?;
+            stream.set_nodelay(true)?;
+            Ok(Stream::Tcp(stream))
+        }
+        Address::Unix(path) => Ok(Stream::Unix(UnixStream::connect(path).await?)),
+    }
+}
+
+#[derive(Debug)]
+pub enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    pub async fn bind(addr: &Address) -> anyhow::Result<Self> {
+        match addr {
+            Address::Vsock { cid, port } => {
+                use nix::sys::socket::{
+                    bind, listen, socket, AddressFamily, Backlog, SockFlag, SockType, VsockAddr,
+                };
+                use std::os::fd::AsRawFd;
+
+                let socket_fd = socket(
+                    AddressFamily::Vsock,
+                    SockType::Stream,
+                    SockFlag::empty(),
+                    None,
+                )?;
+                bind(socket_fd.as_raw_fd(), &VsockAddr::new(*cid, *port))?;
+                // theoretically this is the earliest point to entering Tokio world, but i don't
+                // want to go unsafe with `FromRawFd`, and Tokio don't have a `From<OwnedFd>` yet
+                listen(&socket_fd, Backlog::new(64)?)?;
+                let socket = std::os::unix::net::UnixListener::from(socket_fd);
+                socket.set_nonblocking(true)?;
+                Ok(Self::Unix(UnixListener::from_std(socket)?))
+            }
+            Address::Tcp(addr) => Ok(Self::Tcp(TcpListener::bind(addr).await?)),
+            Address::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt as _;
+                // clean up the socket file left behind by a previous run, but nothing else
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        std::fs::remove_file(path)?
+                    }
+                }
+                Ok(Self::Unix(UnixListener::bind(path)?))
+            }
+        }
+    }
+
+    /// Bound address of a TCP listener, useful when binding to port 0.
+    pub fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().ok(),
+            Self::Unix(_) => None,
+        }
+    }
+
+    pub async fn accept(&self) -> anyhow::Result<Stream> {
+        match self {
+            Self::Unix(listener) => Ok(Stream::Unix(listener.accept().await?.0)),
+            Self::Tcp(listener) => {
+                let (stream, _) = listener.accept().await?;
+                stream.set_nodelay(true)?;
+                Ok(Stream::Tcp(stream))
+            }
+        }
+    }
+}
+
+/// Reads one frame: a little endian u64 length followed by that many bytes.
+pub async fn read_frame(read_half: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Vec<u8>> {
+    let len = read_half.read_u64_le().await?;
+    let mut buf = vec![0; len as _];
+    read_half.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+pub async fn write_frame(
+    write_half: &mut (impl AsyncWrite + Unpin),
+    buf: &[u8],
+) -> anyhow::Result<()> {
+    write_half.write_u64_le(buf.len() as _).await?;
+    write_half.write_all(buf).await?;
+    Ok(())
+}
+
+/// Client side of an enclave service: bincode encodes and sends every event, and decodes every
+/// reply into `sender`, until either direction closes.
+pub async fn session<E, R>(
+    stream: Stream,
+    mut events: UnboundedReceiver<E>,
+    sender: UnboundedSender<R>,
+) -> anyhow::Result<()>
+where
+    E: Serialize + Send + 'static,
+    R: DeserializeOwned + Send + Sync + 'static,
+{
+    let (mut read_half, mut write_half) = stream.into_split();
+    let write_session = tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            let buf = bincode::options().serialize(&event)?;
+            write_frame(&mut write_half, &buf).await?
+        }
+        anyhow::Ok(())
+    });
+    let read_session = tokio::spawn(async move {
+        loop {
+            let buf = read_frame(&mut read_half).await?;
+            sender.send(bincode::options().deserialize(&buf)?)?
+        }
+        #[allow(unreachable_code)] // for type hinting
+        anyhow::Ok(())
+    });
+    tokio::select! {
+        result = write_session => return result?,
+        result = read_session => result??
+    }
+    anyhow::bail!("unreachable")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[test]
+    fn parse_address() -> anyhow::Result<()> {
+        assert_eq!("vsock://15:5005".parse::<Address>()?, Address::vsock(15, 5005));
+        assert_eq!(
+            "vsock://any:5005".parse::<Address>()?,
+            Address::vsock(VSOCK_CID_ANY, 5005)
+        );
+        assert_eq!(
+            "unix:///tmp/llm.sock".parse::<Address>()?,
+            Address::Unix("/tmp/llm.sock".into())
+        );
+        assert_eq!(
+            "tcp://127.0.0.1:5005".parse::<Address>()?,
+            Address::Tcp("127.0.0.1:5005".into())
+        );
+        for addr in ["vsock://any:5005", "tcp://localhost:1", "unix:///tmp/llm.sock"] {
+            assert_eq!(addr.parse::<Address>()?.to_string(), addr)
+        }
+        assert!("127.0.0.1:5005".parse::<Address>().is_err());
+        assert!("vsock://15".parse::<Address>().is_err());
+        assert!("http://localhost".parse::<Address>().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_session() -> anyhow::Result<()> {
+        let listener = Listener::bind(&"tcp://127.0.0.1:0".parse()?).await?;
+        let addr = Address::Tcp(listener.local_addr().unwrap().to_string());
+        // echo server that doubles every number
+        tokio::spawn(async move {
+            let (mut read_half, mut write_half) = listener.accept().await?.into_split();
+            loop {
+                let n = bincode::options().deserialize::<u32>(&read_frame(&mut read_half).await?)?;
+                write_frame(&mut write_half, &bincode::options().serialize(&(n * 2))?).await?
+            }
+            #[allow(unreachable_code)]
+            anyhow::Ok(())
+        });
+
+        let (event_sender, events) = unbounded_channel::<u32>();
+        let (sender, mut receiver) = unbounded_channel::<u32>();
+        tokio::spawn(session(connect(&addr).await?, events, sender));
+        for n in 0..10 {
+            event_sender.send(n)?;
+            assert_eq!(receiver.recv().await, Some(n * 2))
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unix_rebind() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("transport-{}.sock", std::process::id()));
+        let addr = Address::Unix(path.clone());
+        let listener = Listener::bind(&addr).await?;
+        drop(listener);
+        // stale socket file is replaced
+        let listener = Listener::bind(&addr).await?;
+        let accept = tokio::spawn(async move { listener.accept().await });
+        connect(&addr).await?;
+        accept.await??;
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
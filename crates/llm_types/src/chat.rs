@@ -0,0 +1,288 @@
+use std::io::{BufReader, Read};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+impl Role {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+/// Chat formats of the model families we serve. The prompt is rendered without the leading BOS,
+/// which the tokenizer adds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatTemplate {
+    Llama2,
+    Llama3,
+    ChatMl,
+    Zephyr,
+}
+
+impl ChatTemplate {
+    /// Recognizes the family of a `tokenizer.chat_template` of GGUF metadata. It is a jinja
+    /// template, which we do not evaluate but only look for the family's markers in.
+    pub fn detect(jinja: &str) -> Option<Self> {
+        if jinja.contains("<|im_start|>") {
+            Some(Self::ChatMl)
+        } else if jinja.contains("<|start_header_id|>") {
+            Some(Self::Llama3)
+        } else if jinja.contains("[INST]") {
+            Some(Self::Llama2)
+        } else if jinja.contains("<|user|>") {
+            Some(Self::Zephyr)
+        } else {
+            None
+        }
+    }
+
+    /// Renders the conversation, ending with the cue for the assistant's reply.
+    pub fn render(&self, messages: &[ChatMessage]) -> anyhow::Result<String> {
+        validate(messages)?;
+        let mut prompt = String::new();
+        match self {
+            Self::Llama2 => {
+                // the system prompt goes into the first instruction
+                let (system, messages) = match messages.split_first() {
+                    Some((first, rest)) if first.role == Role::System => {
+                        (Some(&first.content), rest)
+                    }
+                    _ => (None, messages),
+                };
+                for (i, message) in messages.iter().enumerate() {
+                    match message.role {
+                        Role::User => {
+                            if i > 0 {
+                                prompt.push_str("<s>")
+                            }
+                            prompt.push_str("[INST] ");
+                            if let (0, Some(system)) = (i, system) {
+                                prompt.push_str(&format!("<<SYS>>\n{system}\n<</SYS>>\n\n"))
+                            }
+                            prompt.push_str(&format!("{} [/INST]", message.content))
+                        }
+                        Role::Assistant => prompt.push_str(&format!(" {} </s>", message.content)),
+                        Role::System => unreachable!(),
+                    }
+                }
+            }
+            Self::Llama3 => {
+                for message in messages {
+                    prompt.push_str(&format!(
+                        "<|start_header_id|>{}<|end_header_id|>\n\n{}<|eot_id|>",
+                        message.role.as_str(),
+                        message.content
+                    ))
+                }
+                prompt.push_str("<|start_header_id|>assistant<|end_header_id|>\n\n")
+            }
+            Self::ChatMl => {
+                for message in messages {
+                    prompt.push_str(&format!(
+                        "<|im_start|>{}\n{}<|im_end|>\n",
+                        message.role.as_str(),
+                        message.content
+                    ))
+                }
+                prompt.push_str("<|im_start|>assistant\n")
+            }
+            Self::Zephyr => {
+                for message in messages {
+                    prompt.push_str(&format!(
+                        "<|{}|>\n{}</s>\n",
+                        message.role.as_str(),
+                        message.content
+                    ))
+                }
+                prompt.push_str("<|assistant|>\n")
+            }
+        }
+        Ok(prompt)
+    }
+}
+
+/// A conversation is an optional system message, then messages ending with the user's.
+pub fn validate(messages: &[ChatMessage]) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        messages.last().map(|message| message.role) == Some(Role::User),
+        "conversation does not end with a user message"
+    );
+    anyhow::ensure!(
+        messages
+            .iter()
+            .skip(1)
+            .all(|message| message.role != Role::System),
+        "system message after the first one"
+    );
+    Ok(())
+}
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+const CHAT_TEMPLATE_KEY: &str = "tokenizer.chat_template";
+
+/// Reads `tokenizer.chat_template` from the metadata of a GGUF (v2 or later) file, without
+/// reading the tensors.
+pub fn gguf_chat_template(path: &str) -> anyhow::Result<Option<String>> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    anyhow::ensure!(&magic == GGUF_MAGIC, "{path} is not a GGUF file");
+    let version = read_u32(&mut reader)?;
+    anyhow::ensure!(version >= 2, "unsupported GGUF version {version}");
+    let _tensor_count = read_u64(&mut reader)?;
+    let kv_count = read_u64(&mut reader)?;
+    for _ in 0..kv_count {
+        let key = read_string(&mut reader)?;
+        let value_type = read_u32(&mut reader)?;
+        if key == CHAT_TEMPLATE_KEY {
+            anyhow::ensure!(value_type == GGUF_TYPE_STRING, "{key} is not a string");
+            return Ok(Some(read_string(&mut reader)?));
+        }
+        skip_value(&mut reader, value_type)?
+    }
+    Ok(None)
+}
+
+const GGUF_TYPE_STRING: u32 = 8;
+const GGUF_TYPE_ARRAY: u32 = 9;
+
+fn read_u32(reader: &mut impl Read) -> anyhow::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> anyhow::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_string(reader: &mut impl Read) -> anyhow::Result<String> {
+    let len = read_u64(reader)?;
+    let mut buf = Vec::new();
+    reader.take(len).read_to_end(&mut buf)?;
+    anyhow::ensure!(buf.len() as u64 == len, "truncated GGUF string");
+    Ok(String::from_utf8(buf)?)
+}
+
+fn skip_value(reader: &mut impl Read, value_type: u32) -> anyhow::Result<()> {
+    let len = match value_type {
+        // u8, i8, bool
+        0 | 1 | 7 => 1,
+        // u16, i16
+        2 | 3 => 2,
+        // u32, i32, f32
+        4..=6 => 4,
+        // u64, i64, f64
+        10..=12 => 8,
+        GGUF_TYPE_STRING => read_u64(reader)?,
+        GGUF_TYPE_ARRAY => {
+            let item_type = read_u32(reader)?;
+            for _ in 0..read_u64(reader)? {
+                skip_value(reader, item_type)?
+            }
+            return Ok(());
+        }
+        _ => anyhow::bail!("unknown GGUF value type {value_type}"),
+    };
+    let skipped = std::io::copy(&mut reader.take(len), &mut std::io::sink())?;
+    anyhow::ensure!(skipped == len, "truncated GGUF value");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: Role, content: &str) -> ChatMessage {
+        ChatMessage {
+            role,
+            content: content.into(),
+        }
+    }
+
+    #[test]
+    fn render() -> anyhow::Result<()> {
+        let messages = [
+            message(Role::System, "Be brief."),
+            message(Role::User, "Hi"),
+            message(Role::Assistant, "Hello"),
+            message(Role::User, "Why?"),
+        ];
+        assert_eq!(
+            ChatTemplate::Llama2.render(&messages)?,
+            "[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi [/INST] Hello </s><s>[INST] Why? [/INST]"
+        );
+        assert_eq!(
+            ChatTemplate::ChatMl.render(&messages[1..])?,
+            "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\nHello<|im_end|>\n\
+             <|im_start|>user\nWhy?<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(
+            ChatTemplate::Llama3.render(&messages[3..])?,
+            "<|start_header_id|>user<|end_header_id|>\n\nWhy?<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+        assert!(ChatTemplate::Zephyr.render(&messages[..3]).is_err());
+        assert!(ChatTemplate::Zephyr.render(&[]).is_err());
+        let mut messages = messages.to_vec();
+        messages.swap(0, 1);
+        assert!(ChatTemplate::Zephyr.render(&messages).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn read_gguf_chat_template() -> anyhow::Result<()> {
+        fn string(buf: &mut Vec<u8>, s: &str) {
+            buf.extend((s.len() as u64).to_le_bytes());
+            buf.extend(s.as_bytes())
+        }
+        let mut buf = GGUF_MAGIC.to_vec();
+        buf.extend(3u32.to_le_bytes());
+        buf.extend(0u64.to_le_bytes());
+        buf.extend(3u64.to_le_bytes());
+        string(&mut buf, "general.architecture");
+        buf.extend(GGUF_TYPE_STRING.to_le_bytes());
+        string(&mut buf, "llama");
+        string(&mut buf, "tokenizer.ggml.tokens");
+        buf.extend(GGUF_TYPE_ARRAY.to_le_bytes());
+        buf.extend(GGUF_TYPE_STRING.to_le_bytes());
+        buf.extend(2u64.to_le_bytes());
+        string(&mut buf, "<s>");
+        string(&mut buf, "</s>");
+        string(&mut buf, CHAT_TEMPLATE_KEY);
+        buf.extend(GGUF_TYPE_STRING.to_le_bytes());
+        string(&mut buf, "{{ '<|im_start|>' + message['role'] }}");
+
+        let path = std::env::temp_dir().join(format!("chat-{}.gguf", std::process::id()));
+        std::fs::write(&path, &buf)?;
+        let template = gguf_chat_template(path.to_str().unwrap())?;
+        assert_eq!(
+            template.as_deref().and_then(ChatTemplate::detect),
+            Some(ChatTemplate::ChatMl)
+        );
+        std::fs::write(&path, &buf[..buf.len() - 4])?;
+        assert!(gguf_chat_template(path.to_str().unwrap()).is_err());
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use common::crypto::canonical::{encode_bincode, CanonicalDigest};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chat::{ChatMessage, ChatTemplate},
+    sampling::SamplingParams,
+};
+
+/// Bumped whenever a field is added to, removed from or reordered in `InferenceCommitment`, or
+/// the digest of one of them changes.
+pub const INFERENCE_COMMITMENT_VERSION: u32 = 1;
+
+/// Everything an attested answer vouches for. The enclave attests `digest()` as the
+/// `user_data` of the document, so the document cannot be replayed for another request, model,
+/// prompt, sampling setup or VRF output.
+///
+/// Canonical encoding: the `bincode::options()` layout of `common::crypto::canonical`, fields in
+/// declaration order, and `digest()` is SHA-256 over it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InferenceCommitment {
+    pub version: u32,
+    pub request_id: String,
+    /// Hex SHA-256 of the model weights file.
+    pub model_hash: String,
+    /// As fed to the model.
+    pub prompt: String,
+    /// The chat `prompt` was rendered from, empty for a raw prompt.
+    pub messages: Vec<ChatMessage>,
+    pub chat_template: Option<ChatTemplate>,
+    /// With the seed resolved, so the answer can be reproduced.
+    pub sampling: SamplingParams,
+    pub n_predict: u64,
+    pub vrf_prompt_hash: String,
+    pub vrf_random_value: String,
+    pub vrf_verify_pubkey: String,
+    pub vrf_proof: String,
+    pub answer: String,
+    /// Hex `transcript_hash` of a streamed answer, empty otherwise.
+    pub transcript_hash: String,
+    /// Chosen by the requester to demand a fresh inference, empty if it does not care.
+    pub nonce: String,
+}
+
+impl InferenceCommitment {
+    pub fn encode(&self) -> Vec<u8> {
+        self.canonical_bytes()
+    }
+
+    pub fn digest(&self) -> [u8; 32] {
+        self.canonical_digest()
+    }
+
+    /// Checks an attested `user_data` against this commitment.
+    pub fn check(&self, user_data: Option<&[u8]>) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.version == INFERENCE_COMMITMENT_VERSION,
+            "unsupported commitment version {}",
+            self.version
+        );
+        anyhow::ensure!(
+            user_data == Some(&self.digest()[..]),
+            "attested user data does not match inference commitment"
+        );
+        Ok(())
+    }
+}
+
+impl CanonicalDigest for InferenceCommitment {
+    fn encode_canonical(&self, buf: &mut Vec<u8>) {
+        encode_bincode(self, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::Role;
+
+    #[test]
+    fn digest_covers_every_field() -> anyhow::Result<()> {
+        let commitment = InferenceCommitment {
+            version: INFERENCE_COMMITMENT_VERSION,
+            request_id: "1".into(),
+            model_hash: "00".into(),
+            prompt: "[INST] How to combine AI and blockchain? [/INST]".into(),
+            messages: vec![ChatMessage {
+                role: Role::User,
+                content: "How to combine AI and blockchain?".into(),
+            }],
+            chat_template: Some(ChatTemplate::Llama2),
+            sampling: SamplingParams::with(0.0, 0.95, Some(42)),
+            n_predict: 128,
+            vrf_prompt_hash: "hash".into(),
+            vrf_random_value: "random".into(),
+            vrf_verify_pubkey: "pubkey".into(),
+            vrf_proof: "proof".into(),
+            answer: "42".into(),
+            transcript_hash: String::new(),
+            nonce: "nonce".into(),
+        };
+        let digest = commitment.digest();
+        commitment.check(Some(&digest))?;
+
+        let mut other = commitment.clone();
+        other.request_id = "2".into();
+        assert!(other.check(Some(&digest)).is_err());
+        let mut other = commitment.clone();
+        other.sampling.seed = Some(43);
+        assert!(other.check(Some(&digest)).is_err());
+        let mut other = commitment.clone();
+        other.sampling.stop.push("\n".into());
+        assert!(other.check(Some(&digest)).is_err());
+        let mut other = commitment.clone();
+        other.messages[0].role = Role::System;
+        assert!(other.check(Some(&digest)).is_err());
+        let mut other = commitment.clone();
+        other.chat_template = Some(ChatTemplate::ChatMl);
+        assert!(other.check(Some(&digest)).is_err());
+        let mut other = commitment.clone();
+        other.nonce = String::new();
+        assert!(other.check(Some(&digest)).is_err());
+        let mut other = commitment.clone();
+        other.version += 1;
+        assert!(other.check(Some(&other.digest())).is_err());
+        assert!(commitment.check(None).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,7 @@
+//! Messages between the operator and the LLM enclave, and what is needed to check the answers.
+//!
+//! Kept apart from `tee_llm` so that verifying an answer does not build llama.cpp.
+pub mod chat;
+pub mod commitment;
+pub mod nitro_llm;
+pub mod sampling;
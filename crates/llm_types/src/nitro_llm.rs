@@ -0,0 +1,443 @@
+//! Messages of the LLM enclave and how answers are checked, without the enclave itself.
+
+use common::{
+    attestation::{verify_document, AttestationDoc},
+    crypto::{
+        canonical::{bincode_digest, encode_bincode, CanonicalDigest},
+        core::H256,
+    },
+    types::Payload,
+};
+use serde::{Deserialize, Serialize};
+use vrf::sortition::{self, Selection};
+
+use crate::{
+    chat::{self, ChatMessage, ChatTemplate},
+    commitment::{InferenceCommitment, INFERENCE_COMMITMENT_VERSION},
+    sampling::SamplingParams,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TEEReq {
+    Ping(String),
+    PromptReq(PromptReq),
+    /// Same as `PromptReq`, but answered with `TokenChunk`s as they are generated, followed by a
+    /// `StreamEnd`.
+    StreamPromptReq(PromptReq),
+    ListModels,
+    /// Loads a model into the cache ahead of the first prompt of it.
+    PreloadModel(String),
+    EvictModel(String),
+    /// The VRF key of this boot, attested.
+    VrfKey,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TEEResp {
+    Ping(PingResp),
+    AnswerResp(AnswerResp),
+    TokenChunk(TokenChunk),
+    StreamEnd(StreamEnd),
+    /// Reply of the model management requests.
+    Models(ModelsResp),
+    VrfKey(VrfKeyResp),
+    /// A prompt that could not be answered, in place of its `AnswerResp` or `StreamEnd`, also
+    /// after some of its `TokenChunk`s.
+    Failed(FailedResp),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptReq {
+    pub request_id: String,
+    pub model_name: String,
+    /// Fed to the model as is, left empty when `messages` are given instead.
+    pub prompt: String,
+    pub messages: Vec<ChatMessage>,
+    /// Renders `messages`, overriding the one detected from the model's metadata.
+    pub chat_template: Option<ChatTemplate>,
+    pub sampling: SamplingParams,
+    pub n_predict: usize, // maximum predict token
+    pub vrf_prompt_hash: String,
+    pub vrf_threshold: u64,
+    pub vrf_precision: usize,
+    /// Overrides `vrf_threshold` out of `vrf_precision` hex digits, see `PromptReq::selection`.
+    pub vrf_selection: Option<Selection>,
+    /// Echoed into the inference commitment, see `InferenceCommitment::nonce`.
+    pub nonce: String,
+    // pub n_threads: u32,
+    // pub clock: NitroEnclavesClock, // to be done
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct AnswerResp {
+    pub request_id: String,
+    pub model_name: String,
+    /// The prompt as fed to the model, rendered from `messages` if given.
+    pub prompt: String,
+    pub messages: Vec<ChatMessage>,
+    /// The template `messages` were rendered by, when selected.
+    pub chat_template: Option<ChatTemplate>,
+    pub answer: String,
+    pub elapsed: u64,
+    pub selected: bool,
+    pub document: Payload,
+    pub vrf_prompt_hash: String,
+    pub vrf_random_value: String,
+    pub vrf_verify_pubkey: String,
+    pub vrf_proof: String,
+    /// Hex `vrf::batch::BatchableProof` of the same evaluation, for checking the proofs of many
+    /// answers at once. Not attested, its `to_proof` is `vrf_proof`.
+    #[serde(default)]
+    pub vrf_batchable_proof: String,
+    pub model_hash: String,
+    /// Sampling of the request, with the seed in use filled in when selected.
+    pub sampling: SamplingParams,
+    pub n_predict: usize,
+    pub nonce: String,
+    // pub clock: NitroEnclavesClock, // to be done
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenChunk {
+    pub request_id: String,
+    pub seq: u64,
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct FailedResp {
+    pub request_id: String,
+    pub error: String,
+}
+
+/// Last message of a stream. `answer.answer` is the concatenation of all tokens, and the
+/// commitment attested by `answer.document` carries `transcript_hash` of them as well.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct StreamEnd {
+    pub chunks: u64,
+    pub answer: AnswerResp,
+    /// Hex `transcript_hash` of the tokens when selected, so that the answer can be checked
+    /// against its attestation without them.
+    pub transcript_hash: String,
+}
+
+/// Digest of a stream's transcript: the canonical digest of the request id and every token in
+/// `seq` order, as a tuple.
+pub fn transcript_hash(request_id: &str, tokens: &[String]) -> H256 {
+    H256(bincode_digest(&(request_id, tokens)))
+}
+
+/// Canonical as `InferenceCommitment`, see `common::crypto::canonical`.
+impl CanonicalDigest for PromptReq {
+    fn encode_canonical(&self, buf: &mut Vec<u8>) {
+        encode_bincode(self, buf)
+    }
+}
+
+impl CanonicalDigest for TEEReq {
+    fn encode_canonical(&self, buf: &mut Vec<u8>) {
+        encode_bincode(self, buf)
+    }
+}
+
+impl CanonicalDigest for AnswerResp {
+    fn encode_canonical(&self, buf: &mut Vec<u8>) {
+        encode_bincode(self, buf)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct VRFReply {
+    pub selected: bool,
+    pub vrf_prompt_hash: String,
+    pub vrf_random_value: String,
+    pub vrf_verify_pubkey: String,
+    pub vrf_proof: String,
+    pub vrf_batchable_proof: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResidentModel {
+    pub name: String,
+    pub size: u64,
+    /// Hex SHA-256 of the weights file.
+    pub hash: String,
+    /// As detected from the GGUF metadata.
+    pub chat_template: Option<ChatTemplate>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct PingResp {
+    pub echo: String,
+    pub cpu_percent: f32,
+    pub cpu_nums: usize,
+    pub mem_total: u64,
+    pub mem_used: u64,
+    pub models: Vec<ResidentModel>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct ModelsResp {
+    pub models: Vec<ResidentModel>,
+    pub capacity: u64,
+    /// Set when a preload failed.
+    pub error: Option<String>,
+}
+
+/// `user_data` of the document attesting a VRF key.
+pub const VRF_KEY_ATTESTATION: &[u8] = b"tee_llm vrf key";
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct VrfKeyResp {
+    /// Hex VRF public key, the `vrf_verify_pubkey` of every answer of this boot.
+    pub public_key: String,
+    /// Attests `public_key` as its `public_key`, with `VRF_KEY_ATTESTATION` as `user_data`.
+    pub document: Payload,
+}
+
+// technically `feature = "aws-nitro-enclaves-attestation"` is sufficient for
+// attestation, NSM API is only depended by `NitroSecureModule` that running
+// inside enclaves image
+#[cfg(feature = "nitro-enclaves")]
+impl AnswerResp {
+    pub fn verify_inference(&self) -> anyhow::Result<Option<AttestationDoc>> {
+        self.verify_inference_with(&aws_nitro_enclaves_attestation::AWS_ROOT_CERT[..])
+    }
+}
+
+impl AnswerResp {
+    /// The commitment of a non-streamed answer. Verifiers that demanded a nonce should also
+    /// compare `self.nonce` to it.
+    pub fn commitment(&self) -> InferenceCommitment {
+        InferenceCommitment {
+            version: INFERENCE_COMMITMENT_VERSION,
+            request_id: self.request_id.clone(),
+            model_hash: self.model_hash.clone(),
+            prompt: self.prompt.clone(),
+            messages: self.messages.clone(),
+            chat_template: self.chat_template,
+            sampling: self.sampling.clone(),
+            n_predict: self.n_predict as _,
+            vrf_prompt_hash: self.vrf_prompt_hash.clone(),
+            vrf_random_value: self.vrf_random_value.clone(),
+            vrf_verify_pubkey: self.vrf_verify_pubkey.clone(),
+            vrf_proof: self.vrf_proof.clone(),
+            answer: self.answer.clone(),
+            transcript_hash: String::new(),
+            nonce: self.nonce.clone(),
+        }
+    }
+
+    /// A request that re-runs this inference on another enclave: same model, prompt, sampling and
+    /// seed, selected by `selection`, one that always selects such as `Selection::fraction(1, 1)`
+    /// for a single enclave. Its answer is expected to equal `self.answer`.
+    pub fn replay_req(&self, request_id: String, selection: Selection) -> PromptReq {
+        PromptReq {
+            request_id,
+            model_name: self.model_name.clone(),
+            // rendered again from the messages
+            prompt: if self.messages.is_empty() {
+                self.prompt.clone()
+            } else {
+                String::new()
+            },
+            messages: self.messages.clone(),
+            chat_template: self.chat_template,
+            sampling: self.sampling.clone(),
+            n_predict: self.n_predict,
+            vrf_prompt_hash: self.vrf_prompt_hash.clone(),
+            // superseded by `vrf_selection`
+            vrf_threshold: 0,
+            vrf_precision: 0,
+            vrf_selection: Some(selection),
+            nonce: String::new(),
+        }
+    }
+
+    /// Recomputes the VRF output from the proof, and the selection as well given the one of the
+    /// prompt.
+    pub fn verify_vrf(&self, selection: Option<&Selection>) -> anyhow::Result<()> {
+        let output = sortition::verify_proof(
+            &self.vrf_verify_pubkey,
+            &self.vrf_proof,
+            self.vrf_prompt_hash.as_bytes(),
+        )?;
+        anyhow::ensure!(
+            hex::encode(output.to_bytes()) == self.vrf_random_value,
+            "vrf random value does not match the proof"
+        );
+        if let Some(selection) = selection {
+            anyhow::ensure!(
+                sortition::select(&output, selection)?.selected == self.selected,
+                "selected does not match the vrf output"
+            );
+        }
+        Ok(())
+    }
+
+    /// Same as `verify_inference`, but trusts the given DER root certificate instead of the AWS
+    /// one, so answers attested by a `MockSecureModule` can be checked as well.
+    pub fn verify_inference_with(&self, root_cert: &[u8]) -> anyhow::Result<Option<AttestationDoc>> {
+        if !self.selected {
+            anyhow::ensure!(self.answer.is_empty(), "answer of an enclave not selected");
+            return Ok(None);
+        }
+        let document = verify_document(
+            &self.document,
+            root_cert,
+            std::time::SystemTime::UNIX_EPOCH.elapsed()?.as_secs(),
+        )?;
+        self.commitment()
+            .check(document.user_data.as_deref().map(|user_data| &user_data[..]))?;
+        Ok(Some(document))
+    }
+}
+
+impl VrfKeyResp {
+    /// Checks that `public_key` is the VRF key of an enclave rooted at `root_cert`.
+    pub fn verify_with(&self, root_cert: &[u8]) -> anyhow::Result<AttestationDoc> {
+        let document = verify_document(
+            &self.document,
+            root_cert,
+            std::time::SystemTime::UNIX_EPOCH.elapsed()?.as_secs(),
+        )?;
+        check_vrf_key(&document, &self.public_key)?;
+        Ok(document)
+    }
+}
+
+/// Checks that a verified `document` attests the hex VRF key `public_key`.
+pub fn check_vrf_key(document: &AttestationDoc, public_key: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        document.user_data.as_deref().map(|user_data| &user_data[..]) == Some(VRF_KEY_ATTESTATION),
+        "document does not attest a VRF key"
+    );
+    anyhow::ensure!(
+        document.public_key.as_deref().map(|key| &key[..]) == Some(&hex::decode(public_key)?[..]),
+        "attested VRF key does not match"
+    );
+    Ok(())
+}
+
+impl StreamEnd {
+    pub fn commitment(&self, tokens: &[String]) -> InferenceCommitment {
+        InferenceCommitment {
+            transcript_hash: hex::encode(transcript_hash(&self.answer.request_id, tokens)),
+            ..self.answer.commitment()
+        }
+    }
+
+    /// Checks the received `tokens` against the attested transcript, with `root_cert` being
+    /// `AWS_ROOT_CERT` or the root of a `MockSecureModule`.
+    pub fn verify_transcript_with(
+        &self,
+        tokens: &[String],
+        root_cert: &[u8],
+    ) -> anyhow::Result<Option<AttestationDoc>> {
+        anyhow::ensure!(tokens.len() as u64 == self.chunks, "missing token chunks");
+        anyhow::ensure!(tokens.concat() == self.answer.answer, "answer mismatch");
+        if !self.answer.selected {
+            return Ok(None);
+        }
+        let document = verify_document(
+            &self.answer.document,
+            root_cert,
+            std::time::SystemTime::UNIX_EPOCH.elapsed()?.as_secs(),
+        )?;
+        self.commitment(tokens)
+            .check(document.user_data.as_deref().map(|user_data| &user_data[..]))?;
+        Ok(Some(document))
+    }
+}
+
+impl PromptReq {
+    /// How the enclave is selected for the prompt.
+    pub fn selection(&self) -> anyhow::Result<Selection> {
+        match self.vrf_selection {
+            Some(selection) => {
+                selection.validate()?;
+                Ok(selection)
+            }
+            None => Selection::threshold(self.vrf_threshold, self.vrf_precision),
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.sampling.validate()?;
+        if !self.messages.is_empty() {
+            anyhow::ensure!(self.prompt.is_empty(), "both prompt and messages given");
+            chat::validate(&self.messages)?
+        }
+        Ok(())
+    }
+
+    /// Renders `messages` into `prompt`, by the template of the request or else of the model,
+    /// and records the template in use.
+    pub fn render(&mut self, model: &ResidentModel) -> anyhow::Result<()> {
+        if self.messages.is_empty() {
+            return Ok(());
+        }
+        let Some(template) = self.chat_template.or(model.chat_template) else {
+            anyhow::bail!("no chat template known for {}", model.name)
+        };
+        self.prompt = template.render(&self.messages)?;
+        self.chat_template = Some(template);
+        Ok(())
+    }
+}
+
+impl AnswerResp {
+    /// An answer to `req` without the inference part yet.
+    pub fn new(req: &PromptReq, vrf: VRFReply) -> Self {
+        Self {
+            request_id: req.request_id.clone(),
+            model_name: req.model_name.clone(),
+            prompt: req.prompt.clone(),
+            messages: req.messages.clone(),
+            chat_template: req.chat_template,
+            selected: vrf.selected,
+            vrf_prompt_hash: vrf.vrf_prompt_hash,
+            vrf_random_value: vrf.vrf_random_value,
+            vrf_verify_pubkey: vrf.vrf_verify_pubkey,
+            vrf_proof: vrf.vrf_proof,
+            vrf_batchable_proof: vrf.vrf_batchable_proof,
+            sampling: req.sampling.clone(),
+            n_predict: req.n_predict,
+            nonce: req.nonce.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_fixtures() -> anyhow::Result<()> {
+        use common::crypto::canonical::Fixture;
+
+        #[derive(Deserialize)]
+        struct Fixtures {
+            prompt_req: Vec<Fixture<PromptReq>>,
+            tee_req: Vec<Fixture<TEEReq>>,
+            answer_resp: Vec<Fixture<AnswerResp>>,
+            inference_commitment: Vec<Fixture<InferenceCommitment>>,
+            transcript: Vec<Fixture<(String, Vec<String>)>>,
+        }
+        let fixtures: Fixtures = serde_json::from_str(include_str!("../fixtures/canonical.json"))?;
+        fixtures.prompt_req.iter().try_for_each(Fixture::check)?;
+        fixtures.tee_req.iter().try_for_each(Fixture::check)?;
+        fixtures.answer_resp.iter().try_for_each(Fixture::check)?;
+        fixtures.inference_commitment.iter().try_for_each(Fixture::check)?;
+        assert_eq!(fixtures.answer_resp[0].value.commitment(), fixtures.inference_commitment[0].value);
+        for fixture in &fixtures.transcript {
+            let (request_id, tokens) = &fixture.value;
+            let mut encoding = Vec::new();
+            encode_bincode(&fixture.value, &mut encoding);
+            assert_eq!(hex::encode(encoding), fixture.encoding);
+            assert_eq!(hex::encode(transcript_hash(request_id, tokens)), fixture.digest);
+        }
+        Ok(())
+    }
+}
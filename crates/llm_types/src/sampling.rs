@@ -0,0 +1,285 @@
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Serializable mirror of `llama_cpp::standard_sampler::SamplerStage`, applied in order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Stage {
+    RepetitionPenalty {
+        repetition_penalty: f32,
+        frequency_penalty: f32,
+        presence_penalty: f32,
+        last_n: i32,
+    },
+    Temperature(f32),
+    TopP(f32),
+    MinP(f32),
+    TopK(i32),
+    Typical(f32),
+    TailFree(f32),
+}
+
+/// How the final token is picked after all stages.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Sampler {
+    MirostatV2 { tau: f32, eta: f32 },
+    Softmax { min_keep: usize },
+    Greedy,
+}
+
+/// Most stop sequences a request may carry.
+pub const MAX_STOP_SEQUENCES: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SamplingParams {
+    /// Seeds both the session and the sampler. Left empty in a request, the enclave picks one and
+    /// reports it in the answer, so that every answer can be reproduced.
+    pub seed: Option<u32>,
+    pub stages: Vec<Stage>,
+    pub sampler: Sampler,
+    /// Generation ends before the first of them, which is left out of the answer.
+    pub stop: Vec<String>,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        SamplerOptions::default().to_params(None)
+    }
+}
+
+/// Sampler knobs as exposed to requesters. Defaults are the setup that used to be hardcoded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SamplerOptions {
+    pub temperature: f32,
+    pub top_p: f32,
+    pub top_k: i32,
+    pub min_p: f32,
+    pub typical: f32,
+    pub repetition_penalty: f32,
+    pub frequency_penalty: f32,
+    pub presence_penalty: f32,
+    /// Tokens looked back by the penalties, -1 for the whole context.
+    pub penalty_last_n: i32,
+    /// Picks the token by mirostat v2, or else samples from the remaining candidates.
+    pub mirostat: bool,
+    pub mirostat_tau: f32,
+    pub mirostat_eta: f32,
+    /// One stop sequence or many, as OpenAI takes them.
+    #[serde(deserialize_with = "one_or_many")]
+    pub stop: Vec<String>,
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match Option::<OneOrMany>::deserialize(deserializer)? {
+        None => Vec::new(),
+        Some(OneOrMany::One(stop)) => vec![stop],
+        Some(OneOrMany::Many(stop)) => stop,
+    })
+}
+
+impl Default for SamplerOptions {
+    fn default() -> Self {
+        Self {
+            temperature: 0.0,
+            top_p: 0.95,
+            top_k: 40,
+            min_p: 0.05,
+            typical: 1.0,
+            repetition_penalty: 1.0,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            penalty_last_n: 64,
+            mirostat: true,
+            mirostat_tau: 0.1,
+            mirostat_eta: 5.0,
+            stop: Vec::new(),
+        }
+    }
+}
+
+impl SamplerOptions {
+    pub fn to_params(&self, seed: Option<u32>) -> SamplingParams {
+        SamplingParams {
+            seed,
+            stages: vec![
+                Stage::RepetitionPenalty {
+                    repetition_penalty: self.repetition_penalty,
+                    frequency_penalty: self.frequency_penalty,
+                    presence_penalty: self.presence_penalty,
+                    last_n: self.penalty_last_n,
+                },
+                Stage::TopK(self.top_k),
+                Stage::TopP(self.top_p),
+                Stage::MinP(self.min_p),
+                Stage::Typical(self.typical),
+                Stage::Temperature(self.temperature),
+            ],
+            sampler: if self.mirostat {
+                Sampler::MirostatV2 {
+                    tau: self.mirostat_tau,
+                    eta: self.mirostat_eta,
+                }
+            } else {
+                Sampler::Softmax { min_keep: 1 }
+            },
+            stop: self.stop.clone(),
+        }
+    }
+}
+
+impl SamplingParams {
+    /// The default setup, with temperature and top p set.
+    pub fn with(temperature: f32, top_p: f32, seed: Option<u32>) -> Self {
+        SamplerOptions {
+            temperature,
+            top_p,
+            ..Default::default()
+        }
+        .to_params(seed)
+    }
+
+    /// Rejects values llama.cpp would misbehave on.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        fn within(name: &str, value: f32, min: f32, max: f32) -> anyhow::Result<()> {
+            anyhow::ensure!(
+                (min..=max).contains(&value),
+                "{name} {value} out of range [{min}, {max}]"
+            );
+            Ok(())
+        }
+        for stage in &self.stages {
+            match *stage {
+                Stage::RepetitionPenalty {
+                    repetition_penalty,
+                    frequency_penalty,
+                    presence_penalty,
+                    last_n,
+                } => {
+                    within("repetition penalty", repetition_penalty, f32::EPSILON, f32::MAX)?;
+                    within("frequency penalty", frequency_penalty, -2.0, 2.0)?;
+                    within("presence penalty", presence_penalty, -2.0, 2.0)?;
+                    anyhow::ensure!(last_n >= -1, "penalty last n {last_n} below -1")
+                }
+                Stage::Temperature(t) => within("temperature", t, 0.0, f32::MAX)?,
+                Stage::TopP(p) => within("top p", p, f32::EPSILON, 1.0)?,
+                Stage::MinP(p) => within("min p", p, 0.0, 1.0)?,
+                Stage::TopK(k) => anyhow::ensure!(k >= 0, "top k {k} is negative"),
+                Stage::Typical(p) => within("typical p", p, f32::EPSILON, 1.0)?,
+                Stage::TailFree(z) => within("tail free z", z, f32::EPSILON, 1.0)?,
+            }
+        }
+        if let Sampler::MirostatV2 { tau, eta } = self.sampler {
+            within("mirostat tau", tau, f32::EPSILON, f32::MAX)?;
+            within("mirostat eta", eta, f32::EPSILON, f32::MAX)?
+        }
+        anyhow::ensure!(
+            self.stop.len() <= MAX_STOP_SEQUENCES,
+            "more than {MAX_STOP_SEQUENCES} stop sequences"
+        );
+        anyhow::ensure!(
+            self.stop.iter().all(|stop| !stop.is_empty()),
+            "empty stop sequence"
+        );
+        Ok(())
+    }
+
+    /// Fills in a random seed if none is given, returns the seed in use.
+    pub fn resolve_seed(&mut self) -> u32 {
+        *self.seed.get_or_insert_with(rand::random)
+    }
+}
+
+/// How much of the generated `text` can be handed out, and whether generation should end there.
+///
+/// Ends at the first stop sequence if there is one, otherwise holds back any tail that may turn
+/// into a stop sequence with the next tokens. What is handed out never shrinks as `text` grows,
+/// so streamed chunks always add up to the final answer.
+pub fn stop_point(text: &str, stop: &[String]) -> (usize, bool) {
+    let stop = stop.iter().filter(|stop| !stop.is_empty());
+    if let Some(pos) = stop.clone().filter_map(|stop| text.find(stop.as_str())).min() {
+        return (pos, true);
+    }
+    let held = text
+        .char_indices()
+        .map(|(i, _)| i)
+        .find(|&i| stop.clone().any(|stop| stop.starts_with(&text[i..])));
+    (held.unwrap_or(text.len()), false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_seed() {
+        let mut params = SamplingParams::with(0.0, 0.95, Some(42));
+        assert_eq!(params.resolve_seed(), 42);
+        let mut params = SamplingParams::default();
+        let seed = params.resolve_seed();
+        assert_eq!(params.seed, Some(seed));
+        // stable once resolved
+        assert_eq!(params.resolve_seed(), seed);
+    }
+
+    #[test]
+    fn validate() {
+        assert!(SamplingParams::default().validate().is_ok());
+        let options = SamplerOptions {
+            mirostat: false,
+            stop: vec!["\n\n".into()],
+            ..Default::default()
+        };
+        assert!(options.to_params(None).validate().is_ok());
+        for options in [
+            SamplerOptions {
+                top_p: 0.0,
+                ..Default::default()
+            },
+            SamplerOptions {
+                top_k: -1,
+                ..Default::default()
+            },
+            SamplerOptions {
+                temperature: f32::NAN,
+                ..Default::default()
+            },
+            SamplerOptions {
+                presence_penalty: 3.0,
+                ..Default::default()
+            },
+            SamplerOptions {
+                mirostat_tau: 0.0,
+                ..Default::default()
+            },
+            SamplerOptions {
+                stop: vec![String::new()],
+                ..Default::default()
+            },
+            SamplerOptions {
+                stop: vec!["a".into(); MAX_STOP_SEQUENCES + 1],
+                ..Default::default()
+            },
+        ] {
+            assert!(options.to_params(None).validate().is_err(), "{options:?}")
+        }
+    }
+
+    #[test]
+    fn stop_point_holds_back_partial_stop() {
+        let stop = ["</s>".to_string(), "User:".to_string()];
+        assert_eq!(stop_point("Hello", &stop), (5, false));
+        // "</" may become "</s>"
+        assert_eq!(stop_point("Hello </", &stop), (6, false));
+        assert_eq!(stop_point("Hello </p>", &stop), (10, false));
+        assert_eq!(stop_point("Hello </s> World", &stop), (6, true));
+        // the earliest one wins
+        assert_eq!(stop_point("Hi User: </s>", &stop), (3, true));
+        assert_eq!(stop_point("Hello", &[]), (5, false));
+        assert_eq!(stop_point("héllo wö", &["ö!".into()]), (8, false));
+    }
+}
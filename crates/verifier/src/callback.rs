@@ -0,0 +1,218 @@
+use common::{
+    crypto::{canonical::CanonicalDigest, recovery::public_key_to_address},
+    ordinary_clock::OrdinaryClock,
+};
+use secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
+use serde::{Deserialize, Serialize};
+use llm_types::{
+    chat::{ChatMessage, ChatTemplate},
+    commitment::InferenceCommitment,
+    sampling::SamplingParams,
+};
+use vrf::{batch::BatchableProof, ecvrf::VRFPublicKey, traits::CryptoMaterialError};
+
+/// An answer as called back to the dispatcher, everything needed to check it offline.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct AnswerCallbackReq {
+    pub request_id: String,
+    pub node_id: String,
+    pub model: String,
+    pub prompt: String,
+    pub messages: Vec<ChatMessage>,
+    pub chat_template: Option<ChatTemplate>,
+    pub answer: String,
+    pub elapsed: u64,
+    pub selected: bool,
+    pub vrf_proof: VRFProof,
+    pub tee_credential: TEECredential,
+    // the rest of the attested inference commitment
+    pub commitment_version: u32,
+    pub model_hash: String,
+    pub sampling: SamplingParams,
+    pub max_tokens: u32,
+    pub nonce: String,
+    /// Hex transcript hash of a streamed answer, empty otherwise.
+    #[serde(default)]
+    pub transcript_hash: String,
+    /// Causal timestamp of the answer, the clock of the node ticked for it in its VLC enclave.
+    #[serde(default)]
+    pub clock: Option<ClockCredential>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct VRFProof {
+    pub vrf_prompt_hash: String,
+    pub vrf_random_value: String,
+    pub vrf_verify_pubkey: String,
+    pub vrf_proof: String,
+    /// Hex `BatchableProof` of the same evaluation, `Gamma || U || V || s`, the points compressed
+    /// and the scalar little endian, 128 bytes. Empty from enclaves that predate it.
+    #[serde(default)]
+    pub vrf_batchable_proof: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct TEECredential {
+    /// Base64 of the attestation document.
+    pub tee_attestation: String,
+    /// Hex secp256k1 signature of the operator over the SHA-256 of the bytes of `tee_attestation`,
+    /// its `canonical_digest`, `r || s || v`.
+    pub tee_attest_signature: String,
+}
+
+/// A clock as attested by a VLC enclave.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct ClockCredential {
+    pub clock: OrdinaryClock,
+    /// Base64 of the attestation document, over the SHA-256 of `clock`.
+    pub tee_attestation: String,
+}
+
+/// The attested VRF key of a node, as served by the operator at `/api/v1/vrf/key`, for the
+/// dispatcher to pin `vrf_verify_pubkey` of its answers to.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct VrfKeyReq {
+    pub node_id: String,
+    /// Hex VRF public key.
+    pub public_key: String,
+    pub tee_credential: TEECredential,
+}
+
+impl TEECredential {
+    pub fn document(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(base64::decode(&self.tee_attestation)?)
+    }
+
+    /// Address of the operator key that signed the attestation, `0x` prefixed lowercase hex.
+    pub fn signer(&self) -> anyhow::Result<String> {
+        let signature = hex::decode(self.tee_attest_signature.trim_start_matches("0x"))?;
+        anyhow::ensure!(
+            signature.len() == 65,
+            "attestation signature is not 65 bytes"
+        );
+        // 27 or 28, in the Ethereum way
+        let recovery_id = RecoveryId::from_i32(i32::from(signature[64]) % 27)?;
+        let signature = RecoverableSignature::from_compact(&signature[..64], recovery_id)?;
+        let message = secp256k1::Message::from_digest(self.canonical_digest());
+        let public_key =
+            secp256k1::Secp256k1::verification_only().recover_ecdsa(&message, &signature)?;
+        Ok(public_key_to_address(&hex::encode(
+            public_key.serialize_uncompressed(),
+        )))
+    }
+}
+
+impl VRFProof {
+    /// The public key, the input and the batchable proof to batch verify, once the batchable
+    /// proof is checked to be the one of `vrf_proof`.
+    pub fn batchable(&self) -> anyhow::Result<(VRFPublicKey, &[u8], BatchableProof)> {
+        let material = |err: CryptoMaterialError| anyhow::anyhow!("{err}");
+        let public_key = hex::decode(self.vrf_verify_pubkey.trim_start_matches("0x"))?;
+        let public_key = VRFPublicKey::try_from(&public_key[..]).map_err(material)?;
+        let batchable = hex::decode(self.vrf_batchable_proof.trim_start_matches("0x"))?;
+        let batchable = BatchableProof::try_from(&batchable[..]).map_err(material)?;
+        let proof = hex::decode(self.vrf_proof.trim_start_matches("0x"))?;
+        let alpha = self.vrf_prompt_hash.as_bytes();
+        anyhow::ensure!(
+            batchable.to_proof(&public_key, alpha).to_bytes()[..] == proof[..],
+            "batchable proof is not the one of vrf_proof"
+        );
+        Ok((public_key, alpha, batchable))
+    }
+}
+
+/// What the operator signs, the base64 attestation document as is.
+impl CanonicalDigest for TEECredential {
+    fn encode_canonical(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.tee_attestation.as_bytes())
+    }
+}
+
+impl ClockCredential {
+    pub fn document(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(base64::decode(&self.tee_attestation)?)
+    }
+}
+
+impl AnswerCallbackReq {
+    /// The commitment the enclave attested for this answer.
+    pub fn commitment(&self) -> InferenceCommitment {
+        InferenceCommitment {
+            version: self.commitment_version,
+            request_id: self.request_id.clone(),
+            model_hash: self.model_hash.clone(),
+            prompt: self.prompt.clone(),
+            messages: self.messages.clone(),
+            chat_template: self.chat_template,
+            sampling: self.sampling.clone(),
+            n_predict: self.max_tokens as _,
+            vrf_prompt_hash: self.vrf_proof.vrf_prompt_hash.clone(),
+            vrf_random_value: self.vrf_proof.vrf_random_value.clone(),
+            vrf_verify_pubkey: self.vrf_proof.vrf_verify_pubkey.clone(),
+            vrf_proof: self.vrf_proof.vrf_proof.clone(),
+            answer: self.answer.clone(),
+            transcript_hash: self.transcript_hash.clone(),
+            nonce: self.nonce.clone(),
+        }
+    }
+
+    pub fn document(&self) -> anyhow::Result<Vec<u8>> {
+        self.tee_credential.document()
+    }
+
+    pub fn attest_signer(&self) -> anyhow::Result<String> {
+        self.tee_credential.signer()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recover_attest_signer() -> anyhow::Result<()> {
+        let secp = secp256k1::Secp256k1::new();
+        let secret_key = secp256k1::SecretKey::from_slice(&[1; 32])?;
+        let mut req = AnswerCallbackReq::default();
+        req.tee_credential.tee_attestation = "document".into();
+        let message = secp256k1::Message::from_digest(req.tee_credential.canonical_digest());
+        let (recovery_id, signature) = secp
+            .sign_ecdsa_recoverable(&message, &secret_key)
+            .serialize_compact();
+        let mut signature = signature.to_vec();
+        signature.push(recovery_id.to_i32() as u8 + 27);
+        req.tee_credential.tee_attest_signature = format!("0x{}", hex::encode(&signature));
+
+        let public_key = secret_key.public_key(&secp).serialize_uncompressed();
+        assert_eq!(
+            req.attest_signer()?,
+            public_key_to_address(&hex::encode(public_key))
+        );
+        req.tee_credential.tee_attestation = "other".into();
+        assert_ne!(
+            req.attest_signer()?,
+            public_key_to_address(&hex::encode(public_key))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn signed_fixtures() -> anyhow::Result<()> {
+        use common::crypto::canonical::Fixture;
+
+        /// Signed with the secret key of 32 bytes of 1.
+        #[derive(Deserialize)]
+        struct Signed {
+            #[serde(flatten)]
+            fixture: Fixture<TEECredential>,
+            signer: String,
+        }
+        let fixtures: Vec<Signed> =
+            serde_json::from_str(include_str!("../fixtures/tee_credentials.json"))?;
+        for Signed { fixture, signer } in &fixtures {
+            fixture.check()?;
+            assert_eq!(&fixture.value.signer()?, signer);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,340 @@
+//! Offline verification of what enclaves attest: the document and its certificate chain, the
+//! enclave measurements against a `PcrPolicy`, and the `user_data` against the commitment it is
+//! expected to carry.
+
+pub mod callback;
+
+pub use common::pcr_policy as policy;
+
+use common::{
+    attestation::{verify_document, AttestationDoc},
+    crypto::canonical::CanonicalDigest as _,
+    ordinary_clock::OrdinaryClock,
+};
+use llm_types::{
+    commitment::{InferenceCommitment, INFERENCE_COMMITMENT_VERSION},
+    nitro_llm::check_vrf_key,
+};
+use vrf::batch::batch_verify;
+
+use crate::{
+    callback::{AnswerCallbackReq, ClockCredential, VrfKeyReq},
+    policy::PcrPolicy,
+};
+
+/// What an enclave attests as `user_data`.
+pub trait Commitment {
+    fn digest(&self) -> anyhow::Result<[u8; 32]>;
+}
+
+/// Attested by the LLM enclave.
+impl Commitment for InferenceCommitment {
+    fn digest(&self) -> anyhow::Result<[u8; 32]> {
+        anyhow::ensure!(
+            self.version == INFERENCE_COMMITMENT_VERSION,
+            "unsupported commitment version {}",
+            self.version
+        );
+        Ok(InferenceCommitment::digest(self))
+    }
+}
+
+/// Attested by the VLC enclave.
+impl Commitment for OrdinaryClock {
+    fn digest(&self) -> anyhow::Result<[u8; 32]> {
+        Ok(self.canonical_digest())
+    }
+}
+
+/// The trusted root if none is given, AWS's.
+#[cfg(feature = "nitro-enclaves")]
+pub fn default_root_cert() -> Option<&'static [u8]> {
+    Some(&aws_nitro_enclaves_attestation::AWS_ROOT_CERT[..])
+}
+
+/// No trusted root without the `nitro-enclaves` feature, it has to be given.
+#[cfg(not(feature = "nitro-enclaves"))]
+pub fn default_root_cert() -> Option<&'static [u8]> {
+    None
+}
+
+pub fn now() -> u64 {
+    std::time::SystemTime::UNIX_EPOCH
+        .elapsed()
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// Verifies a COSE_Sign1 document up to `root_cert` (DER) with the certificates valid at unix
+/// time `at`, and its PCRs against `policy`.
+pub fn verify_attestation(
+    document: &[u8],
+    root_cert: &[u8],
+    at: u64,
+    policy: &PcrPolicy,
+) -> anyhow::Result<AttestationDoc> {
+    let doc = verify_document(document, root_cert, at)?;
+    policy.check(&doc)?;
+    Ok(doc)
+}
+
+pub fn check_user_data(doc: &AttestationDoc, commitment: &impl Commitment) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        doc.user_data.as_deref().map(|user_data| &user_data[..]) == Some(&commitment.digest()?[..]),
+        "attested user data does not match commitment"
+    );
+    Ok(())
+}
+
+/// Verifies a saved answer callback, `None` if the node was not selected, in which case nothing
+/// is attested.
+pub fn verify_answer(
+    req: &AnswerCallbackReq,
+    root_cert: &[u8],
+    at: u64,
+    policy: &PcrPolicy,
+) -> anyhow::Result<Option<AttestationDoc>> {
+    if !req.selected {
+        return Ok(None);
+    }
+    let doc = verify_attestation(&req.document()?, root_cert, at, policy)?;
+    check_user_data(&doc, &req.commitment())?;
+    Ok(Some(doc))
+}
+
+/// Verifies the attested VRF key of a node, which the `vrf_verify_pubkey` of its answers are
+/// then expected to equal.
+pub fn verify_vrf_key(
+    req: &VrfKeyReq,
+    root_cert: &[u8],
+    at: u64,
+    policy: &PcrPolicy,
+) -> anyhow::Result<AttestationDoc> {
+    let doc = verify_attestation(&req.tee_credential.document()?, root_cert, at, policy)?;
+    check_vrf_key(&doc, &req.public_key)?;
+    Ok(doc)
+}
+
+/// Verifies the VRF proofs of many answers at once, with their batchable proofs, or returns the
+/// indices of the answers whose proof is invalid or missing, in order.
+pub fn verify_vrf_proofs(reqs: &[AnswerCallbackReq]) -> Result<(), Vec<usize>> {
+    let mut invalid = Vec::new();
+    let mut indices = Vec::with_capacity(reqs.len());
+    let mut items = Vec::with_capacity(reqs.len());
+    for (index, req) in reqs.iter().enumerate() {
+        match req.vrf_proof.batchable() {
+            Ok(item) => {
+                indices.push(index);
+                items.push(item)
+            }
+            Err(_) => invalid.push(index),
+        }
+    }
+    let batch: Vec<_> = items
+        .iter()
+        .map(|(public_key, alpha, proof)| (public_key, *alpha, proof))
+        .collect();
+    if let Err(failed) = batch_verify(&batch) {
+        invalid.extend(failed.into_iter().map(|index| indices[index]));
+        invalid.sort_unstable();
+    }
+    if invalid.is_empty() {
+        Ok(())
+    } else {
+        Err(invalid)
+    }
+}
+
+/// Verifies the causal timestamp of an answer, against the policy of the VLC enclave rather than
+/// the one of the LLM enclave.
+pub fn verify_clock(
+    clock: &ClockCredential,
+    root_cert: &[u8],
+    at: u64,
+    policy: &PcrPolicy,
+) -> anyhow::Result<AttestationDoc> {
+    let doc = verify_attestation(&clock.document()?, root_cert, at, policy)?;
+    check_user_data(&doc, &clock.clock)?;
+    Ok(doc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{
+        mock_secure::{MockSecureModule, PCR_LENGTH},
+        nitro_secure::SecureModule as _,
+    };
+    use llm_types::{nitro_llm::VRF_KEY_ATTESTATION, sampling::SamplingParams};
+    use vrf::ecvrf::VRFPublicKey;
+
+    use crate::callback::VRFProof;
+
+    fn answer(nsm: &MockSecureModule) -> anyhow::Result<AnswerCallbackReq> {
+        let mut req = AnswerCallbackReq {
+            request_id: "1".into(),
+            model: "llama-2-7b-chat.Q4_0.gguf".into(),
+            prompt: "How to combine AI and blockchain?".into(),
+            answer: "42".into(),
+            selected: true,
+            commitment_version: INFERENCE_COMMITMENT_VERSION,
+            model_hash: "00".into(),
+            sampling: SamplingParams::with(0.0, 0.95, Some(42)),
+            max_tokens: 16,
+            ..Default::default()
+        };
+        let document = nsm.process_attestation(req.commitment().digest().to_vec())?;
+        req.tee_credential.tee_attestation = base64::encode(document);
+        Ok(req)
+    }
+
+    #[test]
+    fn verify_saved_answer() -> anyhow::Result<()> {
+        let nsm = MockSecureModule::new()?;
+        let req = answer(&nsm)?;
+        let doc = verify_document(&req.document()?, nsm.root_certificate(), now())?;
+        let policy = PcrPolicy::of(&doc);
+        policy.validate()?;
+        anyhow::ensure!(verify_answer(&req, nsm.root_certificate(), now(), &policy)?.is_some());
+
+        // certificates of the mock module are not valid yet back then
+        assert!(verify_answer(&req, nsm.root_certificate(), 0, &policy).is_err());
+        let other = MockSecureModule::new()?;
+        assert!(verify_answer(&req, other.root_certificate(), now(), &policy).is_err());
+
+        let mut tampered = answer(&nsm)?;
+        tampered.answer = "43".into();
+        assert!(verify_answer(&tampered, nsm.root_certificate(), now(), &policy).is_err());
+        tampered.selected = false;
+        assert!(verify_answer(&tampered, nsm.root_certificate(), now(), &policy)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn pcr_policy() -> anyhow::Result<()> {
+        let nsm = MockSecureModule::with_pcrs(
+            (0..3).map(|index| (index, vec![index as u8; PCR_LENGTH])),
+        )?;
+        let req = answer(&nsm)?;
+        let mut policy = PcrPolicy::default();
+        assert!(policy.validate().is_err());
+        for index in 0..3 {
+            policy
+                .pcrs
+                .insert(index, vec![hex::encode([index as u8; PCR_LENGTH])]);
+        }
+        policy.validate()?;
+        anyhow::ensure!(verify_answer(&req, nsm.root_certificate(), now(), &policy)?.is_some());
+
+        // an image upgrade is trusted alongside the current one
+        policy.pcrs.insert(2, vec![hex::encode([9; PCR_LENGTH])]);
+        let err = verify_answer(&req, nsm.root_certificate(), now(), &policy).unwrap_err();
+        assert!(err.to_string().starts_with("PCR2 "));
+        policy
+            .pcrs
+            .get_mut(&2)
+            .unwrap()
+            .push(hex::encode([2; PCR_LENGTH]).to_uppercase());
+        anyhow::ensure!(verify_answer(&req, nsm.root_certificate(), now(), &policy)?.is_some());
+
+        policy.pcrs.insert(8, vec![hex::encode([8; PCR_LENGTH])]);
+        let err = verify_answer(&req, nsm.root_certificate(), now(), &policy).unwrap_err();
+        assert_eq!(err.to_string(), "PCR8 missing from document");
+        Ok(())
+    }
+
+    #[test]
+    fn vrf_key() -> anyhow::Result<()> {
+        let nsm = MockSecureModule::new()?;
+        let public_key = [7; 32];
+        let document =
+            nsm.process_key_attestation(VRF_KEY_ATTESTATION.to_vec(), public_key.to_vec())?;
+        let mut req = VrfKeyReq {
+            public_key: hex::encode(public_key),
+            ..Default::default()
+        };
+        req.tee_credential.tee_attestation = base64::encode(document);
+        let policy = PcrPolicy::of(&verify_document(
+            &req.tee_credential.document()?,
+            nsm.root_certificate(),
+            now(),
+        )?);
+        verify_vrf_key(&req, nsm.root_certificate(), now(), &policy)?;
+
+        req.public_key = hex::encode([8; 32]);
+        assert!(verify_vrf_key(&req, nsm.root_certificate(), now(), &policy).is_err());
+        // the document of an answer is not one of a key
+        let answer = answer(&nsm)?;
+        req.tee_credential = answer.tee_credential;
+        assert!(verify_vrf_key(&req, nsm.root_certificate(), now(), &policy).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn batch_vrf_proofs() {
+        use vrf::{ecvrf::VRFPrivateKey, traits::Uniform as _};
+
+        let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::from_seed([0; 32]);
+        let mut reqs: Vec<_> = (0..8)
+            .map(|i| {
+                let private_key = VRFPrivateKey::generate_for_testing(&mut rng);
+                let public_key = VRFPublicKey::from(&private_key);
+                let vrf_prompt_hash = format!("{i:064x}");
+                let alpha = vrf_prompt_hash.as_bytes();
+                AnswerCallbackReq {
+                    vrf_proof: VRFProof {
+                        vrf_verify_pubkey: hex::encode(public_key.as_bytes()),
+                        vrf_proof: hex::encode(private_key.prove(alpha).to_bytes()),
+                        vrf_batchable_proof: hex::encode(
+                            private_key.prove_batchable(alpha).to_bytes(),
+                        ),
+                        vrf_prompt_hash,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                }
+            })
+            .collect();
+        assert_eq!(verify_vrf_proofs(&reqs), Ok(()));
+
+        // proven for another prompt, not the batchable proof of `vrf_proof`, and missing
+        reqs[1].vrf_proof.vrf_prompt_hash = format!("{:064x}", 9);
+        let other = reqs[3].vrf_proof.vrf_batchable_proof.clone();
+        reqs[2].vrf_proof.vrf_batchable_proof = other;
+        reqs[6].vrf_proof.vrf_batchable_proof.clear();
+        assert_eq!(verify_vrf_proofs(&reqs), Err(vec![1, 2, 6]));
+    }
+
+    #[test]
+    fn answer_clock() -> anyhow::Result<()> {
+        let nsm = MockSecureModule::new()?;
+        let clock = OrdinaryClock([(1, 2), (7, 1)].into_iter().collect());
+        let document = nsm.process_attestation(Commitment::digest(&clock)?.to_vec())?;
+        let mut credential = ClockCredential {
+            clock,
+            tee_attestation: base64::encode(document),
+        };
+        let policy = PcrPolicy::of(&verify_document(
+            &credential.document()?,
+            nsm.root_certificate(),
+            now(),
+        )?);
+        verify_clock(&credential, nsm.root_certificate(), now(), &policy)?;
+
+        // the JSON of a callback carries it as is, and is read without it as well
+        let mut req = answer(&nsm)?;
+        req.clock = Some(credential.clone());
+        let json = serde_json::to_string(&req)?;
+        let read: AnswerCallbackReq = serde_json::from_str(&json)?;
+        assert_eq!(read.clock.as_ref(), Some(&credential));
+        let mut json = serde_json::to_value(&req)?;
+        json.as_object_mut().unwrap().remove("clock");
+        assert!(serde_json::from_value::<AnswerCallbackReq>(json)?
+            .clock
+            .is_none());
+
+        *credential.clock.0.get_mut(&1).unwrap() += 1;
+        assert!(verify_clock(&credential, nsm.root_certificate(), now(), &policy).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,88 @@
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+use verifier::{
+    callback::{AnswerCallbackReq, VrfKeyReq},
+    policy::PcrPolicy,
+};
+
+/// Verifies a saved answer callback offline: the attestation document, its certificate chain,
+/// the enclave PCRs and the inference commitment.
+#[derive(StructOpt)]
+struct VerifierCli {
+    #[structopt(
+        short = "p",
+        long = "policy",
+        parse(from_os_str),
+        help = "PCR policy, JSON"
+    )]
+    policy: PathBuf,
+
+    #[structopt(
+        long = "root-cert",
+        parse(from_os_str),
+        help = "DER root certificate, AWS's by default with the nitro-enclaves feature"
+    )]
+    root_cert: Option<PathBuf>,
+
+    #[structopt(
+        long = "at",
+        help = "Unix time to validate the certificates at, now by default"
+    )]
+    at: Option<u64>,
+
+    #[structopt(
+        long = "vrf-key",
+        parse(from_os_str),
+        help = "Attested VRF key of the node, JSON, to check the VRF proof was made with"
+    )]
+    vrf_key: Option<PathBuf>,
+
+    #[structopt(parse(from_os_str), help = "Answer callback, JSON")]
+    answer: PathBuf,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = VerifierCli::from_args();
+    let policy = PcrPolicy::load(&cli.policy)?;
+    let root_cert = match &cli.root_cert {
+        Some(path) => std::fs::read(path)?,
+        None => match verifier::default_root_cert() {
+            Some(root_cert) => root_cert.to_vec(),
+            None => anyhow::bail!("--root-cert is required without the nitro-enclaves feature"),
+        },
+    };
+    let at = cli.at.unwrap_or_else(verifier::now);
+    let req = serde_json::from_slice::<AnswerCallbackReq>(&std::fs::read(&cli.answer)?)?;
+
+    println!(
+        "request {} answered by node {}",
+        req.request_id, req.node_id
+    );
+    match req.attest_signer() {
+        Ok(signer) => println!("attestation signed by {signer}"),
+        Err(err) => println!("attestation signature not recovered: {err}"),
+    }
+    if let Some(path) = &cli.vrf_key {
+        let key = serde_json::from_slice::<VrfKeyReq>(&std::fs::read(path)?)?;
+        verifier::verify_vrf_key(&key, &root_cert, at, &policy)?;
+        println!("vrf key {} attested", key.public_key);
+        anyhow::ensure!(
+            req.vrf_proof.vrf_verify_pubkey == key.public_key,
+            "vrf proof made with another key {}",
+            req.vrf_proof.vrf_verify_pubkey
+        );
+    }
+    match verifier::verify_answer(&req, &root_cert, at, &policy)? {
+        None => println!("not selected, nothing attested"),
+        Some(doc) => {
+            for (index, value) in &doc.pcrs {
+                if policy.pcrs.contains_key(index) {
+                    println!("PCR{index} {}", hex::encode(value))
+                }
+            }
+            println!("inference commitment matches, answer verified")
+        }
+    }
+    Ok(())
+}
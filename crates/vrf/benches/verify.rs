@@ -0,0 +1,64 @@
+//! `cargo bench -p vrf`: verifying proofs one by one against in batches.
+
+use std::time::Instant;
+
+use rand::{rngs::StdRng, SeedableRng};
+use vrf::{
+    batch::{batch_verify, BatchableProof},
+    ecvrf::{Proof, VRFPrivateKey, VRFPublicKey},
+    traits::Uniform,
+};
+
+const ROUNDS: usize = 3;
+
+fn main() {
+    let mut rng = StdRng::from_seed([0; 32]);
+    for n in [1, 16, 256, 1024] {
+        let keys: Vec<_> = (0..n)
+            .map(|_| {
+                let private_key = VRFPrivateKey::generate_for_testing(&mut rng);
+                let public_key = VRFPublicKey::from(&private_key);
+                (private_key, public_key)
+            })
+            .collect();
+        let alphas: Vec<_> = (0..n).map(|i| format!("prompt {i}").into_bytes()).collect();
+        let batchable: Vec<BatchableProof> = keys
+            .iter()
+            .zip(&alphas)
+            .map(|((private_key, _), alpha)| private_key.prove_batchable(alpha))
+            .collect();
+        let proofs: Vec<Proof> = keys
+            .iter()
+            .zip(&alphas)
+            .zip(&batchable)
+            .map(|(((_, public_key), alpha), proof)| proof.to_proof(public_key, alpha))
+            .collect();
+        let items: Vec<_> = keys
+            .iter()
+            .zip(&alphas)
+            .zip(&batchable)
+            .map(|(((_, public_key), alpha), proof)| (public_key, &alpha[..], proof))
+            .collect();
+
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            for (((_, public_key), alpha), proof) in keys.iter().zip(&alphas).zip(&proofs) {
+                public_key.verify(proof, alpha).unwrap()
+            }
+        }
+        let single = start.elapsed() / (ROUNDS * n) as u32;
+
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            batch_verify(&items).unwrap()
+        }
+        let batch = start.elapsed() / (ROUNDS * n) as u32;
+
+        println!(
+            "n = {n:>4}: verify {:>8.1} us/proof, batch_verify {:>8.1} us/proof, {:.2}x",
+            single.as_secs_f64() * 1e6,
+            batch.as_secs_f64() * 1e6,
+            single.as_secs_f64() / batch.as_secs_f64()
+        );
+    }
+}
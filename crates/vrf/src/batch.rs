@@ -0,0 +1,297 @@
+//! Batch verification of ECVRF proofs, for a verifier of many proofs at once, e.g. the
+//! dispatcher with the proofs of every operator in an epoch.
+//!
+//! A [`Proof`] carries the challenge `c` the verifier has to recompute the points `U = s*B - c*Y`
+//! and `V = s*H - c*Gamma` to hash against, one at a time. A [`BatchableProof`] carries `U` and
+//! `V` instead, the challenge is derived from them, and the two equations of many proofs are
+//! checked together, weighted randomly, as a single multiscalar multiplication.
+//!
+//! The equations are checked up to small order components, which the output, made of
+//! `Gamma` multiplied by the cofactor, does not depend on.
+//!
+//! The LLM enclave answers with both forms of a proof, `vrf_proof` and `vrf_batchable_proof`, as
+//! the hex of their `to_bytes`: `Gamma || U || V || s` for the batchable one, the points
+//! compressed and `s` little endian. Its `to_proof` has to be `vrf_proof`, which is the attested
+//! one, as `verifier::verify_vrf_proofs` checks.
+
+use core::convert::TryFrom;
+
+use curve25519_dalek::{
+    constants::ED25519_BASEPOINT_POINT,
+    edwards::{CompressedEdwardsY, EdwardsPoint},
+    scalar::Scalar as ed25519_Scalar,
+    traits::{IsIdentity, VartimeMultiscalarMul},
+};
+use rand::{rngs::OsRng, RngCore};
+
+use crate::{
+    ecvrf::{
+        hash_points, nonce_generation_bytes, Output, Proof, VRFExpandedPrivateKey, VRFPrivateKey,
+        VRFPublicKey,
+    },
+    traits::CryptoMaterialError,
+};
+
+/// The number of bytes of [`BatchableProof`]
+pub const BATCHABLE_PROOF_LENGTH: usize = 128;
+
+/// A VRF proof with the commitments `U` and `V` in place of the challenge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchableProof {
+    gamma: EdwardsPoint,
+    u: EdwardsPoint,
+    v: EdwardsPoint,
+    s: ed25519_Scalar,
+}
+
+impl VRFPrivateKey {
+    /// Same as `prove`, in the batchable form.
+    pub fn prove_batchable(&self, alpha: &[u8]) -> BatchableProof {
+        let pk = VRFPublicKey::from(self);
+        let expanded = VRFExpandedPrivateKey::from(self);
+        let h_point = pk.hash_to_curve(alpha);
+        let k_scalar = ed25519_Scalar::from_bytes_mod_order_wide(&nonce_generation_bytes(
+            expanded.nonce,
+            h_point,
+        ));
+        let gamma = h_point * expanded.key;
+        let u = ED25519_BASEPOINT_POINT * k_scalar;
+        let v = h_point * k_scalar;
+        let c_scalar = hash_points(&[h_point, gamma, u, v]);
+        BatchableProof {
+            gamma,
+            u,
+            v,
+            s: k_scalar + c_scalar * expanded.key,
+        }
+    }
+}
+
+impl BatchableProof {
+    pub fn to_bytes(&self) -> [u8; BATCHABLE_PROOF_LENGTH] {
+        let mut ret = [0u8; BATCHABLE_PROOF_LENGTH];
+        ret[..32].copy_from_slice(self.gamma.compress().as_bytes());
+        ret[32..64].copy_from_slice(self.u.compress().as_bytes());
+        ret[64..96].copy_from_slice(self.v.compress().as_bytes());
+        ret[96..].copy_from_slice(self.s.as_bytes());
+        ret
+    }
+
+    /// The equivalent [`Proof`], which verifies with `VRFPublicKey::verify` the same.
+    pub fn to_proof(&self, pk: &VRFPublicKey, alpha: &[u8]) -> Proof {
+        let h_point = pk.hash_to_curve(alpha);
+        let c_scalar = hash_points(&[h_point, self.gamma, self.u, self.v]);
+        Proof::new(self.gamma, c_scalar, self.s)
+    }
+
+    pub fn verify(&self, pk: &VRFPublicKey, alpha: &[u8]) -> anyhow::Result<()> {
+        batch_verify(&[(pk, alpha, self)])
+            .map_err(|_| anyhow::anyhow!("The proof failed to verify for this public key"))
+    }
+}
+
+impl TryFrom<&[u8]> for BatchableProof {
+    type Error = CryptoMaterialError;
+
+    fn try_from(bytes: &[u8]) -> Result<BatchableProof, CryptoMaterialError> {
+        if bytes.len() != BATCHABLE_PROOF_LENGTH {
+            return Err(CryptoMaterialError::WrongLengthError);
+        }
+        let point = |bytes: &[u8]| {
+            CompressedEdwardsY::from_slice(bytes)
+                .map_err(|_| CryptoMaterialError::WrongLengthError)?
+                .decompress()
+                .ok_or(CryptoMaterialError::DeserializationError)
+        };
+        let mut s_buf = [0u8; 32];
+        s_buf.copy_from_slice(&bytes[96..]);
+        Ok(BatchableProof {
+            gamma: point(&bytes[..32])?,
+            u: point(&bytes[32..64])?,
+            v: point(&bytes[64..96])?,
+            s: Option::from(ed25519_Scalar::from_canonical_bytes(s_buf))
+                .ok_or(CryptoMaterialError::CanonicalRepresentationError)?,
+        })
+    }
+}
+
+impl<'a> From<&'a BatchableProof> for Output {
+    fn from(proof: &'a BatchableProof) -> Output {
+        Output::of_gamma(&proof.gamma)
+    }
+}
+
+/// A proof with what its equations are checked by.
+struct Statement<'a> {
+    /// Of the proof among the verified ones.
+    index: usize,
+    proof: &'a BatchableProof,
+    pk_point: EdwardsPoint,
+    h_point: EdwardsPoint,
+    c: ed25519_Scalar,
+}
+
+impl<'a> Statement<'a> {
+    fn new(
+        index: usize,
+        pk: &VRFPublicKey,
+        alpha: &[u8],
+        proof: &'a BatchableProof,
+    ) -> Result<Self, CryptoMaterialError> {
+        let pk_point = CompressedEdwardsY::from_slice(pk.as_bytes())
+            .map_err(|_| CryptoMaterialError::WrongLengthError)?
+            .decompress()
+            .ok_or(CryptoMaterialError::DeserializationError)?;
+        let h_point = pk.hash_to_curve(alpha);
+        Ok(Self {
+            index,
+            proof,
+            pk_point,
+            h_point,
+            c: hash_points(&[h_point, proof.gamma, proof.u, proof.v]),
+        })
+    }
+}
+
+/// A random 128 bit scalar, a forged proof goes unnoticed in a batch with probability 2^-128.
+fn random_weight() -> ed25519_Scalar {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes[..16]);
+    ed25519_Scalar::from_bytes_mod_order(bytes)
+}
+
+/// `sum(z * (s*B - U - c*Y) + w * (s*H - V - c*Gamma)) == 0` for random `z` and `w` per proof.
+fn check(statements: &[Statement]) -> bool {
+    let mut basepoint_scalar = ed25519_Scalar::ZERO;
+    let mut scalars = Vec::with_capacity(5 * statements.len() + 1);
+    let mut points = Vec::with_capacity(5 * statements.len() + 1);
+    for statement in statements {
+        let (z, w) = (random_weight(), random_weight());
+        let proof = statement.proof;
+        basepoint_scalar += z * proof.s;
+        scalars.extend([-z, -(z * statement.c), w * proof.s, -w, -(w * statement.c)]);
+        points.extend([
+            proof.u,
+            statement.pk_point,
+            statement.h_point,
+            proof.v,
+            proof.gamma,
+        ]);
+    }
+    scalars.push(basepoint_scalar);
+    points.push(ED25519_BASEPOINT_POINT);
+    EdwardsPoint::vartime_multiscalar_mul(scalars, points)
+        .mul_by_cofactor()
+        .is_identity()
+}
+
+/// Narrows a failed batch down to the invalid proofs by halving it, which takes a few more
+/// batches per invalid proof rather than verifying all of them one by one.
+fn find_invalid(statements: &[Statement], invalid: &mut Vec<usize>) {
+    if check(statements) {
+        return;
+    }
+    if let [statement] = statements {
+        invalid.push(statement.index);
+        return;
+    }
+    let (left, right) = statements.split_at(statements.len() / 2);
+    find_invalid(left, invalid);
+    find_invalid(right, invalid);
+}
+
+/// Verifies all `(pk, alpha, proof)` at once, or returns the indices of the invalid ones, in
+/// order. A public key that is not a point on the curve makes its entry invalid.
+pub fn batch_verify(items: &[(&VRFPublicKey, &[u8], &BatchableProof)]) -> Result<(), Vec<usize>> {
+    let mut invalid = Vec::new();
+    let mut statements = Vec::with_capacity(items.len());
+    for (index, (pk, alpha, proof)) in items.iter().enumerate() {
+        match Statement::new(index, pk, alpha, proof) {
+            Ok(statement) => statements.push(statement),
+            Err(_) => invalid.push(index),
+        }
+    }
+    if !statements.is_empty() && !check(&statements) {
+        let (left, right) = statements.split_at(statements.len() / 2);
+        find_invalid(left, &mut invalid);
+        find_invalid(right, &mut invalid);
+        invalid.sort_unstable();
+    }
+    if invalid.is_empty() {
+        Ok(())
+    } else {
+        Err(invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::Uniform;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn keys(n: usize) -> Vec<(VRFPrivateKey, VRFPublicKey)> {
+        let mut rng = StdRng::from_seed([0; 32]);
+        (0..n)
+            .map(|_| {
+                let private_key = VRFPrivateKey::generate_for_testing(&mut rng);
+                let public_key = (&private_key).into();
+                (private_key, public_key)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_as_proof() {
+        let keys = keys(2);
+        let (private_key, public_key) = &keys[0];
+        let batchable = private_key.prove_batchable(b"alpha");
+        batchable.verify(public_key, b"alpha").unwrap();
+        assert!(batchable.verify(public_key, b"other").is_err());
+        assert!(batchable.verify(&keys[1].1, b"alpha").is_err());
+
+        let proof = batchable.to_proof(public_key, b"alpha");
+        public_key.verify(&proof, b"alpha").unwrap();
+        assert_eq!(proof.to_bytes(), private_key.prove(b"alpha").to_bytes());
+        assert_eq!(
+            Output::from(&batchable).to_bytes(),
+            Output::from(&proof).to_bytes()
+        );
+
+        let bytes = batchable.to_bytes();
+        assert_eq!(BatchableProof::try_from(&bytes[..]).unwrap(), batchable);
+        assert!(BatchableProof::try_from(&bytes[..100]).is_err());
+        let mut non_canonical = bytes;
+        non_canonical[127] = 0xff;
+        assert!(BatchableProof::try_from(&non_canonical[..]).is_err());
+    }
+
+    #[test]
+    fn find_invalid_entries() {
+        let keys = keys(16);
+        let alphas: Vec<_> = (0..keys.len()).map(|i| format!("prompt {i}")).collect();
+        let mut proofs: Vec<_> = keys
+            .iter()
+            .zip(&alphas)
+            .map(|((private_key, _), alpha)| private_key.prove_batchable(alpha.as_bytes()))
+            .collect();
+        let items = |proofs: &[BatchableProof]| -> Result<(), Vec<usize>> {
+            let items: Vec<_> = keys
+                .iter()
+                .zip(&alphas)
+                .zip(proofs)
+                .map(|(((_, public_key), alpha), proof)| (public_key, alpha.as_bytes(), proof))
+                .collect();
+            batch_verify(&items)
+        };
+        assert_eq!(items(&proofs), Ok(()));
+        assert_eq!(batch_verify(&[]), Ok(()));
+
+        // proven over another prompt, and by another key
+        proofs[3] = keys[3].0.prove_batchable(b"other");
+        proofs[11] = keys[12].0.prove_batchable(alphas[11].as_bytes());
+        assert_eq!(items(&proofs), Err(vec![3, 11]));
+        proofs.swap(0, 1);
+        assert_eq!(items(&proofs), Err(vec![0, 1, 3, 11]));
+    }
+}
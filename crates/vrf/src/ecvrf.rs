@@ -276,15 +276,19 @@ impl TryFrom<&[u8]> for Proof {
     type Error = CryptoMaterialError;
 
     fn try_from(bytes: &[u8]) -> std::result::Result<Proof, CryptoMaterialError> {
+        if bytes.len() != PROOF_LENGTH {
+            return Err(CryptoMaterialError::WrongLengthError);
+        }
+
         let mut c_buf = [0u8; 32];
         c_buf[..16].copy_from_slice(&bytes[32..48]);
         let mut s_buf = [0u8; 32];
         s_buf.copy_from_slice(&bytes[48..]);
         Ok(Proof {
             gamma: CompressedEdwardsY::from_slice(&bytes[..32])
-                .unwrap()
+                .map_err(|_| CryptoMaterialError::WrongLengthError)?
                 .decompress()
-                .unwrap(),
+                .ok_or(CryptoMaterialError::DeserializationError)?,
             c: ed25519_Scalar::from_bytes_mod_order(c_buf),
             s: ed25519_Scalar::from_bytes_mod_order(s_buf),
         })
@@ -304,11 +308,18 @@ impl Output {
 
 impl<'a> From<&'a Proof> for Output {
     fn from(proof: &'a Proof) -> Output {
+        Output::of_gamma(&proof.gamma)
+    }
+}
+
+impl Output {
+    /// The output of a proof of `gamma`
+    pub(super) fn of_gamma(gamma: &EdwardsPoint) -> Output {
         let mut output = [0u8; OUTPUT_LENGTH];
         output.copy_from_slice(
             &Sha512::new()
                 .chain(&[SUITE, THREE])
-                .chain(&proof.gamma.mul_by_cofactor().compress().to_bytes()[..])
+                .chain(&gamma.mul_by_cofactor().compress().to_bytes()[..])
                 .finalize()[..],
         );
         Output(output)
@@ -5,9 +5,11 @@
 //! (currently only ECVRF). VRFs can be used in the consensus protocol for leader election.
 
 pub mod ecvrf;
+pub mod batch;
 pub mod traits;
 pub mod test_utils;
 pub mod sample;
+pub mod sortition;
 
 #[cfg(test)]
 mod unit_tests;
@@ -1,5 +1,5 @@
 use num_bigint::BigUint;
-use num_traits::{One, Num};
+use num_traits::{Float, Num};
 
 #[derive(Debug, Clone, Copy)]
 pub struct Sampler {
@@ -13,15 +13,27 @@ impl Sampler {
         }
     }
 
-    pub fn hex_to_biguint(&self, hex_str: &str) -> BigUint {
-        BigUint::from_str_radix(hex_str, 16).expect("Invalid hex string")
+    pub fn hex_to_biguint(&self, hex_str: &str) -> anyhow::Result<BigUint> {
+        BigUint::from_str_radix(hex_str, 16)
+            .map_err(|err| anyhow::anyhow!("invalid hex {hex_str:?}, {err}"))
     }
-    
-    pub fn calculate_threshold(&self, probability: f64) -> BigUint {
-        // percision is based at bit
-        let max_output = BigUint::one() << self.precision;
-        let threshold = max_output * BigUint::from((probability * 100.0) as u64) / BigUint::from(100u64);
-        threshold
+
+    /// `floor(probability * 2^precision)`, exactly: an output of `precision` bits is below it
+    /// with `probability`, up to the resolution of `precision`.
+    pub fn calculate_threshold(&self, probability: f64) -> anyhow::Result<BigUint> {
+        anyhow::ensure!(
+            (0.0..=1.0).contains(&probability),
+            "probability {probability} out of [0, 1]"
+        );
+        // probability = mantissa * 2^exponent, with no rounding
+        let (mantissa, exponent, _) = probability.integer_decode();
+        let shift = exponent as i64 + self.precision as i64;
+        let mantissa = BigUint::from(mantissa);
+        Ok(if shift >= 0 {
+            mantissa << shift as usize
+        } else {
+            mantissa >> shift.unsigned_abs() as usize
+        })
     }
     
     pub fn meets_threshold(&self, output: &BigUint, threshold: &BigUint) -> bool {
@@ -38,10 +50,10 @@ mod tests {
     fn meets() {
         let sampler = Sampler::new(512);
         let vrf_output_hex = "a64c292ec45f6b252828aff9a02a0fe88d2fcc7f5fc61bb328f03f4c6c0657a9d26efb23b87647ff54f71cd51a6fa4c4e31661d8f72b41ff00ac4d2eec2ea7b3";
-        let vrf_output = sampler.hex_to_biguint(vrf_output_hex);
+        let vrf_output = sampler.hex_to_biguint(vrf_output_hex).unwrap();
 
         let target_probability = 0.1;
-        let threshold = sampler.calculate_threshold(target_probability);
+        let threshold = sampler.calculate_threshold(target_probability).unwrap();
         let meets = sampler.meets_threshold(&vrf_output, &threshold);
         if  meets {
             println!("Node is selected.");
@@ -51,4 +63,23 @@ mod tests {
         
         assert_eq!(meets, false);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn exact_threshold() {
+        let sampler = Sampler::new(8);
+        // no longer truncated to whole percents
+        assert_eq!(sampler.calculate_threshold(0.005).unwrap(), BigUint::from(1u32));
+        assert_eq!(sampler.calculate_threshold(0.125).unwrap(), BigUint::from(32u32));
+        assert_eq!(sampler.calculate_threshold(1.0).unwrap(), BigUint::from(256u32));
+        assert_eq!(sampler.calculate_threshold(0.0).unwrap(), BigUint::from(0u32));
+        let sampler = Sampler::new(512);
+        assert_eq!(
+            sampler.calculate_threshold(0.5).unwrap(),
+            BigUint::from(1u32) << 511
+        );
+        assert!(sampler.calculate_threshold(1.5).is_err());
+        assert!(sampler.calculate_threshold(f64::NAN).is_err());
+        assert!(sampler.hex_to_biguint("xyz").is_err());
+        assert!(sampler.hex_to_biguint("").is_err());
+    }
+}
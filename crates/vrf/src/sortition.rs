@@ -0,0 +1,393 @@
+//! Sortition by VRF: a node is selected for a prompt by the output of its proof over the prompt
+//! hash. Anyone with the public key and the proof can recompute the output, and the selection
+//! with it.
+//!
+//! The output is read as a uniform fraction `x = output / 2^512` of `[0, 1)`. A node of
+//! `Selection::Fraction` is selected when `x < numerator / denominator`, compared exactly. A node
+//! of `Selection::Binomial` is selected as many times as `x` falls into the binomial
+//! distribution of its stake, Algorand style: `j` times for `CDF(j - 1) <= x < CDF(j)`.
+
+use core::convert::TryFrom;
+
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+
+use crate::ecvrf::{Output, Proof, VRFPublicKey, OUTPUT_LENGTH};
+
+/// Hex digits a `Selection::threshold` may be given in, for its denominator to fit.
+pub const MAX_PRECISION: usize = 31;
+
+/// How a node is selected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Selection {
+    /// Selected with probability `numerator / denominator`, e.g. the width of an on-chain range
+    /// over the size of the space the ranges partition.
+    Fraction { numerator: u128, denominator: u128 },
+    /// Each of the `stake` units out of `total_stake` is selected with probability
+    /// `expected / total_stake`, so that `expected` units are selected on average across all
+    /// nodes. The node is selected when any of its units is.
+    Binomial {
+        stake: u64,
+        total_stake: u64,
+        expected: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sortition {
+    /// Hex of the whole VRF output.
+    pub random_value: String,
+    pub selected: bool,
+    /// Times the node is selected, its units selected with `Selection::Binomial`, at most one
+    /// with `Selection::Fraction`.
+    pub votes: u64,
+}
+
+impl Selection {
+    /// The `16^precision` values of `precision` hex digits.
+    pub fn space(precision: usize) -> anyhow::Result<u128> {
+        anyhow::ensure!(
+            (1..=MAX_PRECISION).contains(&precision),
+            "precision {precision} out of 1..={MAX_PRECISION}"
+        );
+        Ok(1 << (4 * precision))
+    }
+
+    /// A `threshold` out of the values of `precision` hex digits.
+    pub fn threshold(threshold: u64, precision: usize) -> anyhow::Result<Self> {
+        Self::fraction(threshold as _, Self::space(precision)?)
+    }
+
+    /// The range `[start, end)` of a node, out of the `space` all ranges partition.
+    pub fn range(start: u128, end: u128, space: u128) -> anyhow::Result<Self> {
+        let Some(width) = end.checked_sub(start) else {
+            anyhow::bail!("range end {end} before start {start}")
+        };
+        anyhow::ensure!(end <= space, "range end {end} out of space {space}");
+        Self::fraction(width, space)
+    }
+
+    /// The range `[start, end)` of a node as its stake, out of the `space` all ranges partition,
+    /// with `expected` units selected on average.
+    pub fn stake_range(start: u128, end: u128, space: u128, expected: u64) -> anyhow::Result<Self> {
+        let Self::Fraction {
+            numerator,
+            denominator,
+        } = Self::range(start, end, space)?
+        else {
+            unreachable!()
+        };
+        let selection = Self::Binomial {
+            stake: u64::try_from(numerator)?,
+            total_stake: u64::try_from(denominator)?,
+            expected,
+        };
+        selection.validate()?;
+        Ok(selection)
+    }
+
+    pub fn fraction(numerator: u128, denominator: u128) -> anyhow::Result<Self> {
+        let selection = Self::Fraction {
+            numerator,
+            denominator,
+        };
+        selection.validate()?;
+        Ok(selection)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match *self {
+            Self::Fraction {
+                numerator,
+                denominator,
+            } => anyhow::ensure!(
+                denominator > 0 && numerator <= denominator,
+                "fraction {numerator}/{denominator} out of [0, 1]"
+            ),
+            Self::Binomial {
+                stake,
+                total_stake,
+                expected,
+            } => anyhow::ensure!(
+                stake <= total_stake && expected <= total_stake && total_stake > 0,
+                "stake {stake} and expected {expected} out of total stake {total_stake}"
+            ),
+        }
+        Ok(())
+    }
+
+    /// Probability of being selected at least once.
+    pub fn probability(&self) -> f64 {
+        match *self {
+            Self::Fraction {
+                numerator,
+                denominator,
+            } => numerator as f64 / denominator as f64,
+            Self::Binomial {
+                stake,
+                total_stake,
+                expected,
+            } => 1.0 - (1.0 - expected as f64 / total_stake as f64).powf(stake as f64),
+        }
+    }
+
+    /// Times an output selects, see `Sortition::votes`.
+    pub fn votes(&self, output: &[u8; OUTPUT_LENGTH]) -> anyhow::Result<u64> {
+        self.validate()?;
+        match *self {
+            Self::Fraction {
+                numerator,
+                denominator,
+            } => {
+                // x < numerator / denominator, i.e. output * denominator < numerator * 2^512
+                let output = BigUint::from_bytes_be(output);
+                let bound = BigUint::from(numerator) << (OUTPUT_LENGTH * 8);
+                Ok((output * denominator < bound) as u64)
+            }
+            Self::Binomial {
+                stake,
+                total_stake,
+                expected,
+            } => Ok(binomial_votes(
+                fraction_of(output),
+                stake,
+                expected as f64 / total_stake as f64,
+            )),
+        }
+    }
+}
+
+/// The leading 53 bits of an output as a fraction of `[0, 1)`, which is all of it an `f64` holds.
+fn fraction_of(output: &[u8; OUTPUT_LENGTH]) -> f64 {
+    let mut leading = [0; 8];
+    leading.copy_from_slice(&output[..8]);
+    (u64::from_be_bytes(leading) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// The least `j` with `x < CDF(j)` of the binomial distribution of `n` trials of probability `p`.
+/// Probabilities are summed in log space, the first ones of a large `n` underflow otherwise.
+fn binomial_votes(x: f64, n: u64, p: f64) -> u64 {
+    if p >= 1.0 {
+        return n;
+    }
+    if p <= 0.0 {
+        return 0;
+    }
+    let ln_odds = (p / (1.0 - p)).ln();
+    let mut ln_pmf = n as f64 * (-p).ln_1p();
+    let mut cdf = 0.0;
+    for j in 0..n {
+        let pmf = ln_pmf.exp();
+        cdf += pmf;
+        if x < cdf {
+            return j;
+        }
+        // past the mean, the rest of the distribution no longer adds up to anything
+        if pmf == 0.0 && j as f64 > n as f64 * p {
+            return j;
+        }
+        ln_pmf += ((n - j) as f64 / (j + 1) as f64).ln() + ln_odds;
+    }
+    n
+}
+
+/// Sortition of `output` by `selection`.
+pub fn select(output: &Output, selection: &Selection) -> anyhow::Result<Sortition> {
+    let output = output.to_bytes();
+    let votes = selection.votes(&output)?;
+    Ok(Sortition {
+        random_value: hex::encode(output),
+        selected: votes > 0,
+        votes,
+    })
+}
+
+/// Checks the hex `proof` of `alpha` by the hex `public_key`, returns its output.
+pub fn verify_proof(public_key: &str, proof: &str, alpha: &[u8]) -> anyhow::Result<Output> {
+    let public_key = VRFPublicKey::try_from(&hex::decode(public_key)?[..])
+        .map_err(|err| anyhow::anyhow!("invalid public key, {err}"))?;
+    let proof = Proof::try_from(&hex::decode(proof)?[..])
+        .map_err(|err| anyhow::anyhow!("invalid proof, {err}"))?;
+    public_key
+        .verify(&proof, alpha)
+        .map_err(|err| anyhow::anyhow!("{err}"))?;
+    Ok((&proof).into())
+}
+
+/// Recomputes the sortition of a proof, as made inside the enclave.
+pub fn verify(
+    public_key: &str,
+    proof: &str,
+    alpha: &[u8],
+    selection: &Selection,
+) -> anyhow::Result<Sortition> {
+    select(&verify_proof(public_key, proof, alpha)?, selection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ecvrf::VRFPrivateKey, traits::Uniform};
+    use proptest::prelude::*;
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+    const SAMPLES: usize = 4000;
+
+    fn outputs(seed: u64) -> impl Iterator<Item = [u8; OUTPUT_LENGTH]> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        std::iter::repeat_with(move || {
+            let mut output = [0; OUTPUT_LENGTH];
+            rng.fill_bytes(&mut output);
+            output
+        })
+        .take(SAMPLES)
+    }
+
+    /// Whether `hits` out of `SAMPLES` is within 5 standard deviations of probability `p`.
+    fn close_to(hits: usize, p: f64) -> bool {
+        let mean = SAMPLES as f64 * p;
+        let deviation = (SAMPLES as f64 * p * (1.0 - p)).sqrt();
+        (hits as f64 - mean).abs() <= 5.0 * deviation + 1.0
+    }
+
+    #[test]
+    fn verify_sortition() -> anyhow::Result<()> {
+        let mut rng = StdRng::from_seed([0; 32]);
+        let private_key = VRFPrivateKey::generate_for_testing(&mut rng);
+        let public_key = hex::encode(VRFPublicKey::from(&private_key).as_bytes());
+        let proof = private_key.prove(b"hash");
+        let always = Selection::fraction(1, 1)?;
+        let never = Selection::threshold(0, 6)?;
+        let expected = select(&(&proof).into(), &always)?;
+        let proof = hex::encode(proof.to_bytes());
+
+        let sortition = verify(&public_key, &proof, b"hash", &always)?;
+        assert_eq!(sortition, expected);
+        assert!(sortition.selected);
+        assert!(!verify(&public_key, &proof, b"hash", &never)?.selected);
+
+        assert!(verify(&public_key, &proof, b"other", &always).is_err());
+        let other = VRFPrivateKey::generate_for_testing(&mut rng);
+        let other = hex::encode(VRFPublicKey::from(&other).as_bytes());
+        assert!(verify(&other, &proof, b"hash", &always).is_err());
+        // malformed input is an error, not a panic
+        assert!(verify(&public_key, &proof[..10], b"hash", &always).is_err());
+        assert!(verify(&public_key, "zz", b"hash", &always).is_err());
+        assert!(verify(&public_key[..10], &proof, b"hash", &always).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parameters() {
+        assert!(Selection::threshold(1, 0).is_err());
+        assert!(Selection::threshold(1, MAX_PRECISION + 1).is_err());
+        assert_eq!(
+            Selection::threshold(u64::MAX, 16).unwrap(),
+            Selection::fraction(u64::MAX as _, 1 << 64).unwrap()
+        );
+        // the order the on-chain ranges are given in
+        assert_eq!(
+            Selection::range(100, 300, 1000).unwrap(),
+            Selection::fraction(200, 1000).unwrap()
+        );
+        assert!(Selection::range(300, 100, 1000).is_err());
+        assert!(Selection::range(100, 1001, 1000).is_err());
+        assert_eq!(
+            Selection::stake_range(100, 300, 1000, 10).unwrap(),
+            Selection::Binomial {
+                stake: 200,
+                total_stake: 1000,
+                expected: 10
+            }
+        );
+        assert!(Selection::stake_range(0, 1, 1 << 64, 1).is_err());
+        assert!(Selection::stake_range(0, 1, 1000, 1001).is_err());
+        assert!(Selection::fraction(2, 1).is_err());
+        assert!(Selection::fraction(0, 0).is_err());
+        let binomial = Selection::Binomial {
+            stake: 10,
+            total_stake: 5,
+            expected: 1,
+        };
+        assert!(binomial.votes(&[0; OUTPUT_LENGTH]).is_err());
+    }
+
+    #[test]
+    fn fraction_bounds() -> anyhow::Result<()> {
+        let half = Selection::fraction(1, 2)?;
+        let mut output = [0xff; OUTPUT_LENGTH];
+        output[0] = 0x7f;
+        assert_eq!(half.votes(&output)?, 1);
+        output = [0; OUTPUT_LENGTH];
+        output[0] = 0x80;
+        assert_eq!(half.votes(&output)?, 0);
+        assert_eq!(Selection::fraction(1, 1)?.votes(&[0xff; OUTPUT_LENGTH])?, 1);
+        assert_eq!(Selection::fraction(0, 1)?.votes(&[0; OUTPUT_LENGTH])?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn binomial_votes_cover_the_stake() {
+        let all = Selection::Binomial {
+            stake: 7,
+            total_stake: 7,
+            expected: 7,
+        };
+        assert_eq!(all.votes(&[0xff; OUTPUT_LENGTH]).unwrap(), 7);
+        let none = Selection::Binomial {
+            stake: 7,
+            total_stake: 7,
+            expected: 0,
+        };
+        assert_eq!(none.votes(&[0xff; OUTPUT_LENGTH]).unwrap(), 0);
+        // a large stake does not underflow into never being selected
+        let large = Selection::Binomial {
+            stake: 1 << 40,
+            total_stake: 1 << 41,
+            expected: 1 << 20,
+        };
+        assert!(large.votes(&[0; OUTPUT_LENGTH]).unwrap() > 0);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        #[test]
+        fn fraction_frequency(denominator in 1u128..1 << 100, share in 0.0..=1.0f64, seed: u64) {
+            let numerator = (denominator as f64 * share) as u128;
+            let selection = Selection::fraction(numerator.min(denominator), denominator).unwrap();
+            let hits = outputs(seed)
+                .filter(|output| selection.votes(output).unwrap() > 0)
+                .count();
+            prop_assert!(close_to(hits, selection.probability()), "{hits} hits of {selection:?}");
+        }
+
+        #[test]
+        fn binomial_frequency(
+            total_stake in 1u64..1 << 24,
+            stake_share in 0.0..=1.0f64,
+            expected in 0u64..64,
+            seed: u64,
+        ) {
+            let stake = (total_stake as f64 * stake_share) as u64;
+            let selection = Selection::Binomial {
+                stake: stake.min(total_stake),
+                total_stake,
+                expected: expected.min(total_stake),
+            };
+            let votes: Vec<_> = outputs(seed).map(|output| selection.votes(&output).unwrap()).collect();
+            let hits = votes.iter().filter(|votes| **votes > 0).count();
+            prop_assert!(close_to(hits, selection.probability()), "{hits} hits of {selection:?}");
+            // votes average the stake's share of the expected
+            let Selection::Binomial { stake, total_stake, expected } = selection else {
+                unreachable!()
+            };
+            let p = expected as f64 / total_stake as f64;
+            let mean = stake as f64 * p;
+            let deviation = (stake as f64 * p * (1.0 - p) / SAMPLES as f64).sqrt();
+            let average = votes.iter().sum::<u64>() as f64 / SAMPLES as f64;
+            prop_assert!((average - mean).abs() <= 5.0 * deviation + 1e-9, "average {average}, expected {mean}");
+        }
+    }
+}
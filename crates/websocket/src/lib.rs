@@ -51,6 +51,14 @@ impl WireMessage {
         Ok((Message::Binary(s2),id))
     }
 
+    /// Create a new signal message.
+    fn signal(s: Vec<u8>) -> std::io::Result<Message>
+    {
+        let s1 = Self::Signal { data: s };
+        let s2 = serde_json::to_vec(&s1).map_err(Error::other)?;
+        Ok(Message::Binary(s2))
+    }
+
     /// Create a new response message.
     fn response(id: u64, s: Vec<u8>) -> std::io::Result<Message>
     {
@@ -145,6 +153,23 @@ impl WebsocketListener {
 pub struct WebsocketSender(WsCoreSync, std::time::Duration);
 
 impl WebsocketSender {
+    /// Send a signal, which the remote receives without responding to.
+    pub async fn signal(&self, s: Vec<u8>) -> std::io::Result<()>
+    {
+        use futures::sink::SinkExt;
+        self.0
+            .exec(move |_, core| async move {
+                tokio::time::timeout(core.timeout, async {
+                    let s = WireMessage::signal(s)?;
+                    core.send.lock().await.send(s).await.map_err(Error::other)?;
+                    Ok(())
+                })
+                    .await
+                    .map_err(Error::other)?
+            })
+            .await
+    }
+
     pub async fn request(&self, s: Vec<u8>) -> std::io::Result<Vec<u8>>
     {
         self.request_timeout(s, self.1).await
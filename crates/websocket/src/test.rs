@@ -58,3 +58,29 @@ async fn test1() {
     l_task.await.unwrap();
     r_task.await.unwrap();
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn signal() {
+    let l = WebsocketListener::bind(Arc::new(WebsocketConfig::default()), "localhost:0")
+        .await
+        .unwrap();
+    let addr = l.local_addr().unwrap();
+
+    let l_task = tokio::task::spawn(async move {
+        let (_send, mut recv) = l.accept().await.unwrap();
+        for expected in [b"one".to_vec(), b"two".to_vec()] {
+            match recv.recv().await.unwrap() {
+                ReceiveMessage::Signal(data) => assert_eq!(expected, data),
+                oth => panic!("unexpected: {oth:?}"),
+            }
+        }
+    });
+
+    let (send, _recv) = connect(Arc::new(WebsocketConfig::default()), addr)
+        .await
+        .unwrap();
+    send.signal(b"one".to_vec()).await.unwrap();
+    send.signal(b"two".to_vec()).await.unwrap();
+
+    l_task.await.unwrap();
+}
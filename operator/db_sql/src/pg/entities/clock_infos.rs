@@ -16,6 +16,9 @@ pub struct Model {
     pub raw_message: Vec<u8>,
     pub event_count: i64,
     pub create_at: Option<DateTime>,
+    /// JSON of the clock alone, without its attestation. None for records from before it.
+    #[sea_orm(column_type = "Text", nullable)]
+    pub plain_clock: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
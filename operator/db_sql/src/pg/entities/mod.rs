@@ -2,4 +2,6 @@
 
 pub mod prelude;
 
+pub mod answer_outbox;
 pub mod clock_infos;
+pub mod inference_jobs;
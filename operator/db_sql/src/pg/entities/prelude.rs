@@ -1,3 +1,5 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.12.15
 
+pub use super::answer_outbox::Entity as AnswerOutbox;
 pub use super::clock_infos::Entity as ClockInfos;
+pub use super::inference_jobs::Entity as InferenceJobs;
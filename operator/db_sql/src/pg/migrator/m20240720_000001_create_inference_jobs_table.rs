@@ -0,0 +1,79 @@
+use sea_orm_migration::prelude::*;
+use sea_query::Index;
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m_20240720_000001_create_inference_jobs_table"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    // Create the inference_jobs table, a row per question from received to callback delivered.
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(InferenceJobs::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(InferenceJobs::Id)
+                            .big_integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(InferenceJobs::RequestId).string().unique_key().not_null())
+                    .col(ColumnDef::new(InferenceJobs::Model).string().not_null())
+                    .col(ColumnDef::new(InferenceJobs::PromptHash).string().not_null())
+                    .col(ColumnDef::new(InferenceJobs::Params).string().not_null())
+                    .col(ColumnDef::new(InferenceJobs::Status).string_len(16).not_null())
+                    .col(ColumnDef::new(InferenceJobs::Selected).boolean())
+                    .col(ColumnDef::new(InferenceJobs::Answer).text())
+                    .col(ColumnDef::new(InferenceJobs::Attestation).binary())
+                    .col(ColumnDef::new(InferenceJobs::VrfProof).string())
+                    .col(ColumnDef::new(InferenceJobs::Error).string())
+                    .col(ColumnDef::new(InferenceJobs::CreateAt).timestamp().not_null())
+                    .col(ColumnDef::new(InferenceJobs::AnswerAt).timestamp())
+                    .col(ColumnDef::new(InferenceJobs::UpdateAt).timestamp().not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        // create index
+        let status_index = Index::create()
+            .if_not_exists()
+            .name("idx-inferencejobs-status")
+            .table(InferenceJobs::Table)
+            .col(InferenceJobs::Status)
+            .to_owned();
+        manager.create_index(status_index).await
+    }
+
+    // Drop the inference_jobs table.
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(InferenceJobs::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum InferenceJobs {
+    Table,
+    Id,
+    RequestId,
+    Model,
+    PromptHash,
+    Params,
+    Status,
+    Selected,
+    Answer,
+    Attestation,
+    VrfProof,
+    Error,
+    CreateAt,
+    AnswerAt,
+    UpdateAt,
+}
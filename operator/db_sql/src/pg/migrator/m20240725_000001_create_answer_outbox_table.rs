@@ -0,0 +1,70 @@
+use sea_orm_migration::prelude::*;
+use sea_query::Index;
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m_20240725_000001_create_answer_outbox_table"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    // Create the answer_outbox table, answer callbacks stored before delivery to the dispatcher.
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(AnswerOutbox::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(AnswerOutbox::Id)
+                            .big_integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(AnswerOutbox::RequestId).string().unique_key().not_null())
+                    .col(ColumnDef::new(AnswerOutbox::Body).text().not_null())
+                    .col(ColumnDef::new(AnswerOutbox::Status).string_len(16).not_null())
+                    .col(ColumnDef::new(AnswerOutbox::Attempts).integer().not_null())
+                    .col(ColumnDef::new(AnswerOutbox::NextAttemptAt).timestamp().not_null())
+                    .col(ColumnDef::new(AnswerOutbox::LastError).string())
+                    .col(ColumnDef::new(AnswerOutbox::CreateAt).timestamp().not_null())
+                    .col(ColumnDef::new(AnswerOutbox::UpdateAt).timestamp().not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        // create index, the due ones are looked up by status and time
+        let due_index = Index::create()
+            .if_not_exists()
+            .name("idx-answeroutbox-status-nextattemptat")
+            .table(AnswerOutbox::Table)
+            .col(AnswerOutbox::Status)
+            .col(AnswerOutbox::NextAttemptAt)
+            .to_owned();
+        manager.create_index(due_index).await
+    }
+
+    // Drop the answer_outbox table.
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(AnswerOutbox::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum AnswerOutbox {
+    Table,
+    Id,
+    RequestId,
+    Body,
+    Status,
+    Attempts,
+    NextAttemptAt,
+    LastError,
+    CreateAt,
+    UpdateAt,
+}
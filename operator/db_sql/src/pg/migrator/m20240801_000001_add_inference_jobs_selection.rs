@@ -0,0 +1,42 @@
+use sea_orm_migration::prelude::*;
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m_20240801_000001_add_inference_jobs_selection"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    // Add the selection of the prompt to inference_jobs, for answers to be checked by after the
+    // prompt left the queue.
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(InferenceJobs::Table)
+                    .add_column_if_not_exists(ColumnDef::new(InferenceJobs::Selection).string())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    // Drop the selection column.
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(InferenceJobs::Table)
+                    .drop_column(InferenceJobs::Selection)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum InferenceJobs {
+    Table,
+    Selection,
+}
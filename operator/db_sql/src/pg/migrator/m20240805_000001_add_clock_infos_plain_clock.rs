@@ -0,0 +1,42 @@
+use sea_orm_migration::prelude::*;
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m_20240805_000001_add_clock_infos_plain_clock"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    // Add the clock without its attestation to clock_infos, for causal histories to be scanned
+    // without reading the attestations.
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(ClockInfos::Table)
+                    .add_column_if_not_exists(ColumnDef::new(ClockInfos::PlainClock).text())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    // Drop the plain_clock column.
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(ClockInfos::Table)
+                    .drop_column(ClockInfos::PlainClock)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum ClockInfos {
+    Table,
+    PlainClock,
+}
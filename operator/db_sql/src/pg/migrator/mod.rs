@@ -1,6 +1,10 @@
 use sea_orm_migration::prelude::*;
 
 mod m20240705_000001_create_clock_infos_table;
+mod m20240720_000001_create_inference_jobs_table;
+mod m20240725_000001_create_answer_outbox_table;
+mod m20240801_000001_add_inference_jobs_selection;
+mod m20240805_000001_add_clock_infos_plain_clock;
 
 /// Use the sea-orm-cli to generate data entity, 
 /// command like as follow:
@@ -18,6 +22,10 @@ impl MigratorTrait for Migrator {
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
         vec![
             Box::new(m20240705_000001_create_clock_infos_table::Migration),
+            Box::new(m20240720_000001_create_inference_jobs_table::Migration),
+            Box::new(m20240725_000001_create_answer_outbox_table::Migration),
+            Box::new(m20240801_000001_add_inference_jobs_selection::Migration),
+            Box::new(m20240805_000001_add_clock_infos_plain_clock::Migration),
         ]
     }
 }
\ No newline at end of file
@@ -37,6 +37,8 @@ pub async fn setup_db(request_url: &str, db_name: &str) -> Result<DatabaseConnec
 
     Migrator::up(&db.clone(), None).await?;
     assert!(schema_manager.has_table("clock_infos").await?);
+    assert!(schema_manager.has_table("inference_jobs").await?);
+    assert!(schema_manager.has_table("answer_outbox").await?);
 
     Ok(db)
 }
@@ -1,9 +1,12 @@
 use crate::error::{OperatorConfigError, OperatorConfigResult};
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::HashMap;
 use std::path::Path;
 use std::path::PathBuf;
 use tools::helper::validate_addr;
+use tee_llm::chat::ChatTemplate;
+use tee_llm::sampling::MAX_STOP_SEQUENCES;
 use tools::helper::validate_key;
 
 /// Operator Node Config
@@ -34,6 +37,50 @@ pub struct NetworkConfig {
     pub dispatcher_url: String,
     pub tee_llm_cid: u32,
     pub tee_llm_port: u32,
+    /// Overrides `tee_llm_cid` and `tee_llm_port`, e.g. `unix:///tmp/llm.sock` or
+    /// `tcp://127.0.0.1:5005` for a tee_llm service running outside of an enclave.
+    #[serde(default)]
+    pub tee_llm_addr: Option<String>,
+    /// The VLC enclave answers are clocked by, e.g. `vsock://16:5006`. Answers are not clocked
+    /// without it.
+    #[serde(default)]
+    pub tee_vlc_addr: Option<String>,
+    #[serde(default)]
+    pub gossip: GossipConfig,
+}
+
+/// Peers the clocked events of the node are gossiped with, over websocket. Needs
+/// `tee_vlc_addr`, off when neither `listen_addr` nor `peers` are set.
+#[derive(Clone, Deserialize, Serialize, Debug, Default)]
+#[serde(default)]
+pub struct GossipConfig {
+    /// e.g. `0.0.0.0:5007`.
+    pub listen_addr: Option<String>,
+    /// `host:port` of the `listen_addr` of the peers, dialed and redialed.
+    pub peers: Vec<String>,
+    /// DER root certificate the clocks of the peers are attested up to, AWS's by default.
+    pub root_cert: Option<PathBuf>,
+    /// `PcrPolicy` JSON the enclaves of the peers are trusted by, by default the image of the
+    /// local VLC enclave once it attested a clock, no clock of a peer is merged before that.
+    pub pcr_policy: Option<PathBuf>,
+    /// `node_id` of the peers, whose events are accepted signed by their `signer_key`. The
+    /// events of any other node are dropped.
+    pub peer_ids: Vec<String>,
+}
+
+impl GossipConfig {
+    pub fn enabled(&self) -> bool {
+        self.listen_addr.is_some() || !self.peers.is_empty()
+    }
+}
+
+impl NetworkConfig {
+    pub fn tee_llm_addr(&self) -> String {
+        self.tee_llm_addr.clone().unwrap_or(format!(
+            "vsock://{}:{}",
+            self.tee_llm_cid, self.tee_llm_port
+        ))
+    }
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug, Default)]
@@ -45,18 +92,113 @@ pub struct NodeConfig {
 
     #[serde(default)]
     pub ai_models: Vec<String>,
+    /// Keyed by model name, models not listed here get the default bounds.
+    #[serde(default)]
+    pub sampling_bounds: HashMap<String, SamplingBounds>,
+    /// Keyed by model name, for models whose GGUF metadata has no recognized chat template.
+    #[serde(default)]
+    pub chat_templates: HashMap<String, ChatTemplate>,
+    /// Most prompts in flight to the enclave, further ones are refused with HTTP 429.
+    #[serde(default = "default_queue_depth")]
+    pub queue_depth: usize,
+    /// Seconds to wait for the answer of a prompt, which stays in flight until the enclave replies.
+    #[serde(default = "default_answer_timeout")]
+    pub answer_timeout: u64,
+    #[serde(default)]
+    pub callback_retry: CallbackRetry,
+}
+
+fn default_queue_depth() -> usize {
+    64
+}
+
+fn default_answer_timeout() -> u64 {
+    600
+}
+
+impl NodeConfig {
+    pub fn bounds_of(&self, model: &str) -> SamplingBounds {
+        self.sampling_bounds.get(model).cloned().unwrap_or_default()
+    }
+}
+
+/// Limits on what a question may ask of a model.
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(default)]
+pub struct SamplingBounds {
+    pub max_tokens: u32,
+    pub max_temperature: f32,
+    pub max_top_k: i32,
+    pub max_repetition_penalty: f32,
+    pub max_stop_sequences: usize,
+    /// In bytes.
+    pub max_stop_len: usize,
+}
+
+impl Default for SamplingBounds {
+    fn default() -> Self {
+        Self {
+            max_tokens: 2048,
+            max_temperature: 2.0,
+            max_top_k: 100,
+            max_repetition_penalty: 2.0,
+            max_stop_sequences: MAX_STOP_SEQUENCES,
+            max_stop_len: 64,
+        }
+    }
+}
+
+/// Retries of answer callbacks the dispatcher failed to acknowledge.
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(default)]
+pub struct CallbackRetry {
+    /// Attempts before the callback is dead-lettered.
+    pub max_attempts: u32,
+    /// Seconds before the first retry, doubled for every further one.
+    pub initial_backoff: u64,
+    /// Seconds.
+    pub max_backoff: u64,
+}
+
+impl Default for CallbackRetry {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            initial_backoff: 2,
+            max_backoff: 600,
+        }
+    }
+}
+
+impl CallbackRetry {
+    /// Seconds to wait after the `attempts`th failed attempt.
+    pub fn backoff(&self, attempts: u32) -> u64 {
+        let doublings = attempts.saturating_sub(1).min(63);
+        self.initial_backoff
+            .saturating_mul(1u64 << doublings)
+            .min(self.max_backoff)
+    }
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug, Default)]
 pub struct ChainConfig {
     pub chain_rpc_url: String,
     pub vrf_range_contract: String,
+    /// Hex digits of the space the on-chain ranges partition, `16^vrf_sort_precision` values.
     pub vrf_sort_precision: u16,
+    /// Takes the range of the node as its stake, with this many units selected on average
+    /// across all nodes, instead of a plain share of the space.
+    #[serde(default)]
+    pub vrf_expected_selected: Option<u64>,
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug, Default)]
 pub struct ApiConfig {
     pub read_maximum: u64,
+    /// Addresses, the dispatcher's among them, whose signed questions are served. At least one,
+    /// unsigned questions are never served.
+    #[serde(default)]
+    pub allowed_signers: Vec<String>,
 }
 
 #[derive(Clone, serde::Serialize, serde::Deserialize, Debug, Default)]
@@ -92,6 +234,29 @@ impl OperatorConfig {
             return Err(OperatorConfigError::IllegalSignerKey);
         }
 
+        if config.api.allowed_signers.is_empty() {
+            return Err(OperatorConfigError::NoAllowedSigners);
+        }
+
+        if let Some(signer) = config
+            .api
+            .allowed_signers
+            .iter()
+            .find(|signer| !validate_addr(signer))
+        {
+            return Err(OperatorConfigError::IllegalAllowedSigner(signer.clone()));
+        }
+
+        if let Some(peer) = config
+            .net
+            .gossip
+            .peer_ids
+            .iter()
+            .find(|peer| !validate_addr(peer))
+        {
+            return Err(OperatorConfigError::IllegalGossipPeer(peer.clone()));
+        }
+
         Ok(config.clone())
     }
 }
@@ -11,8 +11,17 @@ impl ErrorCodes {
     pub const IO_ERROR: u32 = 1003;
     pub const ILLEGAL_NODE_ID: u32 = 1004;
     pub const ILLEGAL_SIGNER: u32 = 1005;
+    pub const ILLEGAL_ALLOWED_SIGNER: u32 = 1006;
+    pub const NO_ALLOWED_SIGNERS: u32 = 1007;
+    pub const ILLEGAL_GOSSIP_PEER: u32 = 1008;
     
     pub const API_FAIL_TO_JSON: u32 = 2001;
+    pub const API_MODEL_NOT_FOUND: u32 = 2002;
+    pub const API_ANSWER_NOT_FOUND: u32 = 2003;
+    pub const API_QUERY_DB_ERROR: u32 = 2004;
+    pub const API_VRF_KEY_NOT_READY: u32 = 2005;
+    pub const API_INVALID_VRF_PROOF: u32 = 2006;
+    pub const API_CLOCK_NOT_FOUND: u32 = 2007;
 
     pub const OP_CUSTOM_ERROR: u32 = 3001;
     pub const OP_FAIL_REGISTER: u32 = 3002;
@@ -21,6 +30,19 @@ impl ErrorCodes {
     pub const OP_DECODE_SIGNER_KEY_ERROR: u32 = 3005;
     pub const OP_NEW_VRF_RANGE_CONTRACT_ERROR: u32 = 3006;
     pub const OP_GET_RANGE_CONTRACT_ERROR: u32 = 3007;
+    pub const OP_INVALID_SAMPLING_PARAMS: u32 = 3008;
+    pub const OP_INVALID_PROMPT: u32 = 3009;
+    pub const OP_QUEUE_FULL: u32 = 3010;
+    pub const OP_ANSWER_TIMEOUT: u32 = 3011;
+    pub const OP_DUPLICATE_REQUEST: u32 = 3012;
+    pub const OP_INVALID_PROMPT_HASH: u32 = 3013;
+    pub const OP_INVALID_SIGNATURE: u32 = 3014;
+    pub const OP_SIGNER_NOT_ALLOWED: u32 = 3015;
+    pub const OP_REPLAYED_REQUEST: u32 = 3016;
+    pub const OP_VRF_CHECK_FAILED: u32 = 3017;
+    pub const OP_GOSSIP_ERROR: u32 = 3018;
+    pub const OP_ANSWER_FAILED: u32 = 3019;
+    pub const OP_NOT_SELECTED: u32 = 3020;
     
 }
 
@@ -57,6 +79,24 @@ pub enum OperatorConfigError {
         ErrorCodes::ILLEGAL_SIGNER
     )]
     IllegalSignerKey,
+
+    #[error(
+        "Error allowed signer {0} illegal, must be a hex address (Error Code: {})",
+        ErrorCodes::ILLEGAL_ALLOWED_SIGNER
+    )]
+    IllegalAllowedSigner(String),
+
+    #[error(
+        "Error no allowed signers, api.allowed_signers must list the dispatcher (Error Code: {})",
+        ErrorCodes::NO_ALLOWED_SIGNERS
+    )]
+    NoAllowedSigners,
+
+    #[error(
+        "Error gossip peer {0} illegal, must be a hex address (Error Code: {})",
+        ErrorCodes::ILLEGAL_GOSSIP_PEER
+    )]
+    IllegalGossipPeer(String),
 }
 
 pub type OperatorAPIResult<T> = Result<T, OperatorAPIError>;
@@ -68,6 +108,42 @@ pub enum OperatorAPIError {
         ErrorCodes::API_FAIL_TO_JSON
     )]
     APIFailToJson,
+
+    #[error(
+        "Error model {0} not served by this node (Error Code: {})",
+        ErrorCodes::API_MODEL_NOT_FOUND
+    )]
+    APIModelNotFound(String),
+
+    #[error(
+        "Error no answer found, request: {0} (Error Code: {})",
+        ErrorCodes::API_ANSWER_NOT_FOUND
+    )]
+    APIAnswerNotFound(String),
+
+    #[error(
+        "Error failed to query db, detail: {0} (Error Code: {})",
+        ErrorCodes::API_QUERY_DB_ERROR
+    )]
+    APIQueryDbError(String),
+
+    #[error(
+        "Error vrf key not attested by tee yet (Error Code: {})",
+        ErrorCodes::API_VRF_KEY_NOT_READY
+    )]
+    APIVrfKeyNotReady,
+
+    #[error(
+        "Error invalid vrf proof, detail: {0} (Error Code: {})",
+        ErrorCodes::API_INVALID_VRF_PROOF
+    )]
+    APIInvalidVrfProof(String),
+
+    #[error(
+        "Error no clock found, message: {0} (Error Code: {})",
+        ErrorCodes::API_CLOCK_NOT_FOUND
+    )]
+    APIClockNotFound(String),
 }
 
 
@@ -116,4 +192,82 @@ pub enum OperatorError {
         ErrorCodes::OP_GET_RANGE_CONTRACT_ERROR
     )]
     OPGetVrfRangeContractError(String),
+
+    #[error(
+        "Error: invalid sampling params, detail: {0}  (Error Code: {})",
+        ErrorCodes::OP_INVALID_SAMPLING_PARAMS
+    )]
+    OPInvalidSamplingParams(String),
+
+    #[error(
+        "Error: invalid prompt, detail: {0}  (Error Code: {})",
+        ErrorCodes::OP_INVALID_PROMPT
+    )]
+    OPInvalidPrompt(String),
+
+    #[error(
+        "Error: tee queue is full, {0} prompts in flight  (Error Code: {})",
+        ErrorCodes::OP_QUEUE_FULL
+    )]
+    OPQueueFull(usize),
+
+    #[error(
+        "Error: no answer from tee service in time, request: {0}  (Error Code: {})",
+        ErrorCodes::OP_ANSWER_TIMEOUT
+    )]
+    OPAnswerTimeout(String),
+
+    #[error(
+        "Error: request already in flight, request: {0}  (Error Code: {})",
+        ErrorCodes::OP_DUPLICATE_REQUEST
+    )]
+    OPDuplicateRequest(String),
+
+    #[error(
+        "Error: prompt hash does not match the prompt, request: {0}  (Error Code: {})",
+        ErrorCodes::OP_INVALID_PROMPT_HASH
+    )]
+    OPInvalidPromptHash(String),
+
+    #[error(
+        "Error: invalid signature, detail: {0}  (Error Code: {})",
+        ErrorCodes::OP_INVALID_SIGNATURE
+    )]
+    OPInvalidSignature(String),
+
+    #[error(
+        "Error: signer not allowed, signer: {0}  (Error Code: {})",
+        ErrorCodes::OP_SIGNER_NOT_ALLOWED
+    )]
+    OPSignerNotAllowed(String),
+
+    #[error(
+        "Error: request or nonce seen before, request: {0}  (Error Code: {})",
+        ErrorCodes::OP_REPLAYED_REQUEST
+    )]
+    OPReplayedRequest(String),
+
+    #[error(
+        "Error: vrf of the answer does not check out, request: {0}, detail: {1}  (Error Code: {})",
+        ErrorCodes::OP_VRF_CHECK_FAILED
+    )]
+    OPVrfCheckFailed(String, String),
+
+    #[error(
+        "Error: gossip with peers failed, detail: {0}  (Error Code: {})",
+        ErrorCodes::OP_GOSSIP_ERROR
+    )]
+    OPGossipError(String),
+
+    #[error(
+        "Error: tee service could not answer, request: {0}, detail: {1}  (Error Code: {})",
+        ErrorCodes::OP_ANSWER_FAILED
+    )]
+    OPAnswerFailed(String, String),
+
+    #[error(
+        "Error: node not selected by the vrf, request: {0}  (Error Code: {})",
+        ErrorCodes::OP_NOT_SELECTED
+    )]
+    OPNotSelected(String),
 }
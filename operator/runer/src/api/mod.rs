@@ -1,4 +1,5 @@
 pub mod read;
 pub mod write;
 pub mod response;
-pub mod request;
\ No newline at end of file
+pub mod request;
+pub mod openai;
\ No newline at end of file
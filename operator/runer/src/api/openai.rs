@@ -0,0 +1,451 @@
+use crate::api::request::{make_answer_callback_req, prompt_hash, InferParams, QuestionReq};
+use crate::api::write::{admit, submit, submit_error};
+use crate::operator::OperatorArc;
+use crate::tee_queue::{WaitError, Waiter};
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
+use chrono::Utc;
+use node_api::error::ErrorCodes;
+use node_api::error::OperatorAPIError::APIModelNotFound;
+use node_api::error::OperatorError::{OPAnswerFailed, OPAnswerTimeout, OPNotSelected};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tee_llm::chat::ChatMessage;
+use tee_llm::nitro_llm::{AnswerResp, TEEReq, TEEResp};
+use tee_llm::sampling::SamplerOptions;
+use tokio::sync::{mpsc::unbounded_channel, oneshot};
+use tokio::time::timeout_at;
+use tracing::info;
+
+// OpenAI compatible API, so that existing SDKs can talk to an operator directly. answers carry
+// the attestation and the VRF proof as the extension field `tee_answer`, in the same shape as
+// the answer callback. a node not selected by the VRF answers 421, with the proof of it as
+// `tee_answer` of the error. a question is signed by one of `api.allowed_signers` as the ones of
+// `/api/v1/question` are, with the request id in the `X-Request-Id` header and the signature as
+// the bearer token
+
+#[derive(Deserialize, Debug)]
+pub struct ChatCompletionReq {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    #[serde(flatten)]
+    pub params: OpenAiParams,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CompletionReq {
+    pub model: String,
+    pub prompt: String,
+    #[serde(flatten)]
+    pub params: OpenAiParams,
+}
+
+/// Besides the OpenAI ones, every field of `SamplerOptions` (`top_k`, `min_p`, `mirostat` and so
+/// on) is accepted. Absent ones default to the operator's setup, not to OpenAI's.
+#[derive(Deserialize, Debug, Default)]
+pub struct OpenAiParams {
+    /// Up to the bound of the model if absent.
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    #[serde(default)]
+    pub seed: Option<u32>,
+    #[serde(default)]
+    pub stream: bool,
+    /// Extension, bound into the attested inference commitment.
+    #[serde(default)]
+    pub nonce: String,
+    #[serde(flatten)]
+    pub sampler: SamplerOptions,
+}
+
+impl OpenAiParams {
+    fn question(
+        self,
+        op: &OperatorArc,
+        request_id: String,
+        model: String,
+        prompt: String,
+        messages: Vec<ChatMessage>,
+    ) -> QuestionReq {
+        let bounds = op.config.node.bounds_of(&model);
+        let prompt_hash = prompt_hash(&prompt, &messages);
+        QuestionReq {
+            request_id,
+            node_id: op.config.node.node_id.clone(),
+            model,
+            prompt,
+            messages,
+            params: InferParams {
+                max_tokens: self.max_tokens.unwrap_or(bounds.max_tokens),
+                seed: self.seed,
+                sampler: self.sampler,
+            },
+            prompt_hash,
+            nonce: self.nonce,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Chat,
+    Text,
+}
+
+impl Kind {
+    fn id_prefix(self) -> &'static str {
+        match self {
+            Kind::Chat => "chatcmpl",
+            Kind::Text => "cmpl",
+        }
+    }
+
+    fn object(self, chunk: bool) -> &'static str {
+        match (self, chunk) {
+            (Kind::Chat, false) => "chat.completion",
+            (Kind::Chat, true) => "chat.completion.chunk",
+            (Kind::Text, _) => "text_completion",
+        }
+    }
+
+    fn choice(self, text: &str, finish_reason: Option<&str>, chunk: bool) -> Value {
+        match (self, chunk) {
+            (Kind::Chat, false) => json!({
+                "index": 0,
+                "message": { "role": "assistant", "content": text },
+                "finish_reason": finish_reason,
+            }),
+            (Kind::Chat, true) => json!({
+                "index": 0,
+                "delta": { "content": text },
+                "finish_reason": finish_reason,
+            }),
+            (Kind::Text, _) => json!({
+                "index": 0,
+                "text": text,
+                "logprobs": null,
+                "finish_reason": finish_reason,
+            }),
+        }
+    }
+
+    /// A completion object, or a chunk of one.
+    fn body(self, id: &str, model: &str, choices: Vec<Value>, chunk: bool) -> Value {
+        json!({
+            "id": id,
+            "object": self.object(chunk),
+            "created": Utc::now().timestamp(),
+            "model": model,
+            "choices": choices,
+        })
+    }
+
+    fn completion(self, model: &str, answer: &AnswerResp, op: &OperatorArc) -> Value {
+        // the answer does not tell a stop sequence or end of text from running out of tokens
+        let choices = vec![self.choice(&answer.answer, Some("stop"), false)];
+        let mut body = self.body(&answer.request_id, model, choices, false);
+        body["tee_answer"] = tee_answer(op, answer, "");
+        body
+    }
+}
+
+fn tee_answer(op: &OperatorArc, answer: &AnswerResp, transcript_hash: &str) -> Value {
+    let clock = op
+        .vlc
+        .as_ref()
+        .and_then(|vlc| vlc.answer_clock(&answer.request_id));
+    json!(make_answer_callback_req(&op.config, answer, transcript_hash, clock))
+}
+
+fn generated_id(kind: Kind) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    format!(
+        "{}-{:x}{:04x}",
+        kind.id_prefix(),
+        Utc::now().timestamp_micros(),
+        COUNTER.fetch_add(1, Ordering::Relaxed) & 0xffff
+    )
+}
+
+/// The request id of the `X-Request-Id` header, or a new one, and the signature of the bearer
+/// token, empty if none.
+fn signed_headers(http: &HttpRequest, kind: Kind) -> (String, String) {
+    let header = |name| {
+        http.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+    };
+    let request_id = header("X-Request-Id").map_or_else(|| generated_id(kind), str::to_owned);
+    let signature = header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default()
+        .trim()
+        .to_owned();
+    (request_id, signature)
+}
+
+fn error_body(status: StatusCode, code: u32, msg: String) -> Value {
+    json!({
+        "error": {
+            "message": msg,
+            "type": if status.is_client_error() { "invalid_request_error" } else { "server_error" },
+            "code": code,
+        }
+    })
+}
+
+fn error_resp(status: StatusCode, code: u32, msg: String) -> HttpResponse {
+    HttpResponse::build(status).json(error_body(status, code, msg))
+}
+
+/// Another node is to answer, this one proves that it is not selected with `tee_answer`.
+fn not_selected_resp(op: &OperatorArc, answer: &AnswerResp, transcript_hash: &str) -> HttpResponse {
+    let status = StatusCode::MISDIRECTED_REQUEST;
+    let msg = OPNotSelected(answer.request_id.clone()).to_string();
+    let mut body = error_body(status, ErrorCodes::OP_NOT_SELECTED, msg);
+    body["tee_answer"] = tee_answer(op, answer, transcript_hash);
+    HttpResponse::build(status).json(body)
+}
+
+fn timeout_resp(request_id: String) -> HttpResponse {
+    error_resp(
+        StatusCode::GATEWAY_TIMEOUT,
+        ErrorCodes::OP_ANSWER_TIMEOUT,
+        OPAnswerTimeout(request_id).to_string(),
+    )
+}
+
+fn failed_resp(request_id: String, err: String) -> HttpResponse {
+    error_resp(
+        StatusCode::BAD_GATEWAY,
+        ErrorCodes::OP_ANSWER_FAILED,
+        OPAnswerFailed(request_id, err).to_string(),
+    )
+}
+
+fn sse_data(data: impl std::fmt::Display) -> web::Bytes {
+    web::Bytes::from(format!("data: {data}\n\n"))
+}
+
+#[get("/v1/models")]
+async fn models(op: web::Data<OperatorArc>) -> HttpResponse {
+    let data = op
+        .config
+        .node
+        .ai_models
+        .iter()
+        .map(|model| {
+            json!({
+                "id": model,
+                "object": "model",
+                "created": 0,
+                "owned_by": op.config.node.node_id,
+            })
+        })
+        .collect::<Vec<_>>();
+    HttpResponse::Ok().json(json!({ "object": "list", "data": data }))
+}
+
+#[post("/v1/chat/completions")]
+async fn chat_completions(
+    http: HttpRequest,
+    body: web::Json<ChatCompletionReq>,
+    op: web::Data<OperatorArc>,
+) -> HttpResponse {
+    info!("Receive chat completion request, body = {:?}", body);
+    let ChatCompletionReq {
+        model,
+        messages,
+        params,
+    } = body.into_inner();
+    let stream = params.stream;
+    let (request_id, signature) = signed_headers(&http, Kind::Chat);
+    let quest = QuestionReq {
+        signature,
+        ..params.question(&op, request_id, model, String::new(), messages)
+    };
+    complete(quest, stream, Kind::Chat, &op).await
+}
+
+#[post("/v1/completions")]
+async fn completions(
+    http: HttpRequest,
+    body: web::Json<CompletionReq>,
+    op: web::Data<OperatorArc>,
+) -> HttpResponse {
+    info!("Receive completion request, body = {:?}", body);
+    let CompletionReq {
+        model,
+        prompt,
+        params,
+    } = body.into_inner();
+    let stream = params.stream;
+    let (request_id, signature) = signed_headers(&http, Kind::Text);
+    let quest = QuestionReq {
+        signature,
+        ..params.question(&op, request_id, model, prompt, Vec::new())
+    };
+    complete(quest, stream, Kind::Text, &op).await
+}
+
+/// Sends the question to the enclave and waits for the answer, or streams it as the chunks come.
+/// The answer callback is still made.
+async fn complete(quest: QuestionReq, stream: bool, kind: Kind, op: &OperatorArc) -> HttpResponse {
+    if !op.config.node.ai_models.contains(&quest.model) {
+        return error_resp(
+            StatusCode::NOT_FOUND,
+            ErrorCodes::API_MODEL_NOT_FOUND,
+            APIModelNotFound(quest.model).to_string(),
+        );
+    }
+    let req = match admit(&quest, op).await {
+        Ok(req) => req,
+        Err((status, code, msg)) => return error_resp(status, code, msg),
+    };
+
+    if !stream {
+        let (waiter, answered) = oneshot::channel();
+        if let Err(err) = submit(op, TEEReq::PromptReq(req), Waiter::Answer(waiter)).await {
+            let (status, code, msg) = submit_error(err);
+            return error_resp(status, code, msg);
+        }
+        return match op.tee_queue.wait(&quest.request_id, answered).await {
+            Ok(answer) if !answer.selected => not_selected_resp(op, &answer, ""),
+            Ok(answer) => HttpResponse::Ok().json(kind.completion(&quest.model, &answer, op)),
+            Err(WaitError::Timeout) => timeout_resp(quest.request_id),
+            Err(WaitError::Failed(err)) => failed_resp(quest.request_id, err),
+        };
+    }
+
+    let (sender, mut receiver) = unbounded_channel();
+    let deadline = match submit(op, TEEReq::StreamPromptReq(req), Waiter::Stream(sender)).await {
+        Ok(deadline) => deadline,
+        Err(err) => {
+            let (status, code, msg) = submit_error(err);
+            return error_resp(status, code, msg);
+        }
+    };
+    // an enclave not selected only sends the end, which is known before the response status is
+    let first = match timeout_at(deadline, receiver.recv()).await {
+        Ok(Some(TEEResp::StreamEnd(end))) if !end.answer.selected => {
+            return not_selected_resp(op, &end.answer, &end.transcript_hash)
+        }
+        Ok(Some(TEEResp::Failed(failed))) => return failed_resp(failed.request_id, failed.error),
+        Ok(Some(resp)) => resp,
+        // the entry has expired meanwhile, or not in time
+        Ok(None) | Err(_) => {
+            op.tee_queue.abandon(&quest.request_id);
+            return timeout_resp(quest.request_id);
+        }
+    };
+    let config = op.config.clone();
+    let tee_queue = op.tee_queue.clone();
+    let vlc = op.vlc.clone();
+    let (id, model) = (quest.request_id, quest.model);
+    let events = futures::stream::unfold((Some(first), receiver), move |(first, mut receiver)| {
+        let (config, tee_queue, vlc) = (config.clone(), tee_queue.clone(), vlc.clone());
+        let (id, model) = (id.clone(), model.clone());
+        async move {
+            let resp = match first {
+                Some(resp) => resp,
+                None => {
+                    let Ok(resp) = timeout_at(deadline, receiver.recv()).await else {
+                        tee_queue.abandon(&id);
+                        return None;
+                    };
+                    resp?
+                }
+            };
+            // the subscriber is dropped after the end, which closes the stream
+            let data = match resp {
+                TEEResp::TokenChunk(chunk) => {
+                    let choice = kind.choice(&chunk.token, None, true);
+                    sse_data(kind.body(&id, &model, vec![choice], true))
+                }
+                TEEResp::StreamEnd(end) => {
+                    let choices = vec![kind.choice("", Some("stop"), true)];
+                    let mut body = kind.body(&id, &model, choices, true);
+                    body["tee_answer"] = json!(make_answer_callback_req(
+                        &config,
+                        &end.answer,
+                        &end.transcript_hash,
+                        vlc.and_then(|vlc| vlc.answer_clock(&id)),
+                    ));
+                    body["tee_chunks"] = json!(end.chunks);
+                    web::Bytes::from(format!("data: {body}\n\ndata: [DONE]\n\n"))
+                }
+                TEEResp::Failed(failed) => sse_data(error_body(
+                    StatusCode::BAD_GATEWAY,
+                    ErrorCodes::OP_ANSWER_FAILED,
+                    OPAnswerFailed(id, failed.error).to_string(),
+                )),
+                _ => return None,
+            };
+            Some((Ok::<_, Error>(data), (None, receiver)))
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_requests() -> Result<(), serde_json::Error> {
+        let req: ChatCompletionReq = serde_json::from_str(
+            r#"{
+                "model": "llama-2-7b-chat.Q4_0.gguf",
+                "messages": [{"role": "user", "content": "Hi"}],
+                "temperature": 0.5,
+                "top_k": 20,
+                "stop": "\n",
+                "stream": true,
+                "user": "ignored"
+            }"#,
+        )?;
+        assert!(req.params.stream);
+        assert_eq!(req.params.sampler.temperature, 0.5);
+        assert_eq!(req.params.sampler.top_k, 20);
+        assert_eq!(req.params.sampler.stop, ["\n"]);
+
+        let req: CompletionReq = serde_json::from_str(
+            r#"{"model": "m", "prompt": "Hi", "max_tokens": 16, "stop": ["a", "b"]}"#,
+        )?;
+        assert!(!req.params.stream);
+        assert_eq!(req.params.max_tokens, Some(16));
+        assert_eq!(req.params.sampler.stop, ["a", "b"]);
+        assert_eq!(req.params.sampler.top_k, SamplerOptions::default().top_k);
+
+        let req: CompletionReq =
+            serde_json::from_str(r#"{"model": "m", "prompt": "Hi", "stop": null}"#)?;
+        assert!(req.params.sampler.stop.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_signed_headers() {
+        let http = actix_web::test::TestRequest::default()
+            .insert_header(("X-Request-Id", "req-1"))
+            .insert_header(("Authorization", "Bearer 0xabcd"))
+            .to_http_request();
+        assert_eq!(
+            signed_headers(&http, Kind::Chat),
+            ("req-1".to_owned(), "0xabcd".to_owned())
+        );
+
+        let http = actix_web::test::TestRequest::default()
+            .insert_header(("Authorization", "Basic abcd"))
+            .to_http_request();
+        let (request_id, signature) = signed_headers(&http, Kind::Text);
+        assert!(request_id.starts_with("cmpl-"));
+        assert!(signature.is_empty());
+    }
+}
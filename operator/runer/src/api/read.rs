@@ -1,10 +1,22 @@
+use crate::api::request::make_vrf_key_req;
 use crate::api::response::{make_resp_json, Response, WorkerStatus};
 use crate::operator::OperatorArc;
+use crate::vlc::{CausalOrder, ClockCredential, ClockRecord};
 use actix_web::{body, get, post, web, Error, HttpRequest, HttpResponse, Result};
+use common::ordinary_clock::{Clock as _, OrdinaryClock};
+use db_sql::pg::entities::{answer_outbox, inference_jobs};
 use node_api::error::ErrorCodes;
-use node_api::error::OperatorAPIError::APIFailToJson;
+use node_api::error::OperatorAPIError::{
+    APIAnswerNotFound, APIClockNotFound, APIFailToJson, APIInvalidVrfProof, APIQueryDbError,
+    APIVrfKeyNotReady,
+};
+use sea_orm::DbErr;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::future::Future;
+use tee_llm::nitro_llm::TEEReq;
 use tools::helper::machine_used;
+use vrf::sortition::Selection;
 
 pub async fn not_found(_: web::Data<OperatorArc>, request: HttpRequest) -> String {
     format!("Not support api {}", request.uri())
@@ -28,7 +40,7 @@ async fn status(_req: HttpRequest, op: web::Data<OperatorArc>) -> web::Json<Resp
         mem_total: format!("{} M", memory_total / 1024 / 1024),
         mem_used: format!("{} M", memory_used / 1024 / 1024),
         speed: 1,
-        queue_length: 0,
+        queue_length: op.tee_queue.len() as u32,
     };
 
     let json_data = serde_json::to_value(&resp_data);
@@ -43,3 +55,381 @@ async fn status(_req: HttpRequest, op: web::Data<OperatorArc>) -> web::Json<Resp
         Ok(json_value) => make_resp_json(String::new(), 0, String::new(), json_value),
     }
 }
+
+/// The attested VRF key of the enclave, as `VrfKeyReq`, which every `vrf_verify_pubkey` of this
+/// boot equals.
+#[get("/api/v1/vrf/key")]
+async fn vrf_key(op: web::Data<OperatorArc>) -> web::Json<Response> {
+    // attested afresh for the next call, the certificates of a document expire within hours
+    let _ = op.tee_queue.send(TEEReq::VrfKey);
+    match op.tee_queue.vrf_key() {
+        None => make_resp_json(
+            String::new(),
+            ErrorCodes::API_VRF_KEY_NOT_READY,
+            APIVrfKeyNotReady.to_string(),
+            Value::default(),
+        ),
+        Some(key) => make_resp_json(
+            String::new(),
+            0,
+            String::new(),
+            json!(make_vrf_key_req(&op.config, &key)),
+        ),
+    }
+}
+
+/// A VRF proof as found in an answer, with the sortition parameters of its prompt.
+#[derive(Deserialize, Debug)]
+pub struct VrfVerifyReq {
+    pub vrf_verify_pubkey: String,
+    pub vrf_proof: String,
+    /// The VRF input, i.e. `prompt_hash` of the question.
+    pub vrf_prompt_hash: String,
+    #[serde(default)]
+    pub vrf_threshold: u64,
+    #[serde(default)]
+    pub vrf_precision: usize,
+    /// Overrides `vrf_threshold` out of `vrf_precision` hex digits, as in `PromptReq`.
+    pub vrf_selection: Option<Selection>,
+}
+
+/// Recomputes the output of a VRF proof and whether it selects, see `vrf::sortition::Sortition`.
+#[post("/api/v1/vrf/verify")]
+async fn vrf_verify(req: web::Json<VrfVerifyReq>) -> web::Json<Response> {
+    let selection = match req.vrf_selection {
+        Some(selection) => selection.validate().map(|()| selection),
+        None => Selection::threshold(req.vrf_threshold, req.vrf_precision),
+    };
+    let sortition = selection.and_then(|selection| {
+        vrf::sortition::verify(
+            &req.vrf_verify_pubkey,
+            &req.vrf_proof,
+            req.vrf_prompt_hash.as_bytes(),
+            &selection,
+        )
+    });
+    match sortition {
+        Err(err) => make_resp_json(
+            String::new(),
+            ErrorCodes::API_INVALID_VRF_PROOF,
+            APIInvalidVrfProof(err.to_string()).to_string(),
+            Value::default(),
+        ),
+        Ok(sortition) => make_resp_json(String::new(), 0, String::new(), json!(sortition)),
+    }
+}
+
+/// A past inference: the job's lifecycle status, and the answer as sent in the answer callback,
+/// with the base64 attestation, the signer signature and the VRF proof.
+#[derive(Serialize, Debug)]
+pub struct AnswerRecord {
+    pub request_id: String,
+    /// Of the job, from `received` to `delivered`.
+    pub status: Option<String>,
+    /// Of the answer callback: `pending`, `delivered` or `dead`.
+    pub delivery: Option<String>,
+    /// None until answered.
+    pub answer: Option<Value>,
+}
+
+impl AnswerRecord {
+    fn new(
+        request_id: String,
+        job: Option<inference_jobs::Model>,
+        callback: Option<answer_outbox::Model>,
+    ) -> Self {
+        Self {
+            request_id,
+            status: job.map(|job| job.status),
+            delivery: callback.as_ref().map(|callback| callback.status.clone()),
+            answer: callback.and_then(|callback| serde_json::from_str(&callback.body).ok()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AnswersQuery {
+    /// From 0.
+    #[serde(default)]
+    pub page: u64,
+    /// Up to `read_maximum`, which is also the default.
+    pub page_size: Option<u64>,
+}
+
+impl AnswersQuery {
+    fn page_size(&self, op: &OperatorArc) -> u64 {
+        page_size(op, self.page_size)
+    }
+}
+
+/// Up to `read_maximum`, which is also the default.
+fn page_size(op: &OperatorArc, page_size: Option<u64>) -> u64 {
+    let read_maximum = op.config.api.read_maximum.max(1);
+    page_size.unwrap_or(read_maximum).clamp(1, read_maximum)
+}
+
+#[get("/api/v1/answer/{request_id}")]
+async fn answer(request_id: web::Path<String>, op: web::Data<OperatorArc>) -> web::Json<Response> {
+    let request_id = request_id.into_inner();
+    match op.storage.answer_of(&request_id).await {
+        Err(err) => make_resp_json(
+            request_id,
+            ErrorCodes::API_QUERY_DB_ERROR,
+            APIQueryDbError(err.to_string()).to_string(),
+            Value::default(),
+        ),
+        Ok((None, None)) => make_resp_json(
+            request_id.clone(),
+            ErrorCodes::API_ANSWER_NOT_FOUND,
+            APIAnswerNotFound(request_id).to_string(),
+            Value::default(),
+        ),
+        Ok((job, callback)) => {
+            let record = AnswerRecord::new(request_id.clone(), job, callback);
+            make_resp_json(request_id, 0, String::new(), json!(record))
+        }
+    }
+}
+
+#[get("/api/v1/answers")]
+async fn answers(
+    query: web::Query<AnswersQuery>,
+    op: web::Data<OperatorArc>,
+) -> web::Json<Response> {
+    let page_size = query.page_size(&op);
+    match op.storage.answers_page(query.page, page_size).await {
+        Err(err) => make_resp_json(
+            String::new(),
+            ErrorCodes::API_QUERY_DB_ERROR,
+            APIQueryDbError(err.to_string()).to_string(),
+            Value::default(),
+        ),
+        Ok((answers, total)) => {
+            let answers: Vec<_> = answers
+                .into_iter()
+                .map(|(job, callback)| AnswerRecord::new(job.request_id.clone(), Some(job), callback))
+                .collect();
+            let data = json!({
+                "page": query.page,
+                "page_size": page_size,
+                "total": total,
+                "answers": answers,
+            });
+            make_resp_json(String::new(), 0, String::new(), data)
+        }
+    }
+}
+
+/// The first clock recorded for `message_id`, or the response telling why there is none.
+async fn clock_record(
+    op: &OperatorArc,
+    message_id: &str,
+) -> Result<ClockRecord, web::Json<Response>> {
+    let query_error = |err: String| {
+        make_resp_json(
+            message_id.to_owned(),
+            ErrorCodes::API_QUERY_DB_ERROR,
+            APIQueryDbError(err).to_string(),
+            Value::default(),
+        )
+    };
+    match op.storage.clock_of(message_id).await {
+        Err(err) => Err(query_error(err.to_string())),
+        Ok(None) => Err(make_resp_json(
+            message_id.to_owned(),
+            ErrorCodes::API_CLOCK_NOT_FOUND,
+            APIClockNotFound(message_id.to_owned()).to_string(),
+            Value::default(),
+        )),
+        Ok(Some(model)) => ClockRecord::try_from(model).map_err(|err| query_error(err.to_string())),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ClockOrderQuery {
+    pub first: String,
+    pub second: String,
+}
+
+/// Whether the event of message `first` happened before the one of `second`, after it, or
+/// concurrently, by their clocks, which come along with their attestation documents.
+#[get("/api/v1/clock/order")]
+async fn clock_order(
+    query: web::Query<ClockOrderQuery>,
+    op: web::Data<OperatorArc>,
+) -> web::Json<Response> {
+    let (first, second) = match (
+        clock_record(&op, &query.first).await,
+        clock_record(&op, &query.second).await,
+    ) {
+        (Ok(first), Ok(second)) => (first, second),
+        (Err(resp), _) | (_, Err(resp)) => return resp,
+    };
+    let data = json!({
+        "order": CausalOrder::of(&first.clock.clock, &second.clock.clock),
+        "first": first,
+        "second": second,
+    });
+    make_resp_json(String::new(), 0, String::new(), data)
+}
+
+/// Records of `clock_infos` read at a time for a causal history, and at most in total.
+const HISTORY_BATCH: u64 = 1000;
+const HISTORY_SCAN_MAXIMUM: u64 = 100_000;
+
+/// The ids of the recorded events `clock` dominates, in the order recorded, and whether the scan
+/// stopped at `HISTORY_SCAN_MAXIMUM` records, short of the later ones.
+async fn dominated(op: &OperatorArc, clock: &OrdinaryClock) -> Result<(Vec<i64>, bool), DbErr> {
+    let event_count = clock.reduce() as i64;
+    scan_dominated(clock, HISTORY_BATCH, HISTORY_SCAN_MAXIMUM, |after, limit| {
+        op.storage.clocks_within(event_count, after, limit)
+    })
+    .await
+}
+
+/// Same as `dominated`, `batch` records at a time and `maximum` in total, with `fetch(after,
+/// limit)` reading `Storage::clocks_within`.
+async fn scan_dominated<F, Fut>(
+    clock: &OrdinaryClock,
+    batch: u64,
+    maximum: u64,
+    fetch: F,
+) -> Result<(Vec<i64>, bool), DbErr>
+where
+    F: Fn(i64, u64) -> Fut,
+    Fut: Future<Output = Result<Vec<(i64, String)>, DbErr>>,
+{
+    let (mut ids, mut after, mut scanned) = (Vec::new(), 0, 0);
+    loop {
+        let limit = batch.min(maximum - scanned);
+        let clocks = fetch(after, limit).await?;
+        scanned += clocks.len() as u64;
+        let done = (clocks.len() as u64) < limit;
+        if let Some((id, _)) = clocks.last() {
+            after = *id
+        }
+        ids.extend(clocks.into_iter().filter_map(|(id, event)| {
+            let event = history_clock(&event)?;
+            (CausalOrder::of(&event, clock) == CausalOrder::Before).then_some(id)
+        }));
+        if done {
+            return Ok((ids, false));
+        }
+        if scanned >= maximum {
+            // the last batch may have been the last records as well
+            let truncated = !fetch(after, 1).await?.is_empty();
+            return Ok((ids, truncated));
+        }
+    }
+}
+
+/// A clock as read by `Storage::clocks_within`.
+fn history_clock(json: &str) -> Option<OrdinaryClock> {
+    serde_json::from_str(json)
+        .or_else(|_| serde_json::from_str::<ClockCredential>(json).map(|credential| credential.clock))
+        .ok()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ClockHistoryQuery {
+    /// From 0.
+    #[serde(default)]
+    pub page: u64,
+    /// Up to `read_maximum`, which is also the default.
+    pub page_size: Option<u64>,
+}
+
+/// The causal history of the event of a message, the recorded events its clock dominates, in the
+/// order recorded. `truncated` if there are more records than `HISTORY_SCAN_MAXIMUM` to look
+/// through, in which case `total` only counts the events among the earliest ones.
+#[get("/api/v1/clock/history/{message_id}")]
+async fn clock_history(
+    message_id: web::Path<String>,
+    query: web::Query<ClockHistoryQuery>,
+    op: web::Data<OperatorArc>,
+) -> web::Json<Response> {
+    let message_id = message_id.into_inner();
+    let record = match clock_record(&op, &message_id).await {
+        Ok(record) => record,
+        Err(resp) => return resp,
+    };
+    let page_size = page_size(&op, query.page_size);
+    let offset = usize::try_from(query.page.saturating_mul(page_size)).unwrap_or(usize::MAX);
+    let history = async {
+        let (ids, truncated) = dominated(&op, &record.clock.clock).await?;
+        let page = ids.get(offset..).unwrap_or_default();
+        let page = &page[..page.len().min(page_size as usize)];
+        let models = op.storage.clocks_of(page).await?;
+        Ok::<_, DbErr>((models, ids.len(), truncated))
+    };
+    match history.await {
+        Err(err) => make_resp_json(
+            message_id,
+            ErrorCodes::API_QUERY_DB_ERROR,
+            APIQueryDbError(err.to_string()).to_string(),
+            Value::default(),
+        ),
+        Ok((models, total, truncated)) => {
+            let events: Vec<_> = models
+                .into_iter()
+                .filter_map(|model| ClockRecord::try_from(model).ok())
+                .collect();
+            let data = json!({
+                "clock": record,
+                "page": query.page,
+                "page_size": page_size,
+                "total": total,
+                "truncated": truncated,
+                "events": events,
+            });
+            make_resp_json(message_id, 0, String::new(), data)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records of `(id, clock JSON)`, read as `Storage::clocks_within` does.
+    async fn scan(
+        records: &[(i64, String)],
+        clock: &OrdinaryClock,
+        batch: u64,
+        maximum: u64,
+    ) -> Result<(Vec<i64>, bool), DbErr> {
+        scan_dominated(clock, batch, maximum, |after, limit| {
+            let page = records
+                .iter()
+                .filter(|(id, _)| *id > after)
+                .take(limit as usize)
+                .cloned()
+                .collect();
+            std::future::ready(Ok(page))
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn history_truncated() -> Result<(), DbErr> {
+        let clock = |value| OrdinaryClock([(1, value)].into_iter().collect());
+        let mut records: Vec<_> = (1..=9)
+            .map(|id| (id, serde_json::to_string(&clock(id as u32)).unwrap()))
+            .collect();
+        // recorded before `plain_clock`
+        let credential = ClockCredential {
+            clock: clock(10),
+            tee_attestation: String::new(),
+        };
+        records.push((10, serde_json::to_string(&credential).unwrap()));
+
+        let all: Vec<i64> = (1..=10).collect();
+        assert_eq!(scan(&records, &clock(11), 3, 100).await?, (all.clone(), false));
+        // as many records as scanned at most, none left
+        assert_eq!(scan(&records, &clock(11), 5, 10).await?, (all, false));
+        assert_eq!(scan(&records, &clock(11), 3, 4).await?, (vec![1, 2, 3, 4], true));
+        // only the dominated ones
+        assert_eq!(scan(&records, &clock(3), 3, 100).await?, (vec![1, 2], false));
+        Ok(())
+    }
+}
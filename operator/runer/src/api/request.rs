@@ -1,17 +1,30 @@
 use crate::api::response::WorkerStatus;
+use crate::outbox::Outbox;
+use crate::storage::Storage;
+use crate::tee_queue::TeeQueue;
+use crate::vlc::{ClockEvent, VlcClock};
 use alloy_primitives::hex::FromHex;
 use alloy_primitives::B256;
 use alloy_wrapper::util::sign_message;
-use common::crypto::core::DigestHash;
-use node_api::config::OperatorConfig;
+use common::crypto::canonical::{bincode_digest, CanonicalDigest as _};
+use db_sql::pg::entities::inference_jobs::JobStatus;
+use node_api::config::{OperatorConfig, SamplingBounds};
 use node_api::error::OperatorError;
 use reqwest::Client as ReqwestClient;
 use serde::{Deserialize, Serialize};
-use tee_llm::nitro_llm::{AnswerResp, TEEResp};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tee_llm::chat::ChatMessage;
+use tee_llm::commitment::INFERENCE_COMMITMENT_VERSION;
+use tee_llm::nitro_llm::{AnswerResp, TEEReq, TEEResp, VrfKeyResp};
+use tee_llm::sampling::{SamplerOptions, SamplingParams};
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::time::{sleep, Duration};
 use tools::helper::machine_used;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
+pub use verifier::callback::{
+    AnswerCallbackReq, ClockCredential, TEECredential, VRFProof, VrfKeyReq,
+};
 
 #[derive(serde::Serialize)]
 pub struct RegisterWorkerReq {
@@ -30,9 +43,64 @@ pub struct RegisterHeartbeatReq {
 
 #[derive(Serialize, Deserialize, Default, Debug)]
 pub struct InferParams {
-    pub temperature: f32,
-    pub top_p: f32,
     pub max_tokens: u32,
+    /// Makes the inference reproducible, a random one is picked and reported back if absent.
+    #[serde(default)]
+    pub seed: Option<u32>,
+    /// `temperature`, `top_p`, `top_k`, `stop` and the like, all optional.
+    #[serde(flatten)]
+    pub sampler: SamplerOptions,
+}
+
+impl InferParams {
+    /// Checks the params against the bounds of the model, and builds the sampling setup.
+    pub fn sampling(&self, bounds: &SamplingBounds) -> Result<SamplingParams, String> {
+        let sampler = &self.sampler;
+        if self.max_tokens == 0 || self.max_tokens > bounds.max_tokens {
+            return Err(format!(
+                "max_tokens {} out of range [1, {}]",
+                self.max_tokens, bounds.max_tokens
+            ));
+        }
+        if sampler.temperature > bounds.max_temperature {
+            return Err(format!(
+                "temperature {} above {}",
+                sampler.temperature, bounds.max_temperature
+            ));
+        }
+        // 0 means no top k at all
+        if sampler.top_k == 0 || sampler.top_k > bounds.max_top_k {
+            return Err(format!(
+                "top_k {} out of range [1, {}]",
+                sampler.top_k, bounds.max_top_k
+            ));
+        }
+        if sampler.repetition_penalty > bounds.max_repetition_penalty {
+            return Err(format!(
+                "repetition_penalty {} above {}",
+                sampler.repetition_penalty, bounds.max_repetition_penalty
+            ));
+        }
+        if sampler.stop.len() > bounds.max_stop_sequences {
+            return Err(format!(
+                "more than {} stop sequences",
+                bounds.max_stop_sequences
+            ));
+        }
+        if sampler
+            .stop
+            .iter()
+            .any(|stop| stop.len() > bounds.max_stop_len)
+        {
+            return Err(format!(
+                "stop sequence longer than {} bytes",
+                bounds.max_stop_len
+            ));
+        }
+        let sampling = sampler.to_params(self.seed);
+        sampling.validate().map_err(|err| err.to_string())?;
+        Ok(sampling)
+    }
 }
 
 #[derive(Serialize, Deserialize, Default, Debug)]
@@ -40,37 +108,40 @@ pub struct QuestionReq {
     pub request_id: String,
     pub node_id: String,
     pub model: String,
+    #[serde(default)]
     pub prompt: String,
+    /// Chat form of the question, instead of `prompt`.
+    #[serde(default)]
+    pub messages: Vec<ChatMessage>,
     pub params: InferParams,
     pub prompt_hash: String,
     pub signature: String,
+    /// Optional freshness challenge, bound into the attested inference commitment.
+    #[serde(default)]
+    pub nonce: String,
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
-pub struct AnswerCallbackReq {
-    request_id: String,
-    node_id: String,
-    model: String,
-    prompt: String,
-    answer: String,
-    elapsed: u64,
-    selected: bool,
-    vrf_proof: VRFProof,
-    tee_credential: TEECredential,
-}
-
-#[derive(Serialize, Deserialize, Default, Debug)]
-pub struct VRFProof {
-    pub vrf_prompt_hash: String,
-    pub vrf_random_value: String,
-    pub vrf_verify_pubkey: String,
-    pub vrf_proof: String,
+impl QuestionReq {
+    /// What the requester signs, as an EIP-191 personal message. It binds the request id and the
+    /// nonce, so that a signed question cannot be replayed under another one.
+    pub fn signed_message(&self) -> String {
+        format!(
+            "{}:{}:{}:{}",
+            self.request_id, self.model, self.prompt_hash, self.nonce
+        )
+    }
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
-pub struct TEECredential {
-    pub tee_attestation: String,
-    pub tee_attest_signature: String,
+/// Hex SHA-256 of what is asked: the prompt as UTF-8, or else the canonical encoding of the
+/// messages, see `common::crypto::canonical`. That is the number of messages, then for each one
+/// the index of its role (`system`, `user`, `assistant`) and the length of its content, followed
+/// by the content, the numbers as varints.
+pub fn prompt_hash(prompt: &str, messages: &[ChatMessage]) -> String {
+    if messages.is_empty() {
+        hex::encode(Sha256::digest(prompt))
+    } else {
+        hex::encode(bincode_digest(messages))
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Default)]
@@ -78,7 +149,10 @@ struct HeartbeatResp {
     exist: bool,
 }
 
-pub async fn register_worker(config: &OperatorConfig) -> Result<reqwest::Response, reqwest::Error> {
+pub async fn register_worker(
+    config: &OperatorConfig,
+    queue_length: u32,
+) -> Result<reqwest::Response, reqwest::Error> {
     let (cpu_percent, cpu_nums, memory_total, memory_used) = machine_used();
     let worker_status = WorkerStatus {
         node_id: config.node.node_id.clone(),
@@ -88,7 +162,7 @@ pub async fn register_worker(config: &OperatorConfig) -> Result<reqwest::Respons
         mem_total: format!("{} M", memory_total / 1024 / 1024),
         mem_used: format!("{} M", memory_used / 1024 / 1024),
         speed: 1,
-        queue_length: 0,
+        queue_length,
     };
 
     let body = RegisterWorkerReq {
@@ -111,13 +185,16 @@ pub async fn register_worker(config: &OperatorConfig) -> Result<reqwest::Respons
         .await
 }
 
-async fn register_heartbeat(config: &OperatorConfig) -> Result<reqwest::Response, reqwest::Error> {
+async fn register_heartbeat(
+    config: &OperatorConfig,
+    queue_length: u32,
+) -> Result<reqwest::Response, reqwest::Error> {
     debug!("Registering heartbeat to dispatcher...");
 
     let body = RegisterHeartbeatReq {
         worker_name: config.net.outer_url.clone(),
         node_id: config.node.node_id.clone(),
-        queue_length: 0,
+        queue_length,
     };
 
     let client = ReqwestClient::new();
@@ -133,10 +210,11 @@ async fn register_heartbeat(config: &OperatorConfig) -> Result<reqwest::Response
         .await
 }
 
-pub async fn periodic_heartbeat_task(config: OperatorConfig) {
+pub async fn periodic_heartbeat_task(config: OperatorConfig, tee_queue: Arc<TeeQueue>) {
     let interval = Duration::from_secs(config.node.heartbeat_interval);
     loop {
-        match register_heartbeat(&config).await {
+        let queue_length = tee_queue.len() as u32;
+        match register_heartbeat(&config, queue_length).await {
             Ok(response) => {
                 debug!("Response status: {}", response.status());
                 match response.text().await {
@@ -145,7 +223,7 @@ pub async fn periodic_heartbeat_task(config: OperatorConfig) {
                         let json = serde_json::from_str(&body).unwrap_or_default();
                         let data: HeartbeatResp = serde_json::from_value(json).unwrap_or_default();
                         if !data.exist {
-                            let response = register_worker(&config)
+                            let response = register_worker(&config, queue_length)
                                 .await
                                 .map_err(OperatorError::OPSetupRegister)
                                 .unwrap();
@@ -166,27 +244,45 @@ pub async fn periodic_heartbeat_task(config: OperatorConfig) {
     }
 }
 
-async fn answer_callback(
-    config: &OperatorConfig,
-    answer: &AnswerResp,
-) -> Result<reqwest::Response, reqwest::Error> {
-    info!("answer callback to dispatcher. answer = {:?}", answer);
-    use DigestHash as _;
-
-    let mut sig_hex = String::new();
-    let base64_attest = base64::encode(answer.document.0.clone());
+/// Base64 of an attestation document, signed by the operator over its canonical digest.
+fn make_tee_credential(config: &OperatorConfig, document: &[u8]) -> TEECredential {
+    let mut credential = TEECredential {
+        tee_attestation: base64::encode(document),
+        ..Default::default()
+    };
     let signer_key = B256::from_hex(config.node.signer_key.clone());
     if let Ok(signer_key) = signer_key {
-        let msg = base64_attest.sha256().to_fixed_bytes();
-        let sig = sign_message(signer_key.0, msg).unwrap_or_default();
-        sig_hex = sig.to_hex_bytes().to_string();
+        let sig = sign_message(signer_key.0, credential.canonical_digest()).unwrap_or_default();
+        credential.tee_attest_signature = sig.to_hex_bytes().to_string();
     }
+    credential
+}
 
-    let body = AnswerCallbackReq {
+/// The attested VRF key of the enclave, signed like answers are.
+pub fn make_vrf_key_req(config: &OperatorConfig, key: &VrfKeyResp) -> VrfKeyReq {
+    VrfKeyReq {
+        node_id: config.node.node_id.clone(),
+        public_key: key.public_key.clone(),
+        tee_credential: make_tee_credential(config, &key.document.0),
+    }
+}
+
+/// Signed callback body of an answer, also the final event of an answer stream.
+/// `transcript_hash` is the one of the stream end for a streamed answer, empty otherwise, and
+/// `clock` the one the answer was ticked with.
+pub fn make_answer_callback_req(
+    config: &OperatorConfig,
+    answer: &AnswerResp,
+    transcript_hash: &str,
+    clock: Option<ClockCredential>,
+) -> AnswerCallbackReq {
+    AnswerCallbackReq {
         node_id: config.node.node_id.clone(),
         request_id: answer.request_id.clone(),
         model: answer.model_name.clone(),
         prompt: answer.prompt.clone(),
+        messages: answer.messages.clone(),
+        chat_template: answer.chat_template,
         answer: answer.answer.clone(),
         elapsed: answer.elapsed,
         selected: answer.selected,
@@ -195,14 +291,27 @@ async fn answer_callback(
             vrf_random_value: answer.vrf_random_value.clone(),
             vrf_verify_pubkey: answer.vrf_verify_pubkey.clone(),
             vrf_proof: answer.vrf_proof.clone(),
+            vrf_batchable_proof: answer.vrf_batchable_proof.clone(),
         },
-        tee_credential: TEECredential {
-            tee_attestation: base64_attest,
-            tee_attest_signature: sig_hex,
-        }
-    };
+        tee_credential: make_tee_credential(config, &answer.document.0),
+        commitment_version: INFERENCE_COMMITMENT_VERSION,
+        model_hash: answer.model_hash.clone(),
+        sampling: answer.sampling.clone(),
+        max_tokens: answer.n_predict as u32,
+        nonce: answer.nonce.clone(),
+        transcript_hash: transcript_hash.to_string(),
+        clock,
+    }
+}
+
+/// Posts a callback body, as made by `make_answer_callback_req`, to the dispatcher.
+pub async fn answer_callback(
+    client: &ReqwestClient,
+    config: &OperatorConfig,
+    body: String,
+) -> Result<reqwest::Response, reqwest::Error> {
+    info!("answer callback to dispatcher. body = {}", body);
 
-    let client = ReqwestClient::new();
     client
         .post(format!(
             "{}{}",
@@ -210,14 +319,117 @@ async fn answer_callback(
             "/api/tee_callback"
         ))
         .header("Content-Type", "application/json; charset=utf-8")
-        .json(&body)
+        .body(body)
         .send()
         .await
 }
 
+/// Answers are expected to be proven with the attested key, a mismatch means the enclave has
+/// restarted since, or is not the one attested.
+fn check_vrf_key(tee_queue: &TeeQueue, answer: &AnswerResp) {
+    match tee_queue.vrf_key() {
+        Some(key) if key.public_key != answer.vrf_verify_pubkey => {
+            warn!(
+                "answer {} proven with vrf key {}, not the attested {}",
+                answer.request_id, answer.vrf_verify_pubkey, key.public_key
+            );
+            // fetch the current one
+            let _ = tee_queue.send(TEEReq::VrfKey);
+        }
+        _ => {}
+    }
+}
+
+/// Recomputes the VRF of an answer and whether it selects rather than trusting its `selected`,
+/// by the selection of the prompt in flight, or the one recorded with its job for an answer to a
+/// prompt no longer queued. An answer without either does not check out.
+async fn check_vrf(
+    tee_queue: &TeeQueue,
+    storage: &Storage,
+    answer: &AnswerResp,
+) -> Result<(), OperatorError> {
+    let failed = |err: String| OperatorError::OPVrfCheckFailed(answer.request_id.clone(), err);
+    let selection = match tee_queue.selection(&answer.request_id) {
+        Some(selection) => selection,
+        None => storage
+            .job_selection(&answer.request_id)
+            .await
+            .map_err(|err| failed(err.to_string()))?
+            .ok_or_else(|| failed("no selection recorded for the prompt".into()))?,
+    };
+    answer
+        .verify_vrf(Some(&selection))
+        .map_err(|err| failed(err.to_string()))
+}
+
+/// Ticks the clock for an answer that checked out, ahead of handing it to its waiter, which
+/// then finds the clock with `VlcClock::answer_clock`.
+async fn clock_answer(
+    vlc: Option<&VlcClock>,
+    answer: &AnswerResp,
+    checked: &Result<(), OperatorError>,
+) -> Option<ClockCredential> {
+    let vlc = vlc.filter(|_| checked.is_ok())?;
+    vlc.tick(ClockEvent::Answer {
+        request_id: &answer.request_id,
+        selected: answer.selected,
+    })
+    .await
+}
+
+/// Records an answer, and calls it back if its VRF checked out.
+async fn answered(
+    storage: &Storage,
+    outbox: &Outbox,
+    answer: &AnswerResp,
+    transcript_hash: &str,
+    checked: Result<(), OperatorError>,
+    clock: Option<ClockCredential>,
+) {
+    storage.job_answered(answer).await;
+    match checked {
+        Ok(()) => outbox.push(answer, transcript_hash, clock).await,
+        Err(err) => {
+            error!("{}", err);
+            storage
+                .job_status(
+                    &answer.request_id,
+                    JobStatus::CallbackFailed,
+                    Some(err.to_string()),
+                )
+                .await
+        }
+    }
+}
+
+/// Checks, clocks and dispatches an answer, of `TEEResp::AnswerResp` or `TEEResp::StreamEnd`, then
+/// records it and calls it back. Spawned per answer, for the listener to go on forwarding the
+/// replies of other prompts meanwhile.
+async fn answer_task(
+    resp: TEEResp,
+    tee_queue: Arc<TeeQueue>,
+    vlc: Option<Arc<VlcClock>>,
+    storage: Storage,
+    outbox: Arc<Outbox>,
+) {
+    let (answer, transcript_hash) = match &resp {
+        TEEResp::AnswerResp(answer) => (answer.clone(), String::new()),
+        TEEResp::StreamEnd(end) => (end.answer.clone(), end.transcript_hash.clone()),
+        _ => return,
+    };
+    check_vrf_key(&tee_queue, &answer);
+    let checked = check_vrf(&tee_queue, &storage, &answer).await;
+    let clock = clock_answer(vlc.as_deref(), &answer, &checked).await;
+    tee_queue.dispatch(resp);
+    answered(&storage, &outbox, &answer, &transcript_hash, checked, clock).await
+}
+
 pub async fn listening_tee_resp_task(
-    config: OperatorConfig,
     mut receiver: UnboundedReceiver<TEEResp>,
+    tee_queue: Arc<TeeQueue>,
+    vlc: Option<Arc<VlcClock>>,
+    storage: Storage,
+    outbox: Arc<Outbox>,
 ) {
     loop {
         if let Some(resp) = receiver.recv().await {
@@ -225,16 +437,38 @@ pub async fn listening_tee_resp_task(
                 TEEResp::Ping(pong) => {
                     debug!("Response pong: {:?}", pong);
                 }
-                TEEResp::AnswerResp(answer) => match answer_callback(&config, &answer).await {
-                    Ok(response) => {
-                        debug!("Response status: {}", response.status());
-                        match response.text().await {
-                            Ok(body) => debug!("Response body: {}", body),
-                            Err(err) => error!("Failed to read response body, {}", err),
-                        }
-                    }
-                    Err(err) => error!("answer callback request error, {}", err),
+                TEEResp::Models(resp) => match resp.error {
+                    Some(err) => error!("tee model management failed, {}", err),
+                    None => info!("resident models in tee: {:?}", resp.models),
                 },
+                key @ TEEResp::VrfKey(_) => {
+                    tee_queue.dispatch(key);
+                    if let Some(key) = tee_queue.vrf_key() {
+                        info!("vrf key of tee: {}", key.public_key)
+                    }
+                }
+                // the chunks of a prompt are all forwarded before its end is
+                chunk @ TEEResp::TokenChunk(_) => tee_queue.dispatch(chunk),
+                TEEResp::Failed(failed) => {
+                    error!("tee failed to answer {}, {}", failed.request_id, failed.error);
+                    let (request_id, error) = (failed.request_id.clone(), failed.error.clone());
+                    tee_queue.dispatch(TEEResp::Failed(failed));
+                    let storage = storage.clone();
+                    tokio::spawn(async move {
+                        storage
+                            .job_status(&request_id, JobStatus::Failed, Some(error))
+                            .await
+                    });
+                }
+                answer @ (TEEResp::AnswerResp(_) | TEEResp::StreamEnd(_)) => {
+                    tokio::spawn(answer_task(
+                        answer,
+                        tee_queue.clone(),
+                        vlc.clone(),
+                        storage.clone(),
+                        outbox.clone(),
+                    ));
+                }
             }
         }
     }
@@ -278,4 +512,86 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn signed_question() {
+        use alloy_wrapper::util::{
+            generate_eth_account, recover_signer_alloy, sign_message_with_chainid,
+        };
+
+        let (secret, _, address) = generate_eth_account();
+        let mut quest = QuestionReq {
+            request_id: "1".into(),
+            model: "llama-2-7b-chat.Q4_0.gguf".into(),
+            prompt: "How to combine AI and blockchain?".into(),
+            nonce: "n".into(),
+            ..Default::default()
+        };
+        quest.prompt_hash = prompt_hash(&quest.prompt, &quest.messages);
+        assert_eq!(quest.prompt_hash.len(), 64);
+        let (sig, _) = sign_message_with_chainid(secret, &quest.signed_message(), 1).unwrap();
+        let sig = hex::encode(sig.as_bytes());
+        let signer = recover_signer_alloy(sig.clone(), &quest.signed_message()).unwrap();
+        assert_eq!(
+            signer,
+            address.parse::<alloy_primitives::Address>().unwrap()
+        );
+
+        // not valid for another request id
+        quest.request_id = "2".into();
+        let signer = recover_signer_alloy(sig, &quest.signed_message()).unwrap();
+        assert_ne!(
+            signer,
+            address.parse::<alloy_primitives::Address>().unwrap()
+        );
+    }
+
+    #[test]
+    fn prompt_hash_of_messages() {
+        let messages = [
+            ChatMessage {
+                role: tee_llm::chat::Role::System,
+                content: "Be brief.".into(),
+            },
+            ChatMessage {
+                role: tee_llm::chat::Role::User,
+                content: "Hi".into(),
+            },
+        ];
+        let encoding = [&[2, 0, 9][..], b"Be brief.", &[1, 2], b"Hi"].concat();
+        assert_eq!(prompt_hash("", &messages), hex::encode(Sha256::digest(encoding)));
+        assert_eq!(prompt_hash("Hi", &[]), hex::encode(Sha256::digest("Hi")));
+    }
+
+    #[test]
+    fn infer_params() -> Result<(), serde_json::Error> {
+        let bounds = SamplingBounds::default();
+        // the former shape still parses, to the former setup
+        let params: InferParams =
+            serde_json::from_str(r#"{"temperature": 0.5, "top_p": 0.9, "max_tokens": 128}"#)?;
+        assert_eq!(
+            params.sampling(&bounds),
+            Ok(SamplingParams::with(0.5, 0.9, None))
+        );
+
+        let params: InferParams = serde_json::from_str(
+            r#"{"max_tokens": 128, "seed": 7, "top_k": 20, "mirostat": false, "stop": ["\n"]}"#,
+        )?;
+        let sampling = params.sampling(&bounds).unwrap();
+        assert_eq!(sampling.seed, Some(7));
+        assert_eq!(sampling.stop, ["\n"]);
+
+        for json in [
+            r#"{"max_tokens": 0}"#,
+            r#"{"max_tokens": 4096}"#,
+            r#"{"max_tokens": 128, "temperature": 3.0}"#,
+            r#"{"max_tokens": 128, "top_k": 0}"#,
+            r#"{"max_tokens": 128, "top_p": 1.5}"#,
+            r#"{"max_tokens": 128, "stop": ["a", "b", "c", "d", "e"]}"#,
+        ] {
+            let params: InferParams = serde_json::from_str(json)?;
+            assert!(params.sampling(&bounds).is_err(), "{json}")
+        }
+        Ok(())
+    }
 }
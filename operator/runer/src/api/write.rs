@@ -1,74 +1,358 @@
-use crate::api::request::QuestionReq;
-use crate::api::response::{make_resp_json, Response};
+use crate::api::request::{make_answer_callback_req, prompt_hash, QuestionReq};
+use crate::api::response::make_resp_json;
 use crate::operator::OperatorArc;
+use crate::tee_queue::{SubmitError, Waiter};
+use crate::vlc::ClockEvent;
+use actix_web::http::StatusCode;
 use actix_web::{body, get, post, web, Error, HttpRequest, HttpResponse, Result};
 use alloy::primitives::{address, Address};
 use alloy_wrapper::contracts::vrf_range;
 use alloy_wrapper::util::recover_signer_alloy;
+use db_sql::pg::entities::inference_jobs::JobStatus;
 use node_api::error::ErrorCodes;
 use node_api::error::{
-    OperatorAPIError::APIFailToJson,
-    OperatorError::{OPGetVrfRangeContractError, OPSendPromptError},
+    OperatorAPIError::{APIFailToJson, APIQueryDbError},
+    OperatorError::{
+        OPDuplicateRequest, OPGetVrfRangeContractError, OPInvalidPrompt, OPInvalidPromptHash,
+        OPInvalidSamplingParams, OPInvalidSignature, OPQueueFull, OPReplayedRequest,
+        OPSendPromptError, OPSignerNotAllowed,
+    },
 };
 // use serde::{Deserialize, Serialize};
-use serde_json::json;
 use hex::FromHex;
-use tee_llm::nitro_llm::{PromptReq, TEEReq};
-use tracing::{debug, error, info};
+use serde_json::json;
+use tee_llm::chat;
+use tee_llm::nitro_llm::{PromptReq, TEEReq, TEEResp};
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::time::{timeout_at, Instant};
+use tracing::{debug, info};
+use vrf::sortition::Selection;
 
 /// WRITE API
 // question input a prompt, and async return success, the answer callback later
 #[post("/api/v1/question")]
-async fn question(
+async fn question(quest: web::Json<QuestionReq>, op: web::Data<OperatorArc>) -> HttpResponse {
+    info!("Receive request, body = {:?}", quest);
+
+    let req = match admit(&quest, &op).await {
+        Ok(req) => req,
+        Err(rejection) => return rejected(&quest.request_id, rejection),
+    };
+    if let Err(err) = submit(&op, TEEReq::PromptReq(req), Waiter::Callback).await {
+        return rejected(&quest.request_id, submit_error(err));
+    }
+    let json_data = json!({});
+    HttpResponse::Ok()
+        .json(make_resp_json(quest.request_id.clone(), 0, String::new(), json_data).into_inner())
+}
+
+// same as question, but reply the answer as server-sent events: a `token` event per token
+// chunk, then an `end` event carrying the answer with the attested transcript. the answer
+// callback is still made
+#[post("/api/v1/question/stream")]
+async fn question_stream(
     quest: web::Json<QuestionReq>,
     op: web::Data<OperatorArc>,
-) -> web::Json<Response> {
-    info!("Receive request, body = {:?}", quest);
+) -> HttpResponse {
+    info!("Receive stream request, body = {:?}", quest);
+
+    let req = match admit(&quest, &op).await {
+        Ok(req) => req,
+        Err(rejection) => return rejected(&quest.request_id, rejection),
+    };
+    let (sender, receiver) = unbounded_channel();
+    let deadline = match submit(&op, TEEReq::StreamPromptReq(req), Waiter::Stream(sender)).await {
+        Ok(deadline) => deadline,
+        Err(err) => return rejected(&quest.request_id, submit_error(err)),
+    };
 
-    // todo: validate parameter and signature
-    if !quest.signature.is_empty() && !quest.prompt_hash.is_empty() {
-        let addr = recover_signer_alloy(quest.signature.clone(), &quest.prompt_hash);
-        if let Err(err) = addr {
-            error!("Validate signature error, detail = {:?}", err);
-        } else {
-            debug!("recovered addr : {:?}", addr.unwrap());
+    let config = op.config.clone();
+    let tee_queue = op.tee_queue.clone();
+    let vlc = op.vlc.clone();
+    let request_id = quest.request_id.clone();
+    let events = futures::stream::unfold(receiver, move |mut receiver| {
+        let (config, tee_queue, vlc, request_id) = (
+            config.clone(),
+            tee_queue.clone(),
+            vlc.clone(),
+            request_id.clone(),
+        );
+        async move {
+            let Ok(resp) = timeout_at(deadline, receiver.recv()).await else {
+                tee_queue.abandon(&request_id);
+                return None;
+            };
+            // the subscriber is dropped after the end event, which closes the stream
+            let event = match resp? {
+                TEEResp::TokenChunk(chunk) => sse_event("token", json!(chunk)),
+                TEEResp::StreamEnd(end) => {
+                    let clock = vlc.and_then(|vlc| vlc.answer_clock(&request_id));
+                    let answer =
+                        make_answer_callback_req(&config, &end.answer, &end.transcript_hash, clock);
+                    sse_event("end", json!({ "chunks": end.chunks, "answer": answer }))
+                }
+                TEEResp::Failed(failed) => sse_event("error", json!(failed)),
+                _ => return None,
+            };
+            Some((Ok::<_, Error>(event), receiver))
         }
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events)
+}
+
+/// Records the job of a prompt and queues it, and once queued ticks the clock for it. A prompt
+/// not queued releases the request id and nonce `admit` claimed, for it to be retried.
+pub(crate) async fn submit(
+    op: &OperatorArc,
+    req: TEEReq,
+    waiter: Waiter,
+) -> Result<Instant, SubmitError> {
+    let asked = match &req {
+        TEEReq::PromptReq(prompt) | TEEReq::StreamPromptReq(prompt) => Some((
+            prompt.request_id.clone(),
+            prompt.vrf_prompt_hash.clone(),
+            nonce_key(&prompt.nonce),
+        )),
+        _ => None,
+    };
+    let recorded = match &req {
+        TEEReq::PromptReq(prompt) | TEEReq::StreamPromptReq(prompt) => op
+            .storage
+            .job_received(prompt)
+            .await
+            .then(|| prompt.request_id.clone()),
+        _ => None,
+    };
+    let result = op.tee_queue.submit(req, waiter);
+    // a duplicate is not recorded, so it must not touch the job of the same request id
+    if let Some(request_id) = recorded {
+        let status = match &result {
+            Ok(_) => JobStatus::Submitted,
+            Err(_) => JobStatus::Rejected,
+        };
+        let error = result.as_ref().err().map(|err| format!("{err:?}"));
+        op.storage.job_status(&request_id, status, error).await
     }
+    if let (Err(_), Some((request_id, _, nonce))) = (&result, &asked) {
+        let mut state = op.state.write().await;
+        state.forget(request_id);
+        if let Some(nonce) = nonce {
+            state.forget(nonce)
+        }
+    }
+    if let (Ok(_), Some(vlc), Some((request_id, prompt_hash, _))) = (&result, &op.vlc, &asked) {
+        vlc.tick(ClockEvent::Question {
+            request_id,
+            prompt_hash,
+        })
+        .await;
+    }
+    result
+}
+
+/// The HTTP status, error code and message a question is refused with.
+pub(crate) type Rejection = (StatusCode, u32, String);
+
+fn rejected(request_id: &str, (status, code, msg): Rejection) -> HttpResponse {
+    let resp = make_resp_json(request_id.to_owned(), code, msg, serde_json::Value::default());
+    HttpResponse::build(status).json(resp.into_inner())
+}
+
+/// The rejection of a prompt refused by the tee queue.
+pub(crate) fn submit_error(err: SubmitError) -> Rejection {
+    match err {
+        SubmitError::Full(depth) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            ErrorCodes::OP_QUEUE_FULL,
+            OPQueueFull(depth).to_string(),
+        ),
+        SubmitError::Duplicate(request_id) => (
+            StatusCode::CONFLICT,
+            ErrorCodes::OP_DUPLICATE_REQUEST,
+            OPDuplicateRequest(request_id).to_string(),
+        ),
+        SubmitError::Closed => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCodes::OP_SEND_PROMPT_ERROR,
+            OPSendPromptError("tee session closed".into()).to_string(),
+        ),
+    }
+}
+
+fn sse_event(event: &str, data: serde_json::Value) -> web::Bytes {
+    web::Bytes::from(format!("event: {event}\ndata: {data}\n\n"))
+}
+
+/// Authenticates a question, builds its prompt and claims its request id and nonce against
+/// replays, which `submit` releases if the prompt is not queued.
+pub(crate) async fn admit(quest: &QuestionReq, op: &OperatorArc) -> Result<PromptReq, Rejection> {
+    let prompt_hash = authenticate(quest, op)?;
+    let req = prompt_req(quest, prompt_hash, op).await?;
+    claim(quest, op).await?;
+    Ok(req)
+}
+
+/// Checks that a question is what was asked for and signed by an allowed signer, and returns its
+/// prompt hash as recomputed.
+fn authenticate(quest: &QuestionReq, op: &OperatorArc) -> Result<String, Rejection> {
+    // the hash is the VRF input, which must not be left to the requester's choice
+    let expected = prompt_hash(&quest.prompt, &quest.messages);
+    let given = quest.prompt_hash.trim_start_matches("0x");
+    if !given.eq_ignore_ascii_case(&expected) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            ErrorCodes::OP_INVALID_PROMPT_HASH,
+            OPInvalidPromptHash(quest.request_id.clone()).to_string(),
+        ));
+    }
+
+    // never empty, see `OperatorConfig::validate_config`
+    let allowed = &op.config.api.allowed_signers;
+    let signer = recover_signer_alloy(quest.signature.clone(), &quest.signed_message()).map_err(
+        |err| {
+            (
+                StatusCode::UNAUTHORIZED,
+                ErrorCodes::OP_INVALID_SIGNATURE,
+                OPInvalidSignature(err.to_string()).to_string(),
+            )
+        },
+    )?;
+    if !allowed
+        .iter()
+        .any(|addr| addr.parse::<Address>().is_ok_and(|addr| addr == signer))
+    {
+        return Err((
+            StatusCode::FORBIDDEN,
+            ErrorCodes::OP_SIGNER_NOT_ALLOWED,
+            OPSignerNotAllowed(signer.to_string()).to_string(),
+        ));
+    }
+    debug!("request {} signed by {}", quest.request_id, signer);
+    Ok(expected)
+}
+
+/// Refuses a question whose request id or nonce was seen before, and otherwise remembers both.
+async fn claim(quest: &QuestionReq, op: &OperatorArc) -> Result<(), Rejection> {
+    let replayed = || {
+        (
+            StatusCode::CONFLICT,
+            ErrorCodes::OP_REPLAYED_REQUEST,
+            OPReplayedRequest(quest.request_id.clone()).to_string(),
+        )
+    };
+    // past the cache, or from before a restart
+    match op.storage.job_exists(&quest.request_id).await {
+        Ok(false) => {}
+        Ok(true) => return Err(replayed()),
+        Err(err) => {
+            return Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                ErrorCodes::API_QUERY_DB_ERROR,
+                APIQueryDbError(err.to_string()).to_string(),
+            ))
+        }
+    }
+    let nonce = nonce_key(&quest.nonce);
+    let mut state = op.state.write().await;
+    if state.is_known(&quest.request_id) || nonce.as_ref().is_some_and(|nonce| state.is_known(nonce))
+    {
+        return Err(replayed());
+    }
+    state.remember(quest.request_id.clone());
+    if let Some(nonce) = nonce {
+        state.remember(nonce)
+    }
+    Ok(())
+}
+
+/// The key a nonce is remembered by, apart from request ids. An empty nonce is none.
+fn nonce_key(nonce: &str) -> Option<String> {
+    (!nonce.is_empty()).then(|| format!("nonce:{nonce}"))
+}
+
+/// A question is either a raw prompt or a conversation.
+fn validate_prompt(quest: &QuestionReq) -> Result<(), String> {
+    match (quest.prompt.is_empty(), quest.messages.is_empty()) {
+        (false, true) => Ok(()),
+        (true, false) => chat::validate(&quest.messages).map_err(|err| err.to_string()),
+        (false, false) => Err("both prompt and messages given".into()),
+        (true, true) => Err("neither prompt nor messages given".into()),
+    }
+}
+
+/// Validates a question and builds the prompt for the enclave, with `prompt_hash` as the VRF
+/// input.
+async fn prompt_req(
+    quest: &QuestionReq,
+    prompt_hash: String,
+    op: &OperatorArc,
+) -> Result<PromptReq, Rejection> {
+    if let Err(err) = validate_prompt(quest) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            ErrorCodes::OP_INVALID_PROMPT,
+            OPInvalidPrompt(err).to_string(),
+        ));
+    }
+    let bounds = op.config.node.bounds_of(&quest.model);
+    let sampling = match quest.params.sampling(&bounds) {
+        Ok(sampling) => sampling,
+        Err(err) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                ErrorCodes::OP_INVALID_SAMPLING_PARAMS,
+                OPInvalidSamplingParams(err).to_string(),
+            ))
+        }
+    };
 
     // todo: move to others
     let bytes = <[u8; 20]>::from_hex(&op.config.node.node_id[2..]).unwrap_or_default();
     let addr: Address = Address::new(bytes);
-    let threshold = vrf_range::get_range_by_address(op.vrf_range_contract.clone(), addr).await;
-    if let Err(err) = threshold {
-        return make_resp_json(
-            quest.request_id.clone(),
-            ErrorCodes::OP_GET_RANGE_CONTRACT_ERROR,
-            OPGetVrfRangeContractError(err.to_string()).to_string(),
-            serde_json::Value::default(),
-        );
-    }
+    let range = vrf_range::get_range_by_address(op.vrf_range_contract.clone(), addr)
+        .await
+        .map_err(|err| err.to_string())
+        .and_then(|(start, end)| {
+            let precision = op.config.chain.vrf_sort_precision as usize;
+            let selection = Selection::space(precision).and_then(|space| {
+                match op.config.chain.vrf_expected_selected {
+                    None => Selection::range(start, end, space),
+                    Some(expected) => Selection::stake_range(start, end, space, expected),
+                }
+            });
+            // the width, for the record, the selection is what the enclave goes by
+            let threshold = u64::try_from(end - start).unwrap_or(u64::MAX);
+            selection
+                .map(|selection| (threshold, selection))
+                .map_err(|err| err.to_string())
+        });
+    let (threshold, selection) = match range {
+        Ok(range) => range,
+        Err(err) => {
+            return Err((
+                StatusCode::BAD_GATEWAY,
+                ErrorCodes::OP_GET_RANGE_CONTRACT_ERROR,
+                OPGetVrfRangeContractError(err).to_string(),
+            ))
+        }
+    };
 
-    let req = TEEReq::PromptReq(PromptReq {
+    Ok(PromptReq {
         request_id: quest.request_id.clone(),
         model_name: format!("./{}", quest.model),
         prompt: quest.prompt.clone(),
-        temperature: quest.params.temperature,
-        top_p: quest.params.top_p,
+        messages: quest.messages.clone(),
+        // otherwise the one of the model's metadata
+        chat_template: op.config.node.chat_templates.get(&quest.model).copied(),
+        sampling,
         n_predict: quest.params.max_tokens as usize,
-        vrf_threshold: threshold.unwrap(),
+        vrf_threshold: threshold,
         vrf_precision: op.config.chain.vrf_sort_precision as usize,
-        vrf_prompt_hash: quest.prompt_hash.clone(),
-    });
-
-    let result = op.tee_inference_sender.send(req);
-    if let Err(err) = result {
-        return make_resp_json(
-            quest.request_id.clone(),
-            ErrorCodes::OP_SEND_PROMPT_ERROR,
-            OPSendPromptError(err.to_string()).to_string(),
-            serde_json::Value::default(),
-        );
-    }
-    let json_data = json!({});
-    make_resp_json(quest.request_id.clone(), 0, String::new(), json_data)
+        vrf_selection: Some(selection),
+        vrf_prompt_hash: prompt_hash,
+        nonce: quest.nonce.clone(),
+    })
 }
@@ -1,6 +1,11 @@
 use tracing::*;
+use db_sql::pg::entities::answer_outbox::OutboxStatus;
 use db_sql::pg::pg_client::setup_db;
 use alloy_wrapper::util::generate_eth_account;
+use node_api::config::OperatorConfig;
+use std::sync::Arc;
+use crate::cli::operator::OutboxCommand;
+use crate::storage::Storage;
 
 pub async fn init_db(postgres_conn_str: String) -> bool {
     return if let Ok(url) = url::Url::parse(&postgres_conn_str) {
@@ -39,4 +44,44 @@ pub fn eth_account() -> bool {
             pri_hex, pub_key, addr
         );
     true
-}
\ No newline at end of file
+}
+
+pub async fn outbox(config: OperatorConfig, command: OutboxCommand) -> bool {
+    let storage = Storage::new(Arc::new(config)).await;
+    match command {
+        OutboxCommand::List { all, limit } => {
+            let status = (!all).then_some(OutboxStatus::Dead);
+            match storage.outbox_list(status, limit).await {
+                Ok(entries) => {
+                    for entry in entries {
+                        println!(
+                            "{} {} attempts: {} updated: {} error: {}",
+                            entry.request_id,
+                            entry.status,
+                            entry.attempts,
+                            entry.update_at,
+                            entry.last_error.unwrap_or_default()
+                        );
+                    }
+                    true
+                }
+                Err(err) => {
+                    error!("List answer_outbox error, err: {}", err);
+                    false
+                }
+            }
+        }
+        OutboxCommand::Replay { request_id } => {
+            match storage.outbox_replay(request_id.as_deref()).await {
+                Ok(count) => {
+                    println!("{} callbacks queued for replay", count);
+                    true
+                }
+                Err(err) => {
+                    error!("Replay answer_outbox error, err: {}", err);
+                    false
+                }
+            }
+        }
+    }
+}
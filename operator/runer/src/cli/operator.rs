@@ -6,7 +6,7 @@ use node_api::error::{ErrorCodes, OperatorConfigError};
 use structopt::StructOpt;
 use tracing::*;
 
-use crate::cli::command::{eth_account, init_db};
+use crate::cli::command::{eth_account, init_db, outbox};
 use std::path::PathBuf;
 
 #[derive(StructOpt)]
@@ -32,6 +32,32 @@ struct OperatorCli {
         help = "Gen a eth account, and keypair"
     )]
     eth_account: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Answer callbacks to the dispatcher, needs -c for the database
+    Outbox(OutboxCommand),
+}
+
+#[derive(StructOpt)]
+pub enum OutboxCommand {
+    /// List dead-lettered callbacks
+    List {
+        /// Callbacks of any status
+        #[structopt(long)]
+        all: bool,
+        #[structopt(long, default_value = "50")]
+        limit: u64,
+    },
+    /// Retry dead-lettered callbacks, by the operator running on the same database
+    Replay {
+        /// Only the callback of this request, all dead ones if absent
+        request_id: Option<String>,
+    },
 }
 
 pub async fn run_cli() {
@@ -58,6 +84,16 @@ pub async fn run_cli() {
         }
     }
 
+    // outbox maintenance, instead of running the node
+    if let Some(Command::Outbox(command)) = args.command {
+        let Some(config_path) = args.config_path else {
+            error!("outbox command needs the config, -c");
+            return;
+        };
+        outbox(construct_node_config(config_path), command).await;
+        return;
+    }
+
     // setup node
     if let Some(config_path) = args.config_path {
         help_info = false;
@@ -0,0 +1,263 @@
+use crate::vlc::{ClockedEvent, PeerEvent, VlcClock};
+use alloy::primitives::Address;
+use alloy_wrapper::util::{recover_signer_alloy, sign_message_with_chainid};
+use common::pcr_policy::PcrPolicy;
+use futures::future::join_all;
+use node_api::config::GossipConfig;
+use node_api::error::{OperatorError, OperatorResult};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tee_vlc::nitro_clock::ClockTrust;
+use tee_vlc::{Clocked, Verify as _};
+use tokio::net::lookup_host;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, error, info, warn};
+use websocket::{ReceiveMessage, WebsocketConfig, WebsocketReceiver, WebsocketSender};
+
+/// How long a dropped peer is waited for before it is dialed again.
+const REDIAL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Gossip of the clocked events of the node with its peers, for a causal order of the inference
+/// events across the cluster. Every tick of the local clock is signaled to every connected peer,
+/// dialed or accepted, and every clock received is verified and merged into the local clock in
+/// the VLC enclave. Events are not relayed, the peers are expected to be a full mesh.
+///
+/// Only the clock of an event is attested, the event is signed by the node it is of along with
+/// the clock, and accepted from the nodes of `peer_ids` only.
+pub struct Gossip {
+    vlc: Arc<VlcClock>,
+    /// Of the node, its events are signed with.
+    signer_key: [u8; 32],
+    peer_ids: Vec<Address>,
+    trust: RwLock<ClockTrust>,
+    peers: Mutex<HashMap<SocketAddr, WebsocketSender>>,
+}
+
+impl Gossip {
+    pub async fn start(
+        config: &GossipConfig,
+        vlc: Arc<VlcClock>,
+        signer_key: [u8; 32],
+    ) -> OperatorResult<Arc<Self>> {
+        let root_cert = match &config.root_cert {
+            Some(path) => std::fs::read(path).map_err(|err| {
+                OperatorError::OPGossipError(format!("root cert {}, {err}", path.display()))
+            })?,
+            None => verifier::default_root_cert()
+                .ok_or(OperatorError::OPGossipError(
+                    "no root cert to verify the clocks of the peers".into(),
+                ))?
+                .to_vec(),
+        };
+        let policy = match &config.pcr_policy {
+            Some(path) => Some(
+                PcrPolicy::load(path)
+                    .map_err(|err| OperatorError::OPGossipError(err.to_string()))?,
+            ),
+            None => {
+                info!("no gossip pcr policy, peer clocks rejected until the own one is attested");
+                None
+            }
+        };
+        // validated with the config
+        let peer_ids: Vec<Address> = config
+            .peer_ids
+            .iter()
+            .filter_map(|peer| peer.parse().ok())
+            .collect();
+        if peer_ids.is_empty() {
+            warn!("no gossip peer ids configured, the events of all peers are dropped");
+        }
+        let gossip = Arc::new(Self {
+            vlc,
+            signer_key,
+            peer_ids,
+            trust: RwLock::new(ClockTrust { root_cert, policy }),
+            peers: Default::default(),
+        });
+        let ws_config = Arc::new(WebsocketConfig::default());
+
+        if let Some(addr) = &config.listen_addr {
+            let listener = websocket::WebsocketListener::bind(ws_config.clone(), addr.as_str())
+                .await
+                .map_err(|err| OperatorError::OPGossipError(format!("bind {addr}, {err}")))?;
+            let gossip = gossip.clone();
+            tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((sender, receiver)) => {
+                            tokio::spawn(gossip.clone().serve(sender, receiver));
+                        }
+                        Err(err) => warn!("accept gossip peer failed, {err}"),
+                    }
+                }
+            });
+        }
+        for peer in config.peers.clone() {
+            tokio::spawn(gossip.clone().dial(peer, ws_config.clone()));
+        }
+        tokio::spawn(gossip.clone().broadcast());
+        info!("gossip with {} peers started", config.peers.len());
+        Ok(gossip)
+    }
+
+    async fn dial(self: Arc<Self>, peer: String, config: Arc<WebsocketConfig>) {
+        loop {
+            let connected = async {
+                let addr = lookup_host(peer.as_str())
+                    .await?
+                    .next()
+                    .ok_or(std::io::Error::other("no address"))?;
+                websocket::connect(config.clone(), addr).await
+            };
+            match connected.await {
+                Ok((sender, receiver)) => self.clone().serve(sender, receiver).await,
+                Err(err) => debug!("dial gossip peer {peer} failed, {err}"),
+            }
+            tokio::time::sleep(REDIAL_INTERVAL).await;
+        }
+    }
+
+    /// Receives from a peer until it is gone.
+    async fn serve(self: Arc<Self>, sender: WebsocketSender, mut receiver: WebsocketReceiver) {
+        let addr = receiver.peer_addr();
+        info!("gossip peer {addr} connected");
+        self.peers.lock().unwrap().insert(addr, sender);
+        loop {
+            match receiver.recv().await {
+                Ok(ReceiveMessage::Signal(data)) => self.receive(addr, data).await,
+                // not asked by peers
+                Ok(ReceiveMessage::Request(..)) => {}
+                Err(err) => {
+                    info!("gossip peer {addr} gone, {err}");
+                    break;
+                }
+            }
+        }
+        self.peers.lock().unwrap().remove(&addr);
+    }
+
+    async fn receive(&self, addr: SocketAddr, data: Vec<u8>) {
+        let clocked = match serde_json::from_slice::<ClockedEvent>(&data) {
+            Ok(clocked) => clocked,
+            Err(err) => {
+                warn!("malformed gossip from {addr}, {err}");
+                return;
+            }
+        };
+        if let Err(err) = self.authenticate(&clocked) {
+            warn!(
+                "event {} of {} from {} rejected, {}",
+                clocked.inner.message_id, clocked.inner.node_id, addr, err
+            );
+            return;
+        }
+        if self.trust.read().unwrap().policy.is_none() {
+            self.trust_own_image().await
+        }
+        let verified = clocked.verify_clock(0, &self.trust.read().unwrap());
+        if let Err(err) = verified {
+            warn!(
+                "clock of {} from {} rejected, {}",
+                clocked.inner.message_id, clocked.inner.node_id, err
+            );
+            return;
+        }
+        self.vlc.merge(&clocked, data).await;
+    }
+
+    /// Checks that an event is of one of `peer_ids`, and signed by it along with its clock.
+    fn authenticate(&self, clocked: &ClockedEvent) -> Result<(), String> {
+        let node_id = clocked
+            .inner
+            .node_id
+            .parse::<Address>()
+            .map_err(|err| err.to_string())?;
+        if !self.peer_ids.contains(&node_id) {
+            return Err("not a peer".into());
+        }
+        let message = clocked.inner.signed_message(&clocked.clock.plain);
+        let signer = recover_signer_alloy(clocked.inner.signature.clone(), &message)
+            .map_err(|err| err.to_string())?;
+        if signer != node_id {
+            return Err(format!("signed by {signer}"));
+        }
+        Ok(())
+    }
+
+    /// The event of the node signed, see `PeerEvent::signed_message`.
+    fn sign(&self, clocked: &ClockedEvent) -> Result<ClockedEvent, String> {
+        let message = clocked.inner.signed_message(&clocked.clock.plain);
+        // the chain id does not enter a personal message signature
+        let (signature, _) = sign_message_with_chainid(self.signer_key, &message, 1)
+            .map_err(|err| err.to_string())?;
+        Ok(Clocked {
+            clock: clocked.clock.clone(),
+            inner: PeerEvent {
+                signature: hex::encode(signature.as_bytes()),
+                ..clocked.inner.clone()
+            },
+        })
+    }
+
+    /// Trusts the enclave image of the local VLC enclave, as the enclave does by default, once it
+    /// attested a clock.
+    async fn trust_own_image(&self) {
+        let own = self.vlc.current().await;
+        let mut trust = self.trust.write().unwrap();
+        match own.verify_with(&trust.root_cert) {
+            Ok(Some(document)) => {
+                let pcrs = [0, 1, 2].map(|index| {
+                    document
+                        .pcrs
+                        .get(&index)
+                        .map(|value| value.to_vec())
+                        .unwrap_or_default()
+                });
+                trust.policy = Some(PcrPolicy::exact(&pcrs));
+            }
+            Ok(None) => {}
+            Err(err) => error!("own clock does not verify, {err}"),
+        }
+    }
+
+    /// Signals every tick of the local clock to the connected peers.
+    async fn broadcast(self: Arc<Self>) {
+        let mut ticks = self.vlc.subscribe();
+        loop {
+            let clocked = match ticks.recv().await {
+                Ok(clocked) => clocked,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("{skipped} ticks not gossiped, peers too slow");
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let signed = match self.sign(&clocked) {
+                Ok(signed) => signed,
+                Err(err) => {
+                    error!("sign gossip failed, {err}");
+                    continue;
+                }
+            };
+            let data = match serde_json::to_vec(&signed) {
+                Ok(data) => data,
+                Err(err) => {
+                    error!("serialize gossip failed, {err}");
+                    continue;
+                }
+            };
+            let peers = self.peers.lock().unwrap().clone();
+            let data = &data;
+            // a failed peer is closed, and removed once its receiver notices
+            join_all(peers.into_iter().map(|(addr, sender)| async move {
+                if let Err(err) = sender.signal(data.clone()).await {
+                    warn!("gossip to {addr} failed, {err}")
+                }
+            }))
+            .await;
+        }
+    }
+}
@@ -1,5 +1,8 @@
-use crate::api::read::{index, status};
-use crate::api::write::question;
+use crate::api::openai::{chat_completions, completions, models};
+use crate::api::read::{
+    answer, answers, clock_history, clock_order, index, status, vrf_key, vrf_verify,
+};
+use crate::api::write::{question, question_stream};
 use actix_web::web;
 
 // static MESSAGE_COUNT: AtomicUsize = AtomicUsize::new(0);
@@ -7,5 +10,15 @@ use actix_web::web;
 pub fn router(cfg: &mut web::ServiceConfig) {
     cfg.service(index);
     cfg.service(status);
+    cfg.service(answer);
+    cfg.service(answers);
+    cfg.service(vrf_key);
+    cfg.service(vrf_verify);
+    cfg.service(clock_order);
+    cfg.service(clock_history);
     cfg.service(question);
+    cfg.service(question_stream);
+    cfg.service(models);
+    cfg.service(chat_completions);
+    cfg.service(completions);
 }
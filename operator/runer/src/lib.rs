@@ -3,4 +3,8 @@ pub mod storage;
 pub mod node_factory;
 pub mod handler;
 pub mod api;
-pub mod cli;
\ No newline at end of file
+pub mod cli;
+pub mod tee_queue;
+pub mod outbox;
+pub mod vlc;
+pub mod gossip;
\ No newline at end of file
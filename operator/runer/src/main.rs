@@ -4,6 +4,10 @@ mod operator;
 mod storage;
 mod api;
 mod cli;
+mod tee_queue;
+mod outbox;
+mod vlc;
+mod gossip;
 
 use cli::operator::run_cli;
 use tools::tokio_static;
@@ -1,12 +1,17 @@
 use crate::api::read::not_found;
 use crate::api::request::{listening_tee_resp_task, periodic_heartbeat_task, register_worker};
+use crate::gossip::Gossip;
 use crate::handler::router;
 use crate::operator::{Operator, OperatorArc, ServerState};
-use crate::storage;
+use crate::outbox::Outbox;
+use crate::storage::Storage;
+use crate::tee_queue::TeeQueue;
+use crate::vlc::VlcClock;
 use actix_web::{middleware, web, App, HttpServer};
 use alloy_primitives::hex::FromHex;
 use alloy_primitives::B256;
 use alloy_wrapper::contracts::vrf_range::new_vrf_range_backend;
+use common::transport;
 use node_api::config::OperatorConfig;
 use node_api::error::OperatorError;
 use node_api::error::{
@@ -14,10 +19,11 @@ use node_api::error::{
     OperatorResult,
 };
 use std::sync::Arc;
-use tee_llm::nitro_llm::{tee_start_listening, try_connection, AnswerResp, TEEReq, TEEResp};
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use std::time::Duration;
+use tee_llm::nitro_llm::{AnswerResp, TEEReq, TEEResp};
+use tokio::sync::mpsc::unbounded_channel;
 use tokio::sync::RwLock;
-use tracing::info;
+use tracing::{info, warn};
 
 #[derive(Default)]
 pub struct OperatorFactory {
@@ -36,7 +42,9 @@ impl OperatorFactory {
 
     pub async fn create_operator(
         config: OperatorConfig,
-        tee_inference_sender: UnboundedSender<TEEReq>,
+        tee_queue: Arc<TeeQueue>,
+        vlc: Option<Arc<VlcClock>>,
+        storage: Storage,
     ) -> OperatorResult<OperatorArc> {
         let cfg = Arc::new(config.clone());
         let node_id = config.node.node_id.clone();
@@ -50,13 +58,13 @@ impl OperatorFactory {
 
         let server_state = ServerState::new(signer_key, node_id, cfg.node.cache_msg_maximum);
         let state = RwLock::new(server_state);
-        let storage = storage::Storage::new(cfg.clone()).await;
         let operator = Operator {
             config: cfg,
             storage,
             state,
-            tee_inference_sender,
+            tee_queue,
             vrf_range_contract,
+            vlc,
         };
 
         Ok(Arc::new(operator))
@@ -81,27 +89,72 @@ impl OperatorFactory {
             .expect("Failed to run server");
     }
 
-    async fn prepare_setup(config: &OperatorConfig) -> OperatorResult<UnboundedSender<TEEReq>> {
+    async fn prepare_setup(
+        config: &OperatorConfig,
+        storage: Storage,
+    ) -> OperatorResult<(Arc<TeeQueue>, Option<Arc<VlcClock>>)> {
         // detect and connect tee enclave service, if not, and exit
         let (prompt_sender, prompt_receiver) = unbounded_channel::<TEEReq>();
         let (answer_ok_sender, answer_ok_receiver) = unbounded_channel::<TEEResp>();
 
-        let (tee_cid, tee_port) = (config.net.tee_llm_cid, config.net.tee_llm_port);
-        let result = try_connection(tee_cid, tee_port);
+        let tee_addr = config.net.tee_llm_addr();
+        let result = async { transport::connect(&tee_addr.parse()?).await }.await;
         if let Err(err) = result {
             return Err(OperatorError::OPConnectTEEError(err.to_string()));
         } else {
-            info!("connect llm tee service successed!");
+            info!("connect llm tee service at {tee_addr} successed!");
         }
 
-        tokio::spawn(tee_start_listening(
+        tokio::spawn(transport::session(
             result.unwrap(),
             prompt_receiver,
             answer_ok_sender,
         ));
 
+        let tee_queue = Arc::new(TeeQueue::new(
+            prompt_sender,
+            config.node.queue_depth,
+            Duration::from_secs(config.node.answer_timeout),
+        ));
+
+        // load the served models into the enclave ahead of the first prompt
+        for model in &config.node.ai_models {
+            tee_queue
+                .send(TEEReq::PreloadModel(format!("./{}", model)))
+                .map_err(|err| OperatorError::OPSendPromptError(format!("{err:?}")))?;
+        }
+        // the key answers are proven with, attested for the dispatcher to pin
+        tee_queue
+            .send(TEEReq::VrfKey)
+            .map_err(|err| OperatorError::OPSendPromptError(format!("{err:?}")))?;
+
+        // the clock questions and answers are ticked in
+        let vlc = match &config.net.tee_vlc_addr {
+            Some(addr) => {
+                let node_id = config.node.node_id.clone();
+                Some(Arc::new(
+                    VlcClock::connect(addr, node_id, storage.clone()).await?,
+                ))
+            }
+            None => {
+                warn!("no vlc tee service configured, answers are not clocked");
+                None
+            }
+        };
+        // and gossiped with the peers
+        if config.net.gossip.enabled() {
+            match &vlc {
+                Some(vlc) => {
+                    let signer_key = B256::from_hex(config.node.signer_key.clone())
+                        .map_err(OPDecodeSignerKeyError)?;
+                    Gossip::start(&config.net.gossip, vlc.clone(), signer_key.0).await?;
+                }
+                None => warn!("gossip needs a vlc tee service, not started"),
+            }
+        }
+
         // register status to dispatcher service
-        let response = register_worker(config)
+        let response = register_worker(config, 0)
             .await
             .map_err(OperatorError::OPSetupRegister)?;
 
@@ -119,20 +172,31 @@ impl OperatorFactory {
 
         // periodic heartbeat task
         let config_clone = config.clone();
-        tokio::spawn(periodic_heartbeat_task(config_clone));
-
-        // answer callback
-        let config_clone = config.clone();
-        tokio::spawn(listening_tee_resp_task(config_clone, answer_ok_receiver));
+        tokio::spawn(periodic_heartbeat_task(config_clone, tee_queue.clone()));
+
+        // answer callback, through the outbox
+        let outbox = Outbox::new(config.clone(), storage.clone())
+            .map_err(|err| OperatorError::CustomError(err.to_string()))?;
+        let outbox = Arc::new(outbox);
+        tokio::spawn(outbox.clone().run());
+        tokio::spawn(listening_tee_resp_task(
+            answer_ok_receiver,
+            tee_queue.clone(),
+            vlc.clone(),
+            storage,
+            outbox,
+        ));
 
-        Ok(prompt_sender)
+        Ok((tee_queue, vlc))
     }
 
     pub async fn initialize_node(self) -> OperatorResult<OperatorArc> {
-        let prompt_sender = OperatorFactory::prepare_setup(&self.config).await?;
+        let storage = Storage::new(Arc::new(self.config.clone())).await;
+        let (tee_queue, vlc) =
+            OperatorFactory::prepare_setup(&self.config, storage.clone()).await?;
 
         let arc_operator =
-            OperatorFactory::create_operator(self.config.clone(), prompt_sender).await?;
+            OperatorFactory::create_operator(self.config.clone(), tee_queue, vlc, storage).await?;
 
         OperatorFactory::create_actix_node(arc_operator.clone()).await;
 
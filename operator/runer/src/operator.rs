@@ -1,20 +1,19 @@
-use crate::{node_factory::OperatorFactory, storage::Storage};
+use crate::{node_factory::OperatorFactory, storage::Storage, tee_queue::TeeQueue, vlc::VlcClock};
 use alloy_primitives::B256;
 use alloy_wrapper::contracts::vrf_range::OperatorRangeContract;
 use node_api::config::OperatorConfig;
-use tee_llm::nitro_llm::{AnswerResp, TEEReq};
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use std::collections::{BTreeMap, VecDeque};
-use std::{cmp, sync::Arc};
+use std::collections::VecDeque;
+use std::sync::Arc;
 use tokio::sync::RwLock;
-use tracing::*;
 
 pub struct Operator {
     pub config: Arc<OperatorConfig>,
     pub storage: Storage,
     pub state: RwLock<ServerState>,
-    pub tee_inference_sender: UnboundedSender<TEEReq>, 
+    pub tee_queue: Arc<TeeQueue>,
     pub vrf_range_contract: OperatorRangeContract,
+    /// `None` unless `net.tee_vlc_addr` is configured.
+    pub vlc: Option<Arc<VlcClock>>,
 }
 
 pub type OperatorArc = Arc<Operator>;
@@ -23,16 +22,11 @@ impl Operator {
     pub fn operator_factory() -> OperatorFactory {
         OperatorFactory::init()
     }
-
-    pub fn update_tee_sender(mut self, sender: UnboundedSender<TEEReq>) {
-        self.tee_inference_sender = sender;
-    }
 }
 
 /// A cache state of a server node.
 #[derive(Debug, Clone)]
 pub struct ServerState {
-    // pub clock_info: ClockInfo,
     pub signer_key: B256,
     pub message_ids: VecDeque<String>,
     pub cache_maximum: u64,
@@ -47,4 +41,20 @@ impl ServerState {
             cache_maximum,
         }
     }
+
+    /// Whether a request id or nonce is among the latest `cache_maximum` ones.
+    pub fn is_known(&self, message_id: &str) -> bool {
+        self.message_ids.iter().any(|id| id == message_id)
+    }
+
+    pub fn remember(&mut self, message_id: String) {
+        if self.message_ids.len() as u64 >= self.cache_maximum {
+            self.message_ids.pop_front();
+        }
+        self.message_ids.push_back(message_id);
+    }
+
+    pub fn forget(&mut self, message_id: &str) {
+        self.message_ids.retain(|id| id != message_id);
+    }
 }
@@ -0,0 +1,180 @@
+use crate::api::request::{answer_callback, make_answer_callback_req, ClockCredential};
+use crate::storage::Storage;
+use chrono::Local;
+use db_sql::pg::entities::answer_outbox::{self, OutboxStatus};
+use db_sql::pg::entities::inference_jobs::JobStatus;
+use futures::StreamExt;
+use node_api::config::OperatorConfig;
+use reqwest::Client as ReqwestClient;
+use std::sync::Arc;
+use tee_llm::nitro_llm::AnswerResp;
+use tokio::sync::Notify;
+use tokio::time::{sleep, Duration};
+use tracing::{debug, error, warn};
+
+/// Most due callbacks attempted per round.
+const BATCH: u64 = 64;
+/// How often due callbacks are looked for, besides right after a push.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Most callbacks in flight at once.
+const CONCURRENCY: usize = 8;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Of a whole callback, a dispatcher that hangs is retried like one that fails.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Answer callbacks to the dispatcher.
+///
+/// Every answer is stored in `answer_outbox` before delivery, then retried with exponential
+/// backoff until the dispatcher acknowledges it, or dead-lettered after
+/// `callback_retry.max_attempts` attempts. Stored in Postgres, pending callbacks are taken up
+/// again after a restart, and dead ones can be replayed from the CLI.
+pub struct Outbox {
+    config: OperatorConfig,
+    storage: Storage,
+    client: ReqwestClient,
+    pushed: Notify,
+}
+
+impl Outbox {
+    pub fn new(config: OperatorConfig, storage: Storage) -> reqwest::Result<Self> {
+        let client = ReqwestClient::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        Ok(Self {
+            config,
+            storage,
+            client,
+            pushed: Notify::new(),
+        })
+    }
+
+    /// `transcript_hash` and `clock` as in `make_answer_callback_req`.
+    pub async fn push(
+        &self,
+        answer: &AnswerResp,
+        transcript_hash: &str,
+        clock: Option<ClockCredential>,
+    ) {
+        let req = make_answer_callback_req(&self.config, answer, transcript_hash, clock);
+        let body = match serde_json::to_string(&req) {
+            Ok(body) => body,
+            Err(err) => {
+                error!("Serialize answer callback error, err: {}", err);
+                return;
+            }
+        };
+        if self
+            .storage
+            .outbox_push(&answer.request_id, body.clone())
+            .await
+        {
+            self.pushed.notify_one();
+            return;
+        }
+        // not stored, so the one attempt it gets is now
+        if let Err(err) = self.deliver(body).await {
+            error!("answer callback of {} lost, {}", answer.request_id, err);
+        }
+    }
+
+    /// Delivers due callbacks, `CONCURRENCY` at a time, forever.
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            match self.storage.outbox_due(BATCH).await {
+                Ok(due) => {
+                    futures::stream::iter(due)
+                        .for_each_concurrent(CONCURRENCY, |entry| self.attempt(entry))
+                        .await
+                }
+                Err(err) => error!("Query answer_outbox error, err: {}", err),
+            }
+            tokio::select! {
+                _ = self.pushed.notified() => {}
+                _ = sleep(POLL_INTERVAL) => {}
+            }
+        }
+    }
+
+    async fn attempt(&self, entry: answer_outbox::Model) {
+        let now = Local::now().naive_local();
+        let attempts = entry.attempts.saturating_add(1);
+        let err = match self.deliver(entry.body).await {
+            Ok(()) => {
+                self.storage
+                    .outbox_attempted(entry.id, OutboxStatus::Delivered, attempts, now, None)
+                    .await;
+                self.storage
+                    .job_status(&entry.request_id, JobStatus::Delivered, None)
+                    .await;
+                return;
+            }
+            Err(err) => err,
+        };
+
+        let retry = &self.config.node.callback_retry;
+        if attempts as u32 >= retry.max_attempts {
+            warn!(
+                "answer callback of {} dead-lettered after {} attempts, {}",
+                entry.request_id, attempts, err
+            );
+            self.storage
+                .outbox_attempted(
+                    entry.id,
+                    OutboxStatus::Dead,
+                    attempts,
+                    now,
+                    Some(err.clone()),
+                )
+                .await;
+            self.storage
+                .job_status(&entry.request_id, JobStatus::CallbackFailed, Some(err))
+                .await;
+        } else {
+            let backoff = retry.backoff(attempts as u32);
+            debug!(
+                "answer callback of {} failed, retry in {}s, {}",
+                entry.request_id, backoff, err
+            );
+            let next_attempt_at = now + chrono::Duration::seconds(backoff as i64);
+            self.storage
+                .outbox_attempted(
+                    entry.id,
+                    OutboxStatus::Pending,
+                    attempts,
+                    next_attempt_at,
+                    Some(err),
+                )
+                .await;
+        }
+    }
+
+    /// Acknowledged by the dispatcher means a success status.
+    async fn deliver(&self, body: String) -> Result<(), String> {
+        let response = answer_callback(&self.client, &self.config, body)
+            .await
+            .map_err(|err| err.to_string())?;
+        let status = response.status();
+        match response.text().await {
+            Ok(body) => debug!("Response body: {}", body),
+            Err(err) => error!("Failed to read response body, {}", err),
+        }
+        if !status.is_success() {
+            return Err(format!("dispatcher responded {}", status));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use node_api::config::CallbackRetry;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let retry = CallbackRetry::default();
+        let backoffs: Vec<_> = (1..=10).map(|attempts| retry.backoff(attempts)).collect();
+        assert_eq!(backoffs, [2, 4, 8, 16, 32, 64, 128, 256, 512, 600]);
+        assert_eq!(retry.backoff(u32::MAX), 600);
+    }
+}
@@ -1,11 +1,21 @@
 use std::{sync::Arc, time::Duration};
 use chrono::{Local, NaiveDateTime};
+use common::ordinary_clock::Clock as _;
 use node_api::config::OperatorConfig;
 use db_sql::pg::entities::{clock_infos, prelude::ClockInfos};
+use db_sql::pg::entities::inference_jobs::{self, JobStatus};
+use db_sql::pg::entities::answer_outbox::{self, OutboxStatus};
+use db_sql::pg::entities::prelude::{AnswerOutbox, InferenceJobs};
 use sea_orm::*;
+use sea_orm::sea_query::{Expr, Func, SimpleExpr};
+use serde_json::json;
+use tee_llm::nitro_llm::{AnswerResp, PromptReq};
+use tee_llm::sampling::SamplingParams;
 use tracing::{error, info};
+use vrf::sortition::Selection;
+use crate::vlc::{clock_hash, ClockCredential};
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct Storage {
     pub pg_db: Arc<DatabaseConnection>
 }
@@ -30,18 +40,86 @@ impl Storage {
     }
     
     // postgre inner api
-    pub async fn sinker_clock(&self, message_id: String, raw_message: Vec<u8>) {
+    /// Records a tick of the clock of `node_id`, for `message_id`.
+    pub async fn sinker_clock(
+        &self,
+        node_id: &str,
+        message_id: &str,
+        raw_message: Vec<u8>,
+        clock: &ClockCredential,
+    ) {
         let clock_info = clock_infos::ActiveModel {
-            message_id: ActiveValue::Set(message_id),
+            clock: ActiveValue::Set(serde_json::to_string(clock).unwrap_or_default()),
+            clock_hash: ActiveValue::Set(clock_hash(&clock.clock)),
+            node_id: ActiveValue::Set(node_id.to_owned()),
+            message_id: ActiveValue::Set(message_id.to_owned()),
             raw_message: ActiveValue::Set(raw_message),
+            event_count: ActiveValue::Set(clock.clock.reduce() as i64),
+            create_at: ActiveValue::Set(Some(Local::now().naive_local())),
+            plain_clock: ActiveValue::Set(serde_json::to_string(&clock.clock).ok()),
             ..Default::default()
         };
         let res = ClockInfos::insert(clock_info).exec(self.pg_db.as_ref()).await;
         if let Err(err) = res {
-            error!("Insert clock_info error, err: {}", err);
+            error!("Insert clock_info error, message_id: {}, err: {}", message_id, err);
         }
     }
 
+    /// The latest clock of `node_id`.
+    pub async fn last_clock(&self, node_id: &str) -> Result<Option<clock_infos::Model>, DbErr> {
+        ClockInfos::find()
+            .filter(clock_infos::Column::NodeId.eq(node_id))
+            .order_by_desc(clock_infos::Column::Id)
+            .one(self.pg_db.as_ref())
+            .await
+    }
+
+
+    /// The first clock recorded for `message_id`, of its question or of its receipt from a peer.
+    pub async fn clock_of(&self, message_id: &str) -> Result<Option<clock_infos::Model>, DbErr> {
+        ClockInfos::find()
+            .filter(clock_infos::Column::MessageId.eq(message_id))
+            .order_by_asc(clock_infos::Column::Id)
+            .one(self.pg_db.as_ref())
+            .await
+    }
+
+    /// The ids and the JSON of the clocks of at most `limit` records past `after` of at most
+    /// `event_count` events, the only ones a clock of that many can dominate, in the order
+    /// recorded. The JSON is the one of `plain_clock`, without the attestation, or of the whole
+    /// `ClockCredential` for records from before it.
+    pub async fn clocks_within(
+        &self,
+        event_count: i64,
+        after: i64,
+        limit: u64,
+    ) -> Result<Vec<(i64, String)>, DbErr> {
+        let clock: SimpleExpr = Func::if_null(
+            Expr::col(clock_infos::Column::PlainClock),
+            Expr::col(clock_infos::Column::Clock),
+        )
+        .into();
+        ClockInfos::find()
+            .select_only()
+            .column(clock_infos::Column::Id)
+            .column_as(clock, "clock")
+            .filter(clock_infos::Column::EventCount.lte(event_count))
+            .filter(clock_infos::Column::Id.gt(after))
+            .order_by_asc(clock_infos::Column::Id)
+            .limit(limit)
+            .into_tuple()
+            .all(self.pg_db.as_ref())
+            .await
+    }
+
+    /// The records of `ids`, in the order recorded.
+    pub async fn clocks_of(&self, ids: &[i64]) -> Result<Vec<clock_infos::Model>, DbErr> {
+        ClockInfos::find()
+            .filter(clock_infos::Column::Id.is_in(ids.iter().copied()))
+            .order_by_asc(clock_infos::Column::Id)
+            .all(self.pg_db.as_ref())
+            .await
+    }
 
     pub async fn get_clocks_counts(&self) -> Result<u64, DbErr> {
         let clocks_count= ClockInfos::find()
@@ -58,4 +136,275 @@ impl Storage {
             }
         }
     }
-}
\ No newline at end of file
+
+    /// Records a job as received, over a job of the same request id the queue refused. False if
+    /// it could not be, any other job of the same request id included.
+    pub async fn job_received(&self, req: &PromptReq) -> bool {
+        let now = Local::now().naive_local();
+        let model = req.model_name.trim_start_matches("./").to_owned();
+        let params = job_params(&req.sampling, req.n_predict, &req.nonce);
+        let selection = req
+            .selection()
+            .ok()
+            .and_then(|selection| serde_json::to_string(&selection).ok());
+        let retried = InferenceJobs::update_many()
+            .col_expr(inference_jobs::Column::Model, Expr::value(model.clone()))
+            .col_expr(inference_jobs::Column::PromptHash, Expr::value(req.vrf_prompt_hash.clone()))
+            .col_expr(inference_jobs::Column::Params, Expr::value(params.clone()))
+            .col_expr(inference_jobs::Column::Selection, Expr::value(selection.clone()))
+            .col_expr(inference_jobs::Column::Status, Expr::value(JobStatus::Received.as_str()))
+            .col_expr(inference_jobs::Column::Error, Expr::value(None::<String>))
+            .col_expr(inference_jobs::Column::UpdateAt, Expr::value(now))
+            .filter(inference_jobs::Column::RequestId.eq(&req.request_id))
+            .filter(job_status_in(&[JobStatus::Rejected]))
+            .exec(self.pg_db.as_ref())
+            .await;
+        match retried {
+            Ok(res) if res.rows_affected > 0 => return true,
+            Ok(_) => {}
+            Err(err) => {
+                error!("Update inference_job error, request_id: {}, err: {}", req.request_id, err);
+                return false;
+            }
+        }
+        let job = inference_jobs::ActiveModel {
+            request_id: ActiveValue::Set(req.request_id.clone()),
+            model: ActiveValue::Set(model),
+            prompt_hash: ActiveValue::Set(req.vrf_prompt_hash.clone()),
+            params: ActiveValue::Set(params),
+            selection: ActiveValue::Set(selection),
+            status: ActiveValue::Set(JobStatus::Received.as_str().to_owned()),
+            create_at: ActiveValue::Set(now),
+            update_at: ActiveValue::Set(now),
+            ..Default::default()
+        };
+        let res = InferenceJobs::insert(job).exec(self.pg_db.as_ref()).await;
+        if let Err(err) = res {
+            error!("Insert inference_job error, request_id: {}, err: {}", req.request_id, err);
+            return false;
+        }
+        true
+    }
+
+    /// Whether a job of `request_id` was ever queued.
+    pub async fn job_exists(&self, request_id: &str) -> Result<bool, DbErr> {
+        let count = InferenceJobs::find()
+            .filter(inference_jobs::Column::RequestId.eq(request_id))
+            .filter(inference_jobs::Column::Status.ne(JobStatus::Rejected.as_str()))
+            .count(self.pg_db.as_ref())
+            .await
+            .inspect_err(|err| {
+                error!("Query inference_job error, request_id: {}, err: {}", request_id, err)
+            })?;
+        Ok(count > 0)
+    }
+
+    /// The selection the prompt of a job was sent with, None for a job without one.
+    pub async fn job_selection(&self, request_id: &str) -> Result<Option<Selection>, DbErr> {
+        let selection: Option<Option<String>> = InferenceJobs::find()
+            .select_only()
+            .column(inference_jobs::Column::Selection)
+            .filter(inference_jobs::Column::RequestId.eq(request_id))
+            .into_tuple()
+            .one(self.pg_db.as_ref())
+            .await?;
+        selection
+            .flatten()
+            .map(|selection| serde_json::from_str(&selection))
+            .transpose()
+            .map_err(|err| DbErr::Json(err.to_string()))
+    }
+
+    /// Moves a job to `status`, unless it is past that already.
+    pub async fn job_status(&self, request_id: &str, status: JobStatus, error: Option<String>) {
+        let res = InferenceJobs::update_many()
+            .col_expr(inference_jobs::Column::Status, Expr::value(status.as_str()))
+            .col_expr(inference_jobs::Column::Error, Expr::value(error))
+            .col_expr(inference_jobs::Column::UpdateAt, Expr::value(Local::now().naive_local()))
+            .filter(inference_jobs::Column::RequestId.eq(request_id))
+            .filter(job_status_in(status.preceding()))
+            .exec(self.pg_db.as_ref())
+            .await;
+        if let Err(err) = res {
+            error!("Update inference_job status error, request_id: {}, err: {}", request_id, err);
+        }
+    }
+
+    /// Records the answer of the enclave, with the seed in use.
+    pub async fn job_answered(&self, answer: &AnswerResp) {
+        let now = Local::now().naive_local();
+        let vrf_proof = json!({
+            "vrf_prompt_hash": answer.vrf_prompt_hash,
+            "vrf_random_value": answer.vrf_random_value,
+            "vrf_verify_pubkey": answer.vrf_verify_pubkey,
+            "vrf_proof": answer.vrf_proof,
+        });
+        let params = job_params(&answer.sampling, answer.n_predict, &answer.nonce);
+        let res = InferenceJobs::update_many()
+            .col_expr(inference_jobs::Column::Status, Expr::value(JobStatus::Answered.as_str()))
+            .col_expr(inference_jobs::Column::Params, Expr::value(params))
+            .col_expr(inference_jobs::Column::Selected, Expr::value(answer.selected))
+            .col_expr(inference_jobs::Column::Answer, Expr::value(answer.answer.clone()))
+            .col_expr(inference_jobs::Column::Attestation, Expr::value(answer.document.0.clone()))
+            .col_expr(inference_jobs::Column::VrfProof, Expr::value(vrf_proof.to_string()))
+            .col_expr(inference_jobs::Column::AnswerAt, Expr::value(now))
+            .col_expr(inference_jobs::Column::UpdateAt, Expr::value(now))
+            .filter(inference_jobs::Column::RequestId.eq(&answer.request_id))
+            .filter(job_status_in(JobStatus::Answered.preceding()))
+            .exec(self.pg_db.as_ref())
+            .await;
+        if let Err(err) = res {
+            error!("Update inference_job answer error, request_id: {}, err: {}", answer.request_id, err);
+        }
+    }
+
+    /// Stores an answer callback for delivery, false if it could not be.
+    pub async fn outbox_push(&self, request_id: &str, body: String) -> bool {
+        let now = Local::now().naive_local();
+        let entry = answer_outbox::ActiveModel {
+            request_id: ActiveValue::Set(request_id.to_owned()),
+            body: ActiveValue::Set(body),
+            status: ActiveValue::Set(OutboxStatus::Pending.as_str().to_owned()),
+            attempts: ActiveValue::Set(0),
+            next_attempt_at: ActiveValue::Set(now),
+            create_at: ActiveValue::Set(now),
+            update_at: ActiveValue::Set(now),
+            ..Default::default()
+        };
+        let res = AnswerOutbox::insert(entry).exec(self.pg_db.as_ref()).await;
+        if let Err(err) = res {
+            error!("Insert answer_outbox error, request_id: {}, err: {}", request_id, err);
+            return false;
+        }
+        true
+    }
+
+    /// Pending callbacks due by now, the oldest first.
+    pub async fn outbox_due(&self, limit: u64) -> Result<Vec<answer_outbox::Model>, DbErr> {
+        AnswerOutbox::find()
+            .filter(answer_outbox::Column::Status.eq(OutboxStatus::Pending.as_str()))
+            .filter(answer_outbox::Column::NextAttemptAt.lte(Local::now().naive_local()))
+            .order_by_asc(answer_outbox::Column::Id)
+            .limit(limit)
+            .all(self.pg_db.as_ref())
+            .await
+    }
+
+    /// Records an attempt at delivering a callback: `Delivered`, `Pending` again at
+    /// `next_attempt_at`, or `Dead`.
+    pub async fn outbox_attempted(
+        &self,
+        id: i64,
+        status: OutboxStatus,
+        attempts: i32,
+        next_attempt_at: NaiveDateTime,
+        error: Option<String>,
+    ) {
+        let res = AnswerOutbox::update_many()
+            .col_expr(answer_outbox::Column::Status, Expr::value(status.as_str()))
+            .col_expr(answer_outbox::Column::Attempts, Expr::value(attempts))
+            .col_expr(answer_outbox::Column::NextAttemptAt, Expr::value(next_attempt_at))
+            .col_expr(answer_outbox::Column::LastError, Expr::value(error))
+            .col_expr(answer_outbox::Column::UpdateAt, Expr::value(Local::now().naive_local()))
+            .filter(answer_outbox::Column::Id.eq(id))
+            .exec(self.pg_db.as_ref())
+            .await;
+        if let Err(err) = res {
+            error!("Update answer_outbox error, id: {}, err: {}", id, err);
+        }
+    }
+
+    /// Callbacks of `status`, or of any, the latest first.
+    pub async fn outbox_list(
+        &self,
+        status: Option<OutboxStatus>,
+        limit: u64,
+    ) -> Result<Vec<answer_outbox::Model>, DbErr> {
+        let mut query = AnswerOutbox::find();
+        if let Some(status) = status {
+            query = query.filter(answer_outbox::Column::Status.eq(status.as_str()));
+        }
+        query
+            .order_by_desc(answer_outbox::Column::Id)
+            .limit(limit)
+            .all(self.pg_db.as_ref())
+            .await
+    }
+
+    /// Makes dead callbacks, the one of `request_id` or all of them, pending again with a fresh
+    /// set of attempts. Returns how many.
+    pub async fn outbox_replay(&self, request_id: Option<&str>) -> Result<u64, DbErr> {
+        let now = Local::now().naive_local();
+        let mut update = AnswerOutbox::update_many()
+            .col_expr(answer_outbox::Column::Status, Expr::value(OutboxStatus::Pending.as_str()))
+            .col_expr(answer_outbox::Column::Attempts, Expr::value(0))
+            .col_expr(answer_outbox::Column::NextAttemptAt, Expr::value(now))
+            .col_expr(answer_outbox::Column::UpdateAt, Expr::value(now))
+            .filter(answer_outbox::Column::Status.eq(OutboxStatus::Dead.as_str()));
+        if let Some(request_id) = request_id {
+            update = update.filter(answer_outbox::Column::RequestId.eq(request_id));
+        }
+        Ok(update.exec(self.pg_db.as_ref()).await?.rows_affected)
+    }
+
+    /// The job of a request and its answer callback, either may be missing.
+    pub async fn answer_of(
+        &self,
+        request_id: &str,
+    ) -> Result<(Option<inference_jobs::Model>, Option<answer_outbox::Model>), DbErr> {
+        let job = InferenceJobs::find()
+            .filter(inference_jobs::Column::RequestId.eq(request_id))
+            .one(self.pg_db.as_ref())
+            .await?;
+        let callback = AnswerOutbox::find()
+            .filter(answer_outbox::Column::RequestId.eq(request_id))
+            .one(self.pg_db.as_ref())
+            .await?;
+        Ok((job, callback))
+    }
+
+    /// A page of jobs, the latest first, with their answer callbacks and the total count. Jobs
+    /// not answered, or never called back, come without one.
+    pub async fn answers_page(
+        &self,
+        page: u64,
+        page_size: u64,
+    ) -> Result<(Vec<(inference_jobs::Model, Option<answer_outbox::Model>)>, u64), DbErr> {
+        let paginator = InferenceJobs::find()
+            .order_by_desc(inference_jobs::Column::Id)
+            .paginate(self.pg_db.as_ref(), page_size);
+        let total = paginator.num_items().await?;
+        let jobs = paginator.fetch_page(page).await?;
+        let request_ids = jobs.iter().map(|job| job.request_id.clone());
+        let callbacks = AnswerOutbox::find()
+            .filter(answer_outbox::Column::RequestId.is_in(request_ids))
+            .all(self.pg_db.as_ref())
+            .await?;
+        let answers = jobs
+            .into_iter()
+            .map(|job| {
+                let callback = callbacks
+                    .iter()
+                    .find(|callback| callback.request_id == job.request_id)
+                    .cloned();
+                (job, callback)
+            })
+            .collect();
+        Ok((answers, total))
+    }
+}
+
+
+
+fn job_params(sampling: &SamplingParams, max_tokens: usize, nonce: &str) -> String {
+    json!({
+        "sampling": sampling,
+        "max_tokens": max_tokens,
+        "nonce": nonce,
+    })
+    .to_string()
+}
+
+fn job_status_in(statuses: &[JobStatus]) -> SimpleExpr {
+    inference_jobs::Column::Status.is_in(statuses.iter().map(JobStatus::as_str))
+}
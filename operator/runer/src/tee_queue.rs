@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tee_llm::nitro_llm::{AnswerResp, TEEReq, TEEResp, VrfKeyResp};
+use tokio::sync::{
+    mpsc::{error::SendError, UnboundedSender},
+    oneshot,
+};
+use tokio::time::Instant;
+use vrf::sortition::Selection;
+
+/// Who waits for the reply of a prompt.
+#[derive(Debug)]
+pub enum Waiter {
+    /// Nobody, the answer only goes to the dispatcher callback.
+    Callback,
+    /// The answer, or the error the enclave failed with.
+    Answer(oneshot::Sender<Result<AnswerResp, String>>),
+    /// Token chunks, then the stream end. Turns into `Callback` once the client has gone.
+    Stream(UnboundedSender<TEEResp>),
+}
+
+#[derive(Debug)]
+struct Entry {
+    waiter: Waiter,
+    /// Of the prompt, to check the selection of its answer by.
+    selection: Option<Selection>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SubmitError {
+    /// `depth` prompts are in flight already.
+    Full(usize),
+    /// A prompt of the same request id is in flight.
+    Duplicate(String),
+    /// The session to the enclave is gone.
+    Closed,
+}
+
+/// Why a prompt submitted with `Waiter::Answer` has no answer.
+#[derive(Debug, PartialEq, Eq)]
+pub enum WaitError {
+    Timeout,
+    /// The enclave could not answer it.
+    Failed(String),
+}
+
+/// Prompts sent to the LLM enclave and not answered yet, keyed by request id, which is what ties
+/// the replies back to their waiters.
+///
+/// This is the queue of the operator: at most `depth` prompts are in flight, each one until the
+/// enclave has replied to it with its answer, the end of its stream or its failure. Waiters give
+/// up after `timeout`, a prompt still holds its slot then, as the enclave is still at it.
+#[derive(Debug)]
+pub struct TeeQueue {
+    sender: UnboundedSender<TEEReq>,
+    depth: usize,
+    timeout: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+    /// Latest attested VRF key of the enclave, the one its answers are proven with.
+    vrf_key: Mutex<Option<VrfKeyResp>>,
+}
+
+impl TeeQueue {
+    pub fn new(sender: UnboundedSender<TEEReq>, depth: usize, timeout: Duration) -> Self {
+        Self {
+            sender,
+            depth,
+            timeout,
+            entries: Default::default(),
+            vrf_key: Default::default(),
+        }
+    }
+
+    /// `None` until the enclave has replied to a `TEEReq::VrfKey`.
+    pub fn vrf_key(&self) -> Option<VrfKeyResp> {
+        self.vrf_key.lock().unwrap().clone()
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Prompts in flight.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Sends a request that is not a prompt, bypassing the queue.
+    pub fn send(&self, req: TEEReq) -> Result<(), SubmitError> {
+        self.sender
+            .send(req)
+            .map_err(|SendError(_)| SubmitError::Closed)
+    }
+
+    /// Queues a prompt for `waiter`, returns when the waiter is to give up on its reply.
+    pub fn submit(&self, req: TEEReq, waiter: Waiter) -> Result<Instant, SubmitError> {
+        let (request_id, selection) = match &req {
+            TEEReq::PromptReq(req) | TEEReq::StreamPromptReq(req) => {
+                (req.request_id.clone(), req.selection().ok())
+            }
+            _ => return self.send(req).map(|()| Instant::now() + self.timeout),
+        };
+        let deadline = Instant::now() + self.timeout;
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if entries.contains_key(&request_id) {
+                return Err(SubmitError::Duplicate(request_id));
+            }
+            if entries.len() >= self.depth {
+                return Err(SubmitError::Full(self.depth));
+            }
+            entries.insert(
+                request_id.clone(),
+                Entry { waiter, selection },
+            );
+        }
+        if self.send(req).is_err() {
+            self.entries.lock().unwrap().remove(&request_id);
+            return Err(SubmitError::Closed);
+        }
+        Ok(deadline)
+    }
+
+    /// Waits for the answer of a prompt submitted with `Waiter::Answer`.
+    pub async fn wait(
+        &self,
+        request_id: &str,
+        receiver: oneshot::Receiver<Result<AnswerResp, String>>,
+    ) -> Result<AnswerResp, WaitError> {
+        match tokio::time::timeout(self.timeout, receiver).await {
+            Ok(Ok(answer)) => answer.map_err(WaitError::Failed),
+            // replied with the end of a stream instead
+            Ok(Err(_)) => Err(WaitError::Timeout),
+            Err(_) => {
+                self.abandon(request_id);
+                Err(WaitError::Timeout)
+            }
+        }
+    }
+
+    /// Selection of a prompt in flight, `None` once it is replied to.
+    pub fn selection(&self, request_id: &str) -> Option<Selection> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(request_id).and_then(|entry| entry.selection)
+    }
+
+    /// Gives up waiting for the reply of a prompt. The prompt keeps its slot until the reply
+    /// comes, which then only goes to the callback.
+    pub fn abandon(&self, request_id: &str) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(request_id) {
+            entry.waiter = Waiter::Callback
+        }
+    }
+
+    /// Hands a reply of the enclave to the waiter of its prompt.
+    pub fn dispatch(&self, resp: TEEResp) {
+        if let TEEResp::VrfKey(key) = resp {
+            *self.vrf_key.lock().unwrap() = Some(key);
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        match resp {
+            TEEResp::TokenChunk(chunk) => {
+                if let Some(entry) = entries.get_mut(&chunk.request_id) {
+                    if let Waiter::Stream(subscriber) = &entry.waiter {
+                        // the client has gone, keep generating for the callback only
+                        if subscriber.send(TEEResp::TokenChunk(chunk)).is_err() {
+                            entry.waiter = Waiter::Callback
+                        }
+                    }
+                }
+            }
+            TEEResp::StreamEnd(end) => {
+                if let Some(Entry {
+                    waiter: Waiter::Stream(subscriber),
+                    ..
+                }) = entries.remove(&end.answer.request_id)
+                {
+                    let _ = subscriber.send(TEEResp::StreamEnd(end));
+                }
+            }
+            TEEResp::AnswerResp(answer) => {
+                if let Some(Entry {
+                    waiter: Waiter::Answer(waiter),
+                    ..
+                }) = entries.remove(&answer.request_id)
+                {
+                    let _ = waiter.send(Ok(answer));
+                }
+            }
+            TEEResp::Failed(failed) => match entries.remove(&failed.request_id) {
+                Some(Entry {
+                    waiter: Waiter::Answer(waiter),
+                    ..
+                }) => {
+                    let _ = waiter.send(Err(failed.error));
+                }
+                Some(Entry {
+                    waiter: Waiter::Stream(subscriber),
+                    ..
+                }) => {
+                    let _ = subscriber.send(TEEResp::Failed(failed));
+                }
+                _ => {}
+            },
+            TEEResp::Ping(_) | TEEResp::Models(_) | TEEResp::VrfKey(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tee_llm::nitro_llm::{FailedResp, PromptReq, StreamEnd, TokenChunk};
+    use tee_llm::sampling::SamplingParams;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    fn prompt(request_id: &str) -> TEEReq {
+        TEEReq::PromptReq(PromptReq {
+            request_id: request_id.into(),
+            model_name: "./model.gguf".into(),
+            prompt: "How to combine AI and blockchain?".into(),
+            messages: vec![],
+            chat_template: None,
+            sampling: SamplingParams::default(),
+            n_predict: 16,
+            vrf_prompt_hash: "hash".into(),
+            vrf_threshold: 0,
+            vrf_precision: 6,
+            vrf_selection: None,
+            nonce: String::new(),
+        })
+    }
+
+    fn answer(request_id: &str) -> AnswerResp {
+        AnswerResp {
+            request_id: request_id.into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn bounded_and_correlated() {
+        let (sender, mut receiver) = unbounded_channel();
+        let queue = TeeQueue::new(sender, 2, Duration::from_secs(60));
+        let (waiter, answered) = oneshot::channel();
+        queue.submit(prompt("1"), Waiter::Answer(waiter)).unwrap();
+        assert_eq!(
+            queue.submit(prompt("1"), Waiter::Callback),
+            Err(SubmitError::Duplicate("1".into()))
+        );
+        queue.submit(prompt("2"), Waiter::Callback).unwrap();
+        assert_eq!(
+            queue.submit(prompt("3"), Waiter::Callback),
+            Err(SubmitError::Full(2))
+        );
+        assert_eq!(queue.selection("2"), Selection::threshold(0, 6).ok());
+        assert_eq!(queue.selection("3"), None);
+        // not a prompt, not queued
+        queue.submit(TEEReq::ListModels, Waiter::Callback).unwrap();
+        assert_eq!(queue.len(), 2);
+
+        queue.dispatch(TEEResp::AnswerResp(answer("2")));
+        queue.dispatch(TEEResp::AnswerResp(answer("1")));
+        assert_eq!(queue.wait("1", answered).await.unwrap().request_id, "1");
+        assert!(queue.is_empty());
+
+        let (waiter, answered) = oneshot::channel();
+        queue.submit(prompt("5"), Waiter::Answer(waiter)).unwrap();
+        queue.dispatch(TEEResp::Failed(FailedResp {
+            request_id: "5".into(),
+            error: "no such model".into(),
+        }));
+        assert_eq!(
+            queue.wait("5", answered).await.unwrap_err(),
+            WaitError::Failed("no such model".into())
+        );
+        assert!(queue.is_empty());
+        assert_eq!(std::iter::from_fn(|| receiver.try_recv().ok()).count(), 4);
+
+        drop(receiver);
+        assert_eq!(
+            queue.submit(prompt("4"), Waiter::Callback),
+            Err(SubmitError::Closed)
+        );
+        assert!(queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stream_outlives_client() {
+        let (sender, _receiver) = unbounded_channel();
+        let queue = TeeQueue::new(sender, 1, Duration::from_secs(60));
+        let (subscriber, mut chunks) = unbounded_channel();
+        queue
+            .submit(prompt("1"), Waiter::Stream(subscriber))
+            .unwrap();
+        let chunk = |seq| {
+            TEEResp::TokenChunk(TokenChunk {
+                request_id: "1".into(),
+                seq,
+                token: "a".into(),
+            })
+        };
+        queue.dispatch(chunk(0));
+        assert!(matches!(chunks.recv().await, Some(TEEResp::TokenChunk(_))));
+        drop(chunks);
+        queue.dispatch(chunk(1));
+        // still in flight until the end
+        assert_eq!(queue.len(), 1);
+        queue.dispatch(TEEResp::StreamEnd(StreamEnd {
+            chunks: 2,
+            answer: answer("1"),
+            transcript_hash: String::new(),
+        }));
+        assert!(queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn keep_vrf_key() {
+        let (sender, _receiver) = unbounded_channel();
+        let queue = TeeQueue::new(sender, 1, Duration::from_secs(60));
+        assert!(queue.vrf_key().is_none());
+        let key = |public_key: &str| {
+            TEEResp::VrfKey(VrfKeyResp {
+                public_key: public_key.into(),
+                ..Default::default()
+            })
+        };
+        queue.dispatch(key("00"));
+        // re-attested, or the enclave has restarted
+        queue.dispatch(key("01"));
+        assert_eq!(queue.vrf_key().unwrap().public_key, "01");
+    }
+
+    #[tokio::test]
+    async fn hold_slot_until_replied() {
+        let (sender, _receiver) = unbounded_channel();
+        let queue = TeeQueue::new(sender, 1, Duration::from_millis(50));
+        let (waiter, answered) = oneshot::channel();
+        queue.submit(prompt("1"), Waiter::Answer(waiter)).unwrap();
+        assert_eq!(queue.wait("1", answered).await.unwrap_err(), WaitError::Timeout);
+        // still at it in the enclave
+        assert_eq!(
+            queue.submit(prompt("2"), Waiter::Callback),
+            Err(SubmitError::Full(1))
+        );
+        // the late answer frees the slot
+        queue.dispatch(TEEResp::AnswerResp(answer("1")));
+        assert!(queue.is_empty());
+        queue.submit(prompt("2"), Waiter::Callback).unwrap();
+        assert_eq!(queue.len(), 1);
+    }
+}
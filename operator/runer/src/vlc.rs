@@ -0,0 +1,350 @@
+use crate::storage::Storage;
+use chrono::NaiveDateTime;
+use common::crypto::canonical::{bincode_digest, CanonicalDigest as _};
+use common::ordinary_clock::{KeyId, OrdinaryClock};
+use common::types::Payload;
+use db_sql::pg::entities::clock_infos;
+use node_api::error::{OperatorError, OperatorResult};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tee_vlc::nitro_clock::{ClockClient, NitroEnclavesClock};
+use tee_vlc::Clocked;
+use tokio::sync::broadcast;
+use tracing::{error, info, warn};
+pub use verifier::callback::ClockCredential;
+
+/// How long the VLC enclave is waited for to tick.
+const TICK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Clocks of the latest answers kept for their waiters.
+const RECENT_ANSWERS: usize = 1024;
+/// Ticks buffered for the gossip with peers, older ones are dropped for slow peers.
+const TICKS_BACKLOG: usize = 256;
+
+/// What the clock is ticked for, the raw message of a tick in `clock_infos`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClockEvent<'a> {
+    Question {
+        request_id: &'a str,
+        prompt_hash: &'a str,
+    },
+    Answer {
+        request_id: &'a str,
+        selected: bool,
+    },
+}
+
+/// A tick of a node as gossiped to its peers, a `ClockEvent` of it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerEvent {
+    pub node_id: String,
+    pub message_id: String,
+    /// JSON of the `ClockEvent`.
+    pub event: String,
+    /// EIP-191 signature by the node over `signed_message`, hex.
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl PeerEvent {
+    /// Commits to the event and to the clock it is gossiped with: the hex SHA-256 of the
+    /// `bincode::options()` encoding of `(node_id, message_id, event, clock_hash)`.
+    pub fn signed_message(&self, clock: &OrdinaryClock) -> String {
+        let clock_hash = clock.canonical_digest();
+        hex::encode(bincode_digest(&(
+            &self.node_id,
+            &self.message_id,
+            &self.event,
+            clock_hash,
+        )))
+    }
+}
+
+pub type ClockedEvent = Clocked<PeerEvent, NitroEnclavesClock>;
+
+impl ClockEvent<'_> {
+    fn message_id(&self) -> &str {
+        match self {
+            Self::Question { request_id, .. } | Self::Answer { request_id, .. } => request_id,
+        }
+    }
+}
+
+/// How an event is ordered relative to another by their clocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CausalOrder {
+    /// Happened before the other.
+    Before,
+    After,
+    Equal,
+    Concurrent,
+}
+
+impl CausalOrder {
+    pub fn of(clock: &OrdinaryClock, other: &OrdinaryClock) -> Self {
+        match clock.partial_cmp(other) {
+            Some(Ordering::Less) => Self::Before,
+            Some(Ordering::Greater) => Self::After,
+            Some(Ordering::Equal) => Self::Equal,
+            None => Self::Concurrent,
+        }
+    }
+}
+
+/// A tick recorded in `clock_infos`, with the attestation document of its clock. The event of a
+/// peer is ordered by the tick that merged its clock, i.e. by its receipt.
+#[derive(Debug, Serialize)]
+pub struct ClockRecord {
+    pub message_id: String,
+    pub node_id: String,
+    /// The `ClockEvent` of the tick, or the `ClockedEvent` of the peer merged.
+    pub event: serde_json::Value,
+    pub clock: ClockCredential,
+    pub create_at: Option<NaiveDateTime>,
+}
+
+impl TryFrom<clock_infos::Model> for ClockRecord {
+    type Error = serde_json::Error;
+
+    fn try_from(model: clock_infos::Model) -> Result<Self, Self::Error> {
+        Ok(Self {
+            clock: serde_json::from_str(&model.clock)?,
+            event: serde_json::from_slice(&model.raw_message).unwrap_or_default(),
+            message_id: model.message_id,
+            node_id: model.node_id,
+            create_at: model.create_at,
+        })
+    }
+}
+
+/// The verifiable logical clock of the node, ticked in the VLC enclave for every accepted
+/// question and every answer, so that the answer callbacks carry an attested causal timestamp.
+/// Every tick is recorded in `clock_infos`, and the clock is taken up from the latest one after
+/// a restart.
+pub struct VlcClock {
+    node_id: String,
+    client: ClockClient,
+    storage: Storage,
+    answers: Mutex<VecDeque<(String, ClockCredential)>>,
+    ticks: broadcast::Sender<Arc<ClockedEvent>>,
+}
+
+/// Key of the node in the clock, the leading 8 bytes of its address.
+pub fn clock_key(node_id: &str) -> KeyId {
+    let bytes = hex::decode(node_id.trim_start_matches("0x")).unwrap_or_default();
+    let mut key = [0; 8];
+    let len = bytes.len().min(8);
+    key[..len].copy_from_slice(&bytes[..len]);
+    KeyId::from_be_bytes(key)
+}
+
+/// Hex SHA-256 of a clock, what the enclave attests.
+pub fn clock_hash(clock: &OrdinaryClock) -> String {
+    hex::encode(clock.canonical_digest())
+}
+
+pub fn credential(clock: &NitroEnclavesClock) -> ClockCredential {
+    ClockCredential {
+        clock: clock.plain.clone(),
+        tee_attestation: base64::encode(&clock.document.0),
+    }
+}
+
+fn attested(credential: ClockCredential) -> Result<NitroEnclavesClock, base64::DecodeError> {
+    Ok(NitroEnclavesClock {
+        document: Payload(base64::decode(&credential.tee_attestation)?),
+        plain: credential.clock,
+    })
+}
+
+impl VlcClock {
+    pub async fn connect(addr: &str, node_id: String, storage: Storage) -> OperatorResult<Self> {
+        let genesis = NitroEnclavesClock {
+            plain: OrdinaryClock::default(),
+            document: Default::default(),
+        };
+        let clock = match storage.last_clock(&node_id).await {
+            Ok(Some(last)) => serde_json::from_str::<ClockCredential>(&last.clock)
+                .map_err(|err| err.to_string())
+                .and_then(|credential| attested(credential).map_err(|err| err.to_string()))
+                .unwrap_or_else(|err| {
+                    warn!(
+                        "malformed last clock {}, starting from genesis, {}",
+                        last.id, err
+                    );
+                    genesis
+                }),
+            Ok(None) => genesis,
+            Err(err) => return Err(OperatorError::OPConnectTEEError(err.to_string())),
+        };
+        let client = async {
+            ClockClient::connect(addr.parse()?, clock_key(&node_id), clock, TICK_TIMEOUT).await
+        }
+        .await
+        .map_err(|err| OperatorError::OPConnectTEEError(err.to_string()))?;
+        info!("connect vlc tee service at {addr} successed!");
+        Ok(Self {
+            node_id,
+            client,
+            storage,
+            answers: Default::default(),
+            ticks: broadcast::channel(TICKS_BACKLOG).0,
+        })
+    }
+
+    pub async fn current(&self) -> NitroEnclavesClock {
+        self.client.current().await
+    }
+
+    /// The ticks of the node from now on, for its peers.
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<ClockedEvent>> {
+        self.ticks.subscribe()
+    }
+
+    /// Ticks the clock for `event` and records it, `None` if the enclave failed to.
+    pub async fn tick(&self, event: ClockEvent<'_>) -> Option<ClockCredential> {
+        let attested = match self.client.update(vec![]).await {
+            Ok(clock) => clock,
+            Err(err) => {
+                error!("vlc tick of {} failed, {}", event.message_id(), err);
+                return None;
+            }
+        };
+        let clock = credential(&attested);
+        let raw_message = serde_json::to_vec(&event).unwrap_or_default();
+        self.storage
+            .sinker_clock(
+                &self.node_id,
+                event.message_id(),
+                raw_message.clone(),
+                &clock,
+            )
+            .await;
+        // no receivers without peers
+        let _ = self.ticks.send(Arc::new(Clocked {
+            clock: attested,
+            inner: PeerEvent {
+                node_id: self.node_id.clone(),
+                message_id: event.message_id().to_owned(),
+                event: String::from_utf8(raw_message).unwrap_or_default(),
+                // by the gossip
+                signature: String::new(),
+            },
+        }));
+        if let ClockEvent::Answer { request_id, .. } = event {
+            let mut answers = self.answers.lock().unwrap();
+            if answers.len() >= RECENT_ANSWERS {
+                answers.pop_front();
+            }
+            answers.push_back((request_id.to_owned(), clock.clone()));
+        }
+        Some(clock)
+    }
+
+    /// Merges the clock of an event of a peer, verified already, and records it with `raw_message`.
+    /// `None` if the clock is not newer than the one of the node, e.g. an event gossiped by more
+    /// than one connection, or if the enclave failed to merge it.
+    pub async fn merge(
+        &self,
+        clocked: &ClockedEvent,
+        raw_message: Vec<u8>,
+    ) -> Option<ClockCredential> {
+        if clocked.clock.plain <= self.client.current().await.plain {
+            return None;
+        }
+        let message_id = &clocked.inner.message_id;
+        let clock = match self.client.update(vec![clocked.clock.clone()]).await {
+            Ok(clock) => credential(&clock),
+            Err(err) => {
+                error!(
+                    "vlc merge of {} from {} failed, {}",
+                    message_id, clocked.inner.node_id, err
+                );
+                return None;
+            }
+        };
+        self.storage
+            .sinker_clock(&self.node_id, message_id, raw_message, &clock)
+            .await;
+        Some(clock)
+    }
+
+    /// The clock an answer was ticked with, for as long as it is among the latest ones.
+    pub fn answer_clock(&self, request_id: &str) -> Option<ClockCredential> {
+        let answers = self.answers.lock().unwrap();
+        answers
+            .iter()
+            .rev()
+            .find(|(id, _)| id == request_id)
+            .map(|(_, clock)| clock.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_clock_key() {
+        assert_eq!(
+            clock_key("0x02a5592a6de1568f6efdc536da3ef887f98414cb"),
+            0x02a5592a6de1568f
+        );
+        assert_eq!(clock_key("0x0102"), 0x0102000000000000);
+        assert_eq!(clock_key("not hex"), 0);
+    }
+
+    #[test]
+    fn clock_event() {
+        let event = ClockEvent::Question {
+            request_id: "1",
+            prompt_hash: "ab",
+        };
+        assert_eq!(event.message_id(), "1");
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"question":{"request_id":"1","prompt_hash":"ab"}}"#
+        );
+    }
+
+    #[test]
+    fn peer_event_signed_message() {
+        let event = PeerEvent {
+            node_id: "0x02a5592a6de1568f6efdc536da3ef887f98414cb".into(),
+            message_id: "1".into(),
+            event: "{}".into(),
+            signature: String::new(),
+        };
+        let clock = OrdinaryClock([(1, 1)].into_iter().collect());
+        let message = event.signed_message(&clock);
+        assert_eq!(message.len(), 64);
+        // not over the signature itself
+        let signed = PeerEvent {
+            signature: "ab".into(),
+            ..event.clone()
+        };
+        assert_eq!(signed.signed_message(&clock), message);
+        // nor valid along with another clock
+        let other = OrdinaryClock([(1, 2)].into_iter().collect());
+        assert_ne!(event.signed_message(&other), message);
+    }
+
+    #[test]
+    fn causal_order() {
+        let clock = |entries: &[(KeyId, u32)]| OrdinaryClock(entries.iter().copied().collect());
+        let question = clock(&[(1, 1)]);
+        let answer = clock(&[(1, 2)]);
+        let merged = clock(&[(1, 1), (2, 1)]);
+        assert_eq!(CausalOrder::of(&question, &answer), CausalOrder::Before);
+        assert_eq!(CausalOrder::of(&merged, &question), CausalOrder::After);
+        assert_eq!(CausalOrder::of(&answer, &merged), CausalOrder::Concurrent);
+        assert_eq!(
+            CausalOrder::of(&answer, &clock(&[(1, 2), (2, 0)])),
+            CausalOrder::Equal
+        );
+    }
+}
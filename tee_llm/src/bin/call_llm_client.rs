@@ -1,5 +1,7 @@
 use std::{env, fmt::Write, future::pending, time::Duration};
 
+use common::transport::Address;
+use tee_llm::sampling::SamplingParams;
 use tee_llm::nitro_llm::{nitro_enclaves_portal_session, AnswerResp, PromptReq, TEEReq, TEEResp};
 use tokio::{
     sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
@@ -18,6 +20,10 @@ async fn main() -> anyhow::Result<()> {
     } else {
         None
     };
+    let addr = match args.get(2) {
+        Some(addr) => addr.parse()?,
+        None => Address::vsock(CID, 5005),
+    };
 
     let run_nitro_client = {
         let (update_sender, update_receiver) = unbounded_channel();
@@ -31,8 +37,7 @@ async fn main() -> anyhow::Result<()> {
         });
         (
             tokio::spawn(nitro_enclaves_portal_session(
-                CID,
-                5005,
+                addr,
                 update_receiver,
                 update_ok_sender,
             )),
@@ -84,12 +89,15 @@ async fn bench_session(
         request_id: "todo!()".to_owned(),
         model_name: "./llama-2-7b-chat.Q4_0.gguf".to_owned(),
         prompt: "How to combine AI and blockchain?".to_owned(),
-        top_p: 0.95,
-        temperature: 0.0,
+        messages: vec![],
+        chat_template: None,
+        sampling: SamplingParams::with(0.0, 0.95, None),
         n_predict: 128,
         vrf_threshold: 16777215,
         vrf_precision: 6,
+        vrf_selection: None,
         vrf_prompt_hash: "sfas".to_owned(),
+        nonce: String::new(),
     });
 
     let ping = TEEReq::Ping("hello".to_owned());
@@ -1 +1,5 @@
-pub mod nitro_llm;
\ No newline at end of file
+pub mod nitro_llm;
+pub mod model_cache;
+pub mod sampling;
+
+pub use llm_types::{chat, commitment};
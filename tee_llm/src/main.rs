@@ -2,5 +2,10 @@ use tee_llm::nitro_llm::NitroEnclavesLlm;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    NitroEnclavesLlm::run(5005).await
-}
\ No newline at end of file
+    // e.g. `unix:///tmp/llm.sock` to run outside of an enclave
+    let addr = std::env::args()
+        .nth(1)
+        .unwrap_or("vsock://any:5005".into())
+        .parse()?;
+    NitroEnclavesLlm::run(addr).await
+}
@@ -0,0 +1,273 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use llama_cpp::{LlamaModel, LlamaParams};
+use llm_types::nitro_llm::ResidentModel;
+use sha2::{Digest as _, Sha256};
+use tools::helper::machine_used;
+
+use crate::chat::{gguf_chat_template, ChatTemplate};
+
+/// Share of the enclave memory that resident model weights may take, the rest is left for
+/// sessions (KV caches) and everything else.
+pub const MODEL_MEMORY_RATIO: f64 = 0.75;
+
+/// How much memory a model takes once loaded, known before loading it.
+pub type SizeFn = fn(&str) -> anyhow::Result<u64>;
+
+/// Loads a model and describes it.
+pub type LoadFn<M> = fn(&str) -> anyhow::Result<(M, ResidentModel)>;
+
+struct Entry<M> {
+    model: M,
+    info: ResidentModel,
+    last_used: u64,
+}
+
+struct Models<M> {
+    entries: HashMap<String, Entry<M>>,
+    tick: u64,
+}
+
+/// Models resident in the enclave, keyed by model name (the path of the weights), evicted in
+/// least recently used order once their total size would exceed `capacity` bytes.
+///
+/// Models are evicted only once a missing one has loaded, so that a load that fails leaves the
+/// resident ones in place. Loads are serialized and run on the blocking threads, so concurrent
+/// requests of a missing model load it only once, while requests of resident models go on
+/// meanwhile.
+pub struct ModelCache<M = LlamaModel> {
+    capacity: u64,
+    size: SizeFn,
+    load: LoadFn<M>,
+    models: Mutex<Models<M>>,
+    loading: tokio::sync::Mutex<()>,
+}
+
+impl<M> std::fmt::Debug for ModelCache<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ModelCache")
+            .field("capacity", &self.capacity)
+            .field("resident", &self.resident())
+            .finish()
+    }
+}
+
+// weights are mapped as a whole, file size is a fair estimation
+fn llama_size(name: &str) -> anyhow::Result<u64> {
+    Ok(std::fs::metadata(name)?.len())
+}
+
+fn load_llama(name: &str) -> anyhow::Result<(LlamaModel, ResidentModel)> {
+    let chat_template = gguf_chat_template(name)?
+        .as_deref()
+        .and_then(ChatTemplate::detect);
+    let mut file = std::fs::File::open(name)?;
+    let mut hasher = Sha256::new();
+    let size = std::io::copy(&mut file, &mut hasher)?;
+    let model = LlamaModel::load_from_file(name, LlamaParams::default())?;
+    let info = ResidentModel {
+        name: name.into(),
+        size,
+        hash: hex::encode(hasher.finalize()),
+        chat_template,
+    };
+    Ok((model, info))
+}
+
+impl ModelCache {
+    /// A cache bounded by `MODEL_MEMORY_RATIO` of the memory this enclave has.
+    pub fn for_enclave() -> Self {
+        let mem_total = machine_used().2;
+        Self::new(
+            (mem_total as f64 * MODEL_MEMORY_RATIO) as _,
+            llama_size,
+            load_llama,
+        )
+    }
+}
+
+impl<M: Clone + Send + 'static> ModelCache<M> {
+    pub fn new(capacity: u64, size: SizeFn, load: LoadFn<M>) -> Self {
+        Self {
+            capacity,
+            size,
+            load,
+            models: Mutex::new(Models {
+                entries: Default::default(),
+                tick: 0,
+            }),
+            loading: Default::default(),
+        }
+    }
+
+    fn resident_get(&self, name: &str) -> Option<(M, ResidentModel)> {
+        let mut models = self.models.lock().unwrap();
+        models.tick += 1;
+        let tick = models.tick;
+        let entry = models.entries.get_mut(name)?;
+        entry.last_used = tick;
+        Some((entry.model.clone(), entry.info.clone()))
+    }
+
+    /// Returns the resident model, loading it first if necessary.
+    pub async fn get(&self, name: &str) -> anyhow::Result<(M, ResidentModel)> {
+        if let Some(resident) = self.resident_get(name) {
+            return Ok(resident);
+        }
+        let _loading = self.loading.lock().await;
+        // loaded by the request waited for
+        if let Some(resident) = self.resident_get(name) {
+            return Ok(resident);
+        }
+
+        let (size_of, load, capacity) = (self.size, self.load, self.capacity);
+        let owned = name.to_owned();
+        // hashing and loading the weights takes a while
+        let (model, mut info, size) = tokio::task::spawn_blocking(move || {
+            let size = size_of(&owned)?;
+            anyhow::ensure!(
+                size <= capacity,
+                "model {owned} of {size} bytes exceeds cache capacity {capacity}"
+            );
+            let (model, info) = load(&owned)?;
+            anyhow::Ok((model, info, size))
+        })
+        .await??;
+        info.size = size;
+
+        let mut models = self.models.lock().unwrap();
+        while models
+            .entries
+            .values()
+            .map(|entry| entry.info.size)
+            .sum::<u64>()
+            + size
+            > self.capacity
+        {
+            let Some(lru) = models
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(name, _)| name.clone())
+            else {
+                unreachable!()
+            };
+            models.entries.remove(&lru);
+        }
+        models.tick += 1;
+        let last_used = models.tick;
+        models.entries.insert(
+            name.into(),
+            Entry {
+                model: model.clone(),
+                info: info.clone(),
+                last_used,
+            },
+        );
+        Ok((model, info))
+    }
+
+    /// Returns whether the model was resident. Sessions that are still running keep their own
+    /// reference to it.
+    pub fn evict(&self, name: &str) -> bool {
+        self.models.lock().unwrap().entries.remove(name).is_some()
+    }
+}
+
+impl<M> ModelCache<M> {
+    /// Resident models, most recently used first.
+    pub fn resident(&self) -> Vec<ResidentModel> {
+        let models = self.models.lock().unwrap();
+        let mut entries = models.entries.iter().collect::<Vec<_>>();
+        entries.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.last_used));
+        entries
+            .into_iter()
+            .map(|(_, entry)| entry.info.clone())
+            .collect()
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "model" named by its size
+    fn size(name: &str) -> anyhow::Result<u64> {
+        Ok(name.parse()?)
+    }
+
+    fn load(name: &str) -> anyhow::Result<(String, ResidentModel)> {
+        let info = ResidentModel {
+            name: name.into(),
+            size: size(name)?,
+            hash: Default::default(),
+            chat_template: None,
+        };
+        Ok((name.into(), info))
+    }
+
+    fn names(cache: &ModelCache<String>) -> Vec<String> {
+        cache.resident().into_iter().map(|model| model.name).collect()
+    }
+
+    #[tokio::test]
+    async fn evict_least_recently_used() -> anyhow::Result<()> {
+        let cache = ModelCache::new(10, size, load);
+        cache.get("4").await?;
+        cache.get("5").await?;
+        cache.get("4").await?;
+        assert_eq!(names(&cache), ["4", "5"]);
+        // 5 is the least recently used one
+        cache.get("6").await?;
+        assert_eq!(names(&cache), ["6", "4"]);
+        cache.get("10").await?;
+        assert_eq!(names(&cache), ["10"]);
+        assert!(cache.get("11").await.is_err());
+        assert_eq!(names(&cache), ["10"]);
+        Ok(())
+    }
+
+    fn slow_load(name: &str) -> anyhow::Result<(String, ResidentModel)> {
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        anyhow::ensure!(name != "7", "corrupted weights");
+        load(name)
+    }
+
+    #[tokio::test]
+    async fn evict_after_load() -> anyhow::Result<()> {
+        let cache = std::sync::Arc::new(ModelCache::new(10, size, slow_load));
+        cache.get("2").await?;
+        cache.get("4").await?;
+        let loading = tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.get("6").await.map(|_| ()) }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        // resident ones are served meanwhile, and nothing is evicted yet
+        let start = std::time::Instant::now();
+        cache.get("4").await?;
+        assert!(start.elapsed() < std::time::Duration::from_millis(100));
+        assert_eq!(names(&cache), ["4", "2"]);
+        loading.await??;
+        assert_eq!(names(&cache), ["6", "4"]);
+        // a failed load evicts nothing
+        assert!(cache.get("7").await.is_err());
+        assert_eq!(names(&cache), ["6", "4"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn explicit_evict() -> anyhow::Result<()> {
+        let cache = ModelCache::new(10, size, load);
+        cache.get("1").await?;
+        assert!(cache.evict("1"));
+        assert!(!cache.evict("1"));
+        assert!(cache.resident().is_empty());
+        assert!(cache.get("not a model").await.is_err());
+        Ok(())
+    }
+}
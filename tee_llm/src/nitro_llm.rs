@@ -1,11 +1,10 @@
 use common::{
-    crypto::core::DigestHash,
-    nitro_secure::{HandleFn, NitroSecureModule as NitroSecure},
+    nitro_secure::{HandleFn, NitroSecureModule as NitroSecure, SecureModule},
     ordinary_clock::{Clock, LamportClock, OrdinaryClock},
+    transport::{self, Address},
     types::Payload,
 };
-use num_bigint::BigUint;
-use vrf::{ecvrf::{Output, VRFPrivateKey, VRFPublicKey, OUTPUT_LENGTH}, sample::Sampler as VRFSampler};
+use vrf::{ecvrf::{Output, VRFPrivateKey, VRFPublicKey}, sortition};
 use tools::helper::machine_used;
 use anyhow::Ok;
 use bincode::Options;
@@ -18,68 +17,35 @@ use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tracing::*;
 
-use llama_cpp::standard_sampler::{SamplerStage, StandardSampler};
-use llama_cpp::{LlamaModel, LlamaParams, SessionParams};
+use llama_cpp::{LlamaModel, SessionParams};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum TEEReq {
-    Ping(String),
-    PromptReq(PromptReq),
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum TEEResp {
-    Ping(PingResp),
-    AnswerResp(AnswerResp),
-}
+use crate::{
+    model_cache::ModelCache,
+    sampling::{standard_sampler, stop_point},
+};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct PromptReq {
-    pub request_id: String,
-    pub model_name: String,
-    pub prompt: String,
-    pub temperature: f32,
-    pub top_p: f32,       // top p
-    pub n_predict: usize, // maximum predict token
-    pub vrf_prompt_hash: String,
-    pub vrf_threshold: u64,
-    pub vrf_precision: usize,
-    // pub n_threads: u32,
-    // pub clock: NitroEnclavesClock, // to be done
-}
+pub use llm_types::nitro_llm::*;
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
-pub struct AnswerResp {
-    pub request_id: String,
-    pub model_name: String,
-    pub prompt: String,
-    pub answer: String,
-    pub elapsed: u64,
-    pub selected: bool,
-    pub document: Payload,
-    pub vrf_prompt_hash: String,
-    pub vrf_random_value: String,
-    pub vrf_verify_pubkey: String,
-    pub vrf_proof: String,
-    // pub clock: NitroEnclavesClock, // to be done
+/// The VRF keypair of an enclave, generated once per boot so that its proofs cannot be re-rolled
+/// until selected. The public key is only ever given out attested, see `VrfKeyResp`.
+pub struct VrfKey {
+    private_key: VRFPrivateKey,
+    public_key: VRFPublicKey,
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
-pub struct VRFReply {
-    pub selected: bool,
-    pub vrf_prompt_hash: String,
-    pub vrf_random_value: String,
-    pub vrf_verify_pubkey: String,
-    pub vrf_proof: String,
-}
+impl VrfKey {
+    pub fn generate() -> Self {
+        let private_key = VRFPrivateKey::generate_keypair(&mut OsRng);
+        let public_key = (&private_key).into();
+        Self {
+            private_key,
+            public_key,
+        }
+    }
 
-#[derive(Serialize, Deserialize, Clone, Default, Debug)]
-pub struct PingResp {
-    pub echo: String,
-    pub cpu_percent: f32,
-    pub cpu_nums: usize,
-    pub mem_total: u64,
-    pub mem_used: u64,
+    pub fn public_key_hex(&self) -> String {
+        hex::encode(self.public_key.as_bytes())
+    }
 }
 
 #[derive(Debug, Clone, Hash, Serialize, Deserialize)]
@@ -89,68 +55,39 @@ pub struct NitroEnclavesLlm {
     pub mem_mb: u32,
 }
 
-// technically `feature = "aws-nitro-enclaves-attestation"` is sufficient for
-// attestation, NSM API is only depended by `NitroSecureModule` that running
-// inside enclaves image
-#[cfg(feature = "nitro-enclaves")]
-impl AnswerResp {
-    pub fn verify_inference(
-        &self,
-    ) -> anyhow::Result<Option<aws_nitro_enclaves_nsm_api::api::AttestationDoc>> {
-        if self.answer.is_empty() {
-            return Ok(None);
-        }
-        use aws_nitro_enclaves_attestation::{AttestationProcess as _, AWS_ROOT_CERT};
-        use aws_nitro_enclaves_nsm_api::api::AttestationDoc;
-        let document = AttestationDoc::from_bytes(
-            &self.document,
-            AWS_ROOT_CERT,
-            std::time::SystemTime::UNIX_EPOCH
-                .elapsed()
-                .unwrap()
-                .as_secs(),
-        )?;
-        use DigestHash as _;
-        anyhow::ensure!(
-            document.user_data.as_ref().map(|user_data| &***user_data)
-                == Some(&self.answer.sha256().to_fixed_bytes()[..])
-        );
-        Ok(Some(document))
-    }
-}
-
-#[cfg(feature = "nitro-enclaves")]
 impl NitroEnclavesLlm {
 
-    pub fn run_vrf(req: PromptReq) -> Result<VRFReply, anyhow::Error> {
-        let private_key = VRFPrivateKey::generate_keypair(&mut OsRng);
-        let public_key: VRFPublicKey = (&private_key).into();
-        let proof: vrf::ecvrf::Proof = private_key.prove(req.vrf_prompt_hash.as_bytes());
+    pub fn run_vrf(req: PromptReq, key: &VrfKey) -> Result<VRFReply, anyhow::Error> {
+        let alpha = req.vrf_prompt_hash.as_bytes();
+        let batchable = key.private_key.prove_batchable(alpha);
+        let proof = batchable.to_proof(&key.public_key, alpha);
         let output: Output = (&proof).into();
-        let start = OUTPUT_LENGTH * 2 - req.vrf_precision;
-        let end = OUTPUT_LENGTH * 2;
-        let random_num = hex::encode(output.to_bytes());  
-        let random_str = &random_num[start..end];
-        let vrf_sampler = VRFSampler::new(req.vrf_precision * 4);
-        let random_bigint = vrf_sampler.hex_to_biguint(random_str);
-        let selected = vrf_sampler.meets_threshold(&random_bigint, &BigUint::from(req.vrf_threshold));
+        let sortition = sortition::select(&output, &req.selection()?)?;
         Ok(VRFReply {
-            selected,
+            selected: sortition.selected,
             vrf_prompt_hash: req.vrf_prompt_hash,
-            vrf_random_value: random_num,
-            vrf_verify_pubkey: hex::encode(public_key.as_bytes()),
+            vrf_random_value: sortition.random_value,
+            vrf_verify_pubkey: key.public_key_hex(),
             vrf_proof: hex::encode(proof.to_bytes()),
+            vrf_batchable_proof: hex::encode(batchable.to_bytes()),
         })
     }
-    pub fn run_llm_task(req: PromptReq) -> Result<String, anyhow::Error> {
-        // llama format
-        let params = LlamaParams::default();
+    pub fn run_llm_task(req: PromptReq, model: &LlamaModel) -> Result<String, anyhow::Error> {
+        NitroEnclavesLlm::run_llm_task_with(req, model, |_| Ok(()))
+    }
 
-        // Create a model from anything that implements `AsRef<Path>`:
-        let model = LlamaModel::load_from_file(req.model_name.clone(), params)
-            .expect("Could not load model");
+    /// Runs the completion, passing every decoded token to `on_token` as soon as it is out.
+    ///
+    /// Given the same model, prompt and sampling with a resolved seed, the answer is the same.
+    pub fn run_llm_task_with(
+        mut req: PromptReq,
+        model: &LlamaModel,
+        mut on_token: impl FnMut(&str) -> Result<(), anyhow::Error>,
+    ) -> Result<String, anyhow::Error> {
+        let seed = req.sampling.resolve_seed();
         let cpu_nums = machine_used().1;
         let session_params = SessionParams {
+            seed,
             n_ctx: 4096,
             n_batch: 2048,
             n_ubatch: 512,
@@ -160,31 +97,21 @@ impl NitroEnclavesLlm {
 
         // A `LlamaModel` holds the weights shared across many _sessions_; while your model may be
         // several gigabytes large, a session is typically a few dozen to a hundred megabytes!
-        let mut ctx = model
-            .create_session(session_params)
-            .expect("Failed to create session");
-
-        // You can feed anything that implements `AsRef<[u8]>` into the model's context.
-        ctx.advance_context(req.prompt).unwrap();
+        let mut ctx = model.create_session(session_params)?;
+
+        if req.messages.is_empty() {
+            // You can feed anything that implements `AsRef<[u8]>` into the model's context.
+            ctx.advance_context(req.prompt)?;
+        } else {
+            // the markers of a rendered chat are special tokens, not text
+            let tokens = model.tokenize_bytes(&req.prompt, true, true)?;
+            ctx.advance_context_with_tokens(&tokens)?;
+        }
 
         // LLMs are typically used to predict the next word in a sequence. Let's generate some tokens!
         let mut decoded_tokens = 0;
 
-        let sampler_stages = vec![
-            SamplerStage::RepetitionPenalty {
-                repetition_penalty: 1.0,
-                frequency_penalty: 0.0,
-                presence_penalty: 0.0,
-                last_n: 64,
-            },
-            SamplerStage::TopK(40),
-            SamplerStage::TopP(req.top_p), // 0.95
-            SamplerStage::MinP(0.05),
-            SamplerStage::Typical(1.0),
-            SamplerStage::Temperature(req.temperature),
-        ];
-
-        let sampler = StandardSampler::new_mirostat_v2(sampler_stages, 0, 0.1, 5.0);
+        let sampler = standard_sampler(&req.sampling, seed);
 
         // `ctx.start_completing_with` creates a worker thread that generates tokens. When the completion
         // handle is dropped, tokens stop generating!
@@ -193,6 +120,7 @@ impl NitroEnclavesLlm {
             .into_strings();
 
         let mut answer = String::new();
+        let mut released = 0;
         for completion in completions {
             answer.push_str(&completion);
             // print!("{completion}");
@@ -200,48 +128,122 @@ impl NitroEnclavesLlm {
 
             decoded_tokens += 1;
 
+            let (end, stopped) = stop_point(&answer, &req.sampling.stop);
+            if end > released {
+                on_token(&answer[released..end])?;
+                released = end;
+            }
+            if stopped {
+                answer.truncate(end);
+                break;
+            }
             if decoded_tokens > req.n_predict {
                 break;
             }
         }
+        // held back for a stop sequence that never came
+        if released < answer.len() {
+            on_token(&answer[released..])?;
+        }
 
         Ok(answer)
     }
 
-    pub fn handle_prompt(req: PromptReq, nsm: Arc<NitroSecure>, write_sender: UnboundedSender<Vec<u8>>) -> Result<(), anyhow::Error> {
-        let mut answer = String::new();
-        let mut document = Vec::<u8>::new();
-        let start = Instant::now();
-        let vrf = NitroEnclavesLlm::run_vrf(req.clone())?;
-        if vrf.selected {
-            answer = NitroEnclavesLlm::run_llm_task(req.clone())?;
-            let user_data = answer.sha256().to_fixed_bytes().to_vec();
-            document = nsm.process_attestation(user_data)?;
+    /// Replies `TEEResp::Failed` for a prompt that could not be answered, so that its waiter
+    /// does not wait until it times out.
+    fn reply_failed(request_id: String, result: Result<(), anyhow::Error>, write_sender: &UnboundedSender<Vec<u8>>) -> Result<(), anyhow::Error> {
+        if let Err(err) = &result {
+            let resp = TEEResp::Failed(FailedResp {
+                request_id,
+                error: err.to_string(),
+            });
+            write_sender.send(bincode::options().serialize(&resp)?)?;
         }
-        let duration = start.elapsed();
-        // println!("\n\n Duration passed: {:?}", duration);
-        // let _ = io::stdout().flush();
-        
-        let answer_doc = TEEResp::AnswerResp(AnswerResp {
-            request_id: req.request_id,
-            model_name: req.model_name,
-            prompt: req.prompt.clone(),
-            answer,
-            elapsed: duration.as_secs(),
-            document: Payload(document),
-            selected: vrf.selected,
-            vrf_prompt_hash: vrf.vrf_prompt_hash,
-            vrf_random_value: vrf.vrf_random_value,
-            vrf_verify_pubkey: vrf.vrf_verify_pubkey,
-            vrf_proof: vrf.vrf_proof,
-        });
+        result
+    }
 
-        let buf = bincode::options().serialize(&answer_doc)?;
-        write_sender.send(buf)?;
-        Ok(())
+    pub async fn handle_prompt(mut req: PromptReq, models: &ModelCache, vrf_key: &VrfKey, nsm: Arc<dyn SecureModule>, write_sender: UnboundedSender<Vec<u8>>) -> Result<(), anyhow::Error> {
+        let request_id = req.request_id.clone();
+        let result = async {
+            let start = Instant::now();
+            req.validate()?;
+            req.sampling.resolve_seed();
+            let vrf = NitroEnclavesLlm::run_vrf(req.clone(), vrf_key)?;
+            let mut answer = AnswerResp::new(&req, vrf);
+            if answer.selected {
+                let (model, info) = models.get(&req.model_name).await?;
+                req.render(&info)?;
+                answer.prompt = req.prompt.clone();
+                answer.chat_template = req.chat_template;
+                answer.model_hash = info.hash;
+                answer.answer = NitroEnclavesLlm::run_llm_task(req.clone(), &model)?;
+                let user_data = answer.commitment().digest().to_vec();
+                answer.document = Payload(nsm.process_attestation(user_data)?);
+            }
+            let duration = start.elapsed();
+            // println!("\n\n Duration passed: {:?}", duration);
+            // let _ = io::stdout().flush();
+            answer.elapsed = duration.as_secs();
+
+            let answer_doc = TEEResp::AnswerResp(answer);
+
+            let buf = bincode::options().serialize(&answer_doc)?;
+            write_sender.send(buf)?;
+            Ok(())
+        }
+        .await;
+        NitroEnclavesLlm::reply_failed(request_id, result, &write_sender)
+    }
+
+    pub async fn handle_stream_prompt(mut req: PromptReq, models: &ModelCache, vrf_key: &VrfKey, nsm: Arc<dyn SecureModule>, write_sender: UnboundedSender<Vec<u8>>) -> Result<(), anyhow::Error> {
+        let request_id = req.request_id.clone();
+        let result = async {
+            let mut tokens = Vec::<String>::new();
+            let start = Instant::now();
+            req.validate()?;
+            req.sampling.resolve_seed();
+            let vrf = NitroEnclavesLlm::run_vrf(req.clone(), vrf_key)?;
+            let mut end = StreamEnd {
+                chunks: 0,
+                answer: AnswerResp::new(&req, vrf),
+                transcript_hash: String::new(),
+            };
+            if end.answer.selected {
+                let (model, info) = models.get(&req.model_name).await?;
+                req.render(&info)?;
+                end.answer.prompt = req.prompt.clone();
+                end.answer.chat_template = req.chat_template;
+                end.answer.model_hash = info.hash;
+                NitroEnclavesLlm::run_llm_task_with(req.clone(), &model, |token| {
+                    let chunk = TEEResp::TokenChunk(TokenChunk {
+                        request_id: req.request_id.clone(),
+                        seq: tokens.len() as _,
+                        token: token.to_owned(),
+                    });
+                    write_sender.send(bincode::options().serialize(&chunk)?)?;
+                    tokens.push(token.to_owned());
+                    Ok(())
+                })?;
+                end.chunks = tokens.len() as _;
+                end.answer.answer = tokens.concat();
+                let commitment = end.commitment(&tokens);
+                end.answer.document = Payload(nsm.process_attestation(commitment.digest().to_vec())?);
+                end.transcript_hash = commitment.transcript_hash;
+            }
+            end.answer.elapsed = start.elapsed().as_secs();
+
+            let end = TEEResp::StreamEnd(end);
+
+            let buf = bincode::options().serialize(&end)?;
+            write_sender.send(buf)?;
+            Ok(())
+        }
+        .await;
+        // ends the stream, also when some chunks are out already
+        NitroEnclavesLlm::reply_failed(request_id, result, &write_sender)
     }
 
-    pub fn handle_ping(req: String, write_sender: UnboundedSender<Vec<u8>>) -> Result<(), anyhow::Error> {
+    pub fn handle_ping(req: String, models: &ModelCache, write_sender: UnboundedSender<Vec<u8>>) -> Result<(), anyhow::Error> {
         let status = machine_used();
         let req = TEEResp::Ping(PingResp {
             echo: req,
@@ -249,6 +251,7 @@ impl NitroEnclavesLlm {
             cpu_nums: status.1,
             mem_total: status.2,
             mem_used: status.3,
+            models: models.resident(),
         });
 
         let buf = bincode::options().serialize(&req)?;
@@ -256,8 +259,51 @@ impl NitroEnclavesLlm {
         Ok(())
     }
 
+    pub async fn handle_models(req: TEEReq, models: &ModelCache, write_sender: UnboundedSender<Vec<u8>>) -> Result<(), anyhow::Error> {
+        let error = match req {
+            TEEReq::PreloadModel(name) => models.get(&name).await.err().map(|err| err.to_string()),
+            TEEReq::EvictModel(name) => {
+                models.evict(&name);
+                None
+            }
+            _ => None,
+        };
+        let resp = TEEResp::Models(ModelsResp {
+            models: models.resident(),
+            capacity: models.capacity(),
+            error,
+        });
+
+        let buf = bincode::options().serialize(&resp)?;
+        write_sender.send(buf)?;
+        Ok(())
+    }
+
+    pub fn handle_vrf_key(vrf_key: &VrfKey, nsm: Arc<dyn SecureModule>, write_sender: UnboundedSender<Vec<u8>>) -> Result<(), anyhow::Error> {
+        // attested afresh every time, the certificates of a document do not last long
+        let document = nsm.process_key_attestation(
+            VRF_KEY_ATTESTATION.to_vec(),
+            vrf_key.public_key.as_bytes().to_vec(),
+        )?;
+        let resp = TEEResp::VrfKey(VrfKeyResp {
+            public_key: vrf_key.public_key_hex(),
+            document: Payload(document),
+        });
+
+        let buf = bincode::options().serialize(&resp)?;
+        write_sender.send(buf)?;
+        Ok(())
+    }
+
     pub fn router() -> HandleFn {
-        Arc::new(|buf, nsm, pcrs, write_sender| {
+        NitroEnclavesLlm::router_with(Arc::new(ModelCache::for_enclave()), Arc::new(VrfKey::generate()))
+    }
+
+    /// Router that serves prompts with the models of `models`, and proves with `vrf_key`.
+    pub fn router_with(models: Arc<ModelCache>, vrf_key: Arc<VrfKey>) -> HandleFn {
+        Arc::new(move |buf, nsm, pcrs, write_sender| {
+            let models = models.clone();
+            let vrf_key = vrf_key.clone();
             Box::pin(async move {
                 if let Err(err) = async {
                     let req: TEEReq = bincode::options().deserialize::<TEEReq>(&buf)?;
@@ -265,10 +311,21 @@ impl NitroEnclavesLlm {
                     anyhow::ensure!(true);
                     match req {
                         TEEReq::Ping(req) => {
-                            NitroEnclavesLlm::handle_ping(req, write_sender)
+                            NitroEnclavesLlm::handle_ping(req, &models, write_sender)
                         },
                         TEEReq::PromptReq(req) => {
-                            NitroEnclavesLlm::handle_prompt(req, nsm, write_sender)
+                            NitroEnclavesLlm::handle_prompt(req, &models, &vrf_key, nsm, write_sender).await
+                        },
+                        TEEReq::StreamPromptReq(req) => {
+                            NitroEnclavesLlm::handle_stream_prompt(req, &models, &vrf_key, nsm, write_sender).await
+                        },
+                        req @ (TEEReq::ListModels
+                        | TEEReq::PreloadModel(_)
+                        | TEEReq::EvictModel(_)) => {
+                            NitroEnclavesLlm::handle_models(req, &models, write_sender).await
+                        },
+                        TEEReq::VrfKey => {
+                            NitroEnclavesLlm::handle_vrf_key(&vrf_key, nsm, write_sender)
                         },
                     }
                 }
@@ -280,122 +337,343 @@ impl NitroEnclavesLlm {
             })
         })
     }
+}
 
-    pub async fn run(port: u32) -> anyhow::Result<()> {
+#[cfg(feature = "nitro-enclaves")]
+impl NitroEnclavesLlm {
+    pub async fn run(addr: Address) -> anyhow::Result<()> {
         let handler: HandleFn = NitroEnclavesLlm::router();
 
-        NitroSecure::run(port, handler).await
+        NitroSecure::run(addr, handler).await
+    }
+}
+
+#[cfg(not(feature = "nitro-enclaves"))]
+impl NitroEnclavesLlm {
+    /// Outside of an enclave, answers are attested by a throwaway `MockSecureModule`.
+    pub async fn run(addr: Address) -> anyhow::Result<()> {
+        warn!("nitro-enclaves feature is disabled, attesting with a mock secure module");
+        let nsm = Arc::new(common::mock_secure::MockSecureModule::new()?);
+        common::nitro_secure::serve(nsm, addr, NitroEnclavesLlm::router()).await
     }
 }
 
 pub async fn nitro_enclaves_portal_session(
-    cid: u32,
-    port: u32,
-    mut events: UnboundedReceiver<TEEReq>,
+    addr: Address,
+    events: UnboundedReceiver<TEEReq>,
     sender: UnboundedSender<TEEResp>,
 ) -> anyhow::Result<()> {
-    use std::os::fd::AsRawFd;
-
-    use bincode::Options;
-    use nix::sys::socket::{connect, socket, AddressFamily, SockFlag, SockType, VsockAddr};
-    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
-
-    let fd = socket(
-        AddressFamily::Vsock,
-        SockType::Stream,
-        SockFlag::empty(),
-        None,
-    )?;
-    // this one is blocking, but should be instant, hopefully
-    {
-        let _span = tracing::debug_span!("connect").entered();
-        connect(fd.as_raw_fd(), &VsockAddr::new(cid, port))?
+    transport::session(transport::connect(&addr).await?, events, sender).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chat::{self, ChatMessage},
+        sampling::{SamplerOptions, SamplingParams},
+    };
+    use common::mock_secure::MockSecureModule;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    async fn route(nsm: Arc<dyn SecureModule>, req: TEEReq) -> anyhow::Result<TEEResp> {
+        route_with(&NitroEnclavesLlm::router(), nsm, req).await
     }
-    let stream = std::os::unix::net::UnixStream::from(fd);
-    stream.set_nonblocking(true)?;
-    let stream = tokio::net::UnixStream::from_std(stream)?;
-    let (mut read_half, mut write_half) = stream.into_split();
-    let write_session = tokio::spawn(async move {
-        while let Some(prompt) = events.recv().await {
-            let buf = bincode::options().serialize(&prompt)?;
-            write_half.write_u64_le(buf.len() as _).await?;
-            write_half.write_all(&buf).await?;
-        }
-        anyhow::Ok(())
-    });
-    let read_session = tokio::spawn(async move {
-        loop {
-            let len = read_half.read_u64_le().await?;
-            let mut buf = vec![0; len as _];
-            read_half.read_exact(&mut buf).await?;
-            sender.send(bincode::options().deserialize(&buf)?)?
-        }
-        #[allow(unreachable_code)] // for type hinting
-        anyhow::Ok(())
-    });
-    tokio::select! {
-        result = write_session => return result?,
-        result = read_session => result??
+
+    async fn route_with(router: &HandleFn, nsm: Arc<dyn SecureModule>, req: TEEReq) -> anyhow::Result<TEEResp> {
+        let pcrs = [0, 1, 2].map(|i| nsm.describe_pcr(i).unwrap());
+        let (write_sender, mut write_receiver) = unbounded_channel();
+        let buf = bincode::options().serialize(&req)?;
+        router(buf, nsm, pcrs, write_sender).await?;
+        let Some(buf) = write_receiver.recv().await else {
+            anyhow::bail!("missing reply")
+        };
+        Ok(bincode::options().deserialize(&buf)?)
     }
-    anyhow::bail!("unreachable")
-}
 
-pub fn try_connection(cid: u32, port: u32) -> anyhow::Result<tokio::net::UnixStream> {
-    use nix::sys::socket::{connect, socket, AddressFamily, SockFlag, SockType, VsockAddr};
-    use std::os::fd::AsRawFd;
+    #[tokio::test]
+    async fn ping() -> anyhow::Result<()> {
+        let nsm = Arc::new(MockSecureModule::new()?);
+        let TEEResp::Ping(pong) = route(nsm, TEEReq::Ping("hello".into())).await? else {
+            anyhow::bail!("unexpected reply")
+        };
+        assert_eq!(pong.echo, "hello");
+        Ok(())
+    }
 
-    let fd = socket(
-        AddressFamily::Vsock,
-        SockType::Stream,
-        SockFlag::empty(),
-        None,
-    )?;
+    #[tokio::test]
+    async fn prompt_not_selected() -> anyhow::Result<()> {
+        let nsm = Arc::new(MockSecureModule::new()?);
+        let req = TEEReq::PromptReq(PromptReq {
+            request_id: "1".into(),
+            model_name: "./missing.gguf".into(),
+            prompt: "How to combine AI and blockchain?".into(),
+            messages: vec![],
+            chat_template: None,
+            sampling: SamplingParams::default(),
+            n_predict: 16,
+            vrf_prompt_hash: "hash".into(),
+            // nothing is below zero, so the model is never loaded
+            vrf_threshold: 0,
+            vrf_precision: 6,
+            vrf_selection: None,
+            nonce: String::new(),
+        });
+        let TEEResp::AnswerResp(answer) = route(nsm.clone(), req).await? else {
+            anyhow::bail!("unexpected reply")
+        };
+        assert!(!answer.selected);
+        // the seed in use is echoed, and replayed
+        assert!(answer.sampling.seed.is_some());
+        let always = sortition::Selection::fraction(1, 1)?;
+        let replay = answer.replay_req("2".into(), always);
+        assert_eq!(replay.sampling, answer.sampling);
+        assert_eq!(replay.selection()?, always);
+        assert!(answer.verify_inference_with(nsm.root_certificate())?.is_none());
+        Ok(())
+    }
 
-    {
-        let _span = tracing::debug_span!("connect").entered();
-        connect(fd.as_raw_fd(), &VsockAddr::new(cid, port))?
+    #[tokio::test]
+    async fn fail_missing_model() -> anyhow::Result<()> {
+        let nsm = Arc::new(MockSecureModule::new()?);
+        let prompt = PromptReq {
+            request_id: "1".into(),
+            model_name: "./missing.gguf".into(),
+            prompt: "How to combine AI and blockchain?".into(),
+            messages: vec![],
+            chat_template: None,
+            sampling: SamplingParams::default(),
+            n_predict: 16,
+            vrf_prompt_hash: "hash".into(),
+            // everything is below all of the space, so the model is always loaded
+            vrf_threshold: 1 << 24,
+            vrf_precision: 6,
+            vrf_selection: None,
+            nonce: String::new(),
+        };
+        for req in [TEEReq::PromptReq(prompt.clone()), TEEReq::StreamPromptReq(prompt)] {
+            let TEEResp::Failed(failed) = route(nsm.clone(), req).await? else {
+                anyhow::bail!("unexpected reply")
+            };
+            assert_eq!(failed.request_id, "1");
+        }
+        Ok(())
     }
 
-    let stream = std::os::unix::net::UnixStream::from(fd);
-    stream.set_nonblocking(true)?;
+    #[tokio::test]
+    async fn reject_invalid_prompt() -> anyhow::Result<()> {
+        let nsm = Arc::new(MockSecureModule::new()?);
+        let (write_sender, mut write_receiver) = unbounded_channel();
+        let req = PromptReq {
+            request_id: "1".into(),
+            model_name: "./missing.gguf".into(),
+            prompt: "How to combine AI and blockchain?".into(),
+            messages: vec![],
+            chat_template: None,
+            sampling: SamplerOptions {
+                top_k: -1,
+                ..Default::default()
+            }
+            .to_params(None),
+            n_predict: 16,
+            vrf_prompt_hash: "hash".into(),
+            vrf_threshold: 0,
+            vrf_precision: 6,
+            vrf_selection: None,
+            nonce: String::new(),
+        };
+        let models = ModelCache::for_enclave();
+        let key = VrfKey::generate();
+        assert!(NitroEnclavesLlm::handle_prompt(req.clone(), &models, &key, nsm.clone(), write_sender.clone()).await.is_err());
+        assert!(NitroEnclavesLlm::handle_stream_prompt(req.clone(), &models, &key, nsm.clone(), write_sender.clone()).await.is_err());
+        // either a prompt or messages
+        let req = PromptReq {
+            sampling: SamplingParams::default(),
+            messages: vec![ChatMessage {
+                role: chat::Role::User,
+                content: "How to combine AI and blockchain?".into(),
+            }],
+            ..req
+        };
+        assert!(NitroEnclavesLlm::handle_prompt(req.clone(), &models, &key, nsm.clone(), write_sender.clone()).await.is_err());
+        assert!(NitroEnclavesLlm::handle_prompt(PromptReq { prompt: String::new(), ..req }, &models, &key, nsm, write_sender).await.is_ok());
+        // the rejected ones are failed, only the last one is answered
+        let replies = std::iter::from_fn(|| write_receiver.try_recv().ok())
+            .map(|buf| bincode::options().deserialize(&buf))
+            .collect::<Result<Vec<TEEResp>, _>>()?;
+        assert!(matches!(
+            &replies[..],
+            [TEEResp::Failed(_), TEEResp::Failed(_), TEEResp::Failed(_), TEEResp::AnswerResp(_)]
+        ));
+        Ok(())
+    }
 
-    let stream = tokio::net::UnixStream::from_std(stream)?;
-    Ok(stream)
-}
+    #[test]
+    fn verify_mock_attested_answer() -> anyhow::Result<()> {
+        let nsm = MockSecureModule::new()?;
+        let mut answer = AnswerResp {
+            request_id: "1".into(),
+            answer: "42".into(),
+            selected: true,
+            nonce: "nonce".into(),
+            ..Default::default()
+        };
+        answer.document = Payload(nsm.process_attestation(answer.commitment().digest().to_vec())?);
+        anyhow::ensure!(answer.verify_inference_with(nsm.root_certificate())?.is_some());
+        // the document is not transferable to another request
+        let mut other = answer.clone();
+        other.request_id = "2".into();
+        assert!(other.verify_inference_with(nsm.root_certificate()).is_err());
+        let mut other = answer.clone();
+        other.nonce = String::new();
+        assert!(other.verify_inference_with(nsm.root_certificate()).is_err());
+        // nothing is attested for an enclave not selected, and it has no answer
+        let mut other = answer.clone();
+        other.selected = false;
+        assert!(other.verify_inference_with(nsm.root_certificate()).is_err());
+        other.answer = String::new();
+        assert!(other.verify_inference_with(nsm.root_certificate())?.is_none());
+        answer.answer = "43".into();
+        assert!(answer.verify_inference_with(nsm.root_certificate()).is_err());
+        Ok(())
+    }
 
-pub async fn tee_start_listening(
-    stream: tokio::net::UnixStream,
-    mut events: UnboundedReceiver<TEEReq>,
-    sender: UnboundedSender<TEEResp>,
-) -> anyhow::Result<()> {
-    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    #[tokio::test]
+    async fn preload_missing_model() -> anyhow::Result<()> {
+        let nsm = Arc::new(MockSecureModule::new()?);
+        let req = TEEReq::PreloadModel("./missing.gguf".into());
+        let TEEResp::Models(resp) = route(nsm.clone(), req).await? else {
+            anyhow::bail!("unexpected reply")
+        };
+        assert!(resp.error.is_some());
+        assert!(resp.models.is_empty());
+        let TEEResp::Ping(pong) = route(nsm, TEEReq::Ping("hello".into())).await? else {
+            anyhow::bail!("unexpected reply")
+        };
+        assert!(pong.models.is_empty());
+        Ok(())
+    }
 
-    let (mut read_half, mut write_half) = stream.into_split();
+    #[tokio::test]
+    async fn vrf_key_per_boot() -> anyhow::Result<()> {
+        let nsm = Arc::new(MockSecureModule::new()?);
+        let router = NitroEnclavesLlm::router();
+        let prompt = |request_id: &str, vrf_prompt_hash: &str| {
+            TEEReq::PromptReq(PromptReq {
+                request_id: request_id.into(),
+                model_name: "./missing.gguf".into(),
+                prompt: "How to combine AI and blockchain?".into(),
+                messages: vec![],
+                chat_template: None,
+                sampling: SamplingParams::default(),
+                n_predict: 16,
+                vrf_prompt_hash: vrf_prompt_hash.into(),
+                vrf_threshold: 0,
+                vrf_precision: 6,
+                vrf_selection: None,
+                nonce: String::new(),
+            })
+        };
+        let TEEResp::AnswerResp(first) = route_with(&router, nsm.clone(), prompt("1", "hash")).await? else {
+            anyhow::bail!("unexpected reply")
+        };
+        let TEEResp::AnswerResp(second) = route_with(&router, nsm.clone(), prompt("2", "hash")).await? else {
+            anyhow::bail!("unexpected reply")
+        };
+        // same key and prompt hash, same output, no second chance at being selected
+        assert_eq!(first.vrf_verify_pubkey, second.vrf_verify_pubkey);
+        assert_eq!(first.vrf_random_value, second.vrf_random_value);
+
+        let never = sortition::Selection::threshold(0, 6)?;
+        first.verify_vrf(Some(&never))?;
+        let mut forged = first.clone();
+        forged.selected = true;
+        assert!(forged.verify_vrf(None).is_ok());
+        assert!(forged.verify_vrf(Some(&never)).is_err());
+        forged.vrf_prompt_hash = "other".into();
+        assert!(forged.verify_vrf(None).is_err());
+
+        // the batchable proof of the same evaluation
+        let material = |err: vrf::traits::CryptoMaterialError| anyhow::anyhow!("{err}");
+        let public_key = VRFPublicKey::try_from(&hex::decode(&first.vrf_verify_pubkey)?[..])
+            .map_err(material)?;
+        let batchable =
+            vrf::batch::BatchableProof::try_from(&hex::decode(&first.vrf_batchable_proof)?[..])
+                .map_err(material)?;
+        batchable.verify(&public_key, b"hash")?;
+        let proof = batchable.to_proof(&public_key, b"hash");
+        assert_eq!(hex::encode(proof.to_bytes()), first.vrf_proof);
+
+        let TEEResp::VrfKey(key) = route_with(&router, nsm.clone(), TEEReq::VrfKey).await? else {
+            anyhow::bail!("unexpected reply")
+        };
+        assert_eq!(key.public_key, first.vrf_verify_pubkey);
+        let doc = key.verify_with(nsm.root_certificate())?;
+        assert_eq!(doc.user_data.as_deref().map(|user_data| &user_data[..]), Some(VRF_KEY_ATTESTATION));
 
-    let write_session = tokio::spawn(async move {
-        while let Some(prompt) = events.recv().await {
-            let buf = bincode::options().serialize(&prompt)?;
-            write_half.write_u64_le(buf.len() as _).await?;
-            write_half.write_all(&buf).await?;
-        }
-        anyhow::Ok(())
-    });
-
-    let read_session = tokio::spawn(async move {
-        loop {
-            let len = read_half.read_u64_le().await?;
-            let mut buf = vec![0; len as _];
-            read_half.read_exact(&mut buf).await?;
-            sender.send(bincode::options().deserialize(&buf)?)?
-        }
-        #[allow(unreachable_code)] // for type hinting
-        anyhow::Ok(())
-    });
+        // another boot
+        let TEEResp::AnswerResp(other) = route(nsm.clone(), prompt("3", "hash")).await? else {
+            anyhow::bail!("unexpected reply")
+        };
+        assert_ne!(other.vrf_verify_pubkey, first.vrf_verify_pubkey);
+        let forged = VrfKeyResp {
+            public_key: other.vrf_verify_pubkey,
+            ..key
+        };
+        assert!(forged.verify_with(nsm.root_certificate()).is_err());
+        // an answer document does not attest a key
+        let answer = Payload(nsm.process_key_attestation(b"answer".to_vec(), hex::decode(&forged.public_key)?)?);
+        assert!(VrfKeyResp { document: answer, ..forged }.verify_with(nsm.root_certificate()).is_err());
+        Ok(())
+    }
 
-    tokio::select! {
-        result = write_session => return result?,
-        result = read_session => result??
+    #[tokio::test]
+    async fn stream_not_selected() -> anyhow::Result<()> {
+        let nsm = Arc::new(MockSecureModule::new()?);
+        let req = TEEReq::StreamPromptReq(PromptReq {
+            request_id: "1".into(),
+            model_name: "./missing.gguf".into(),
+            prompt: "How to combine AI and blockchain?".into(),
+            messages: vec![],
+            chat_template: None,
+            sampling: SamplingParams::default(),
+            n_predict: 16,
+            vrf_prompt_hash: "hash".into(),
+            vrf_threshold: 0,
+            vrf_precision: 6,
+            vrf_selection: None,
+            nonce: String::new(),
+        });
+        let TEEResp::StreamEnd(end) = route(nsm.clone(), req).await? else {
+            anyhow::bail!("unexpected reply")
+        };
+        assert_eq!(end.chunks, 0);
+        assert!(end.verify_transcript_with(&[], nsm.root_certificate())?.is_none());
+        Ok(())
     }
 
-    anyhow::bail!("unreachable")
+    #[test]
+    fn verify_mock_attested_transcript() -> anyhow::Result<()> {
+        let nsm = MockSecureModule::new()?;
+        let tokens = ["4".to_string(), "2".to_string()];
+        let mut end = StreamEnd {
+            chunks: 2,
+            answer: AnswerResp {
+                request_id: "1".into(),
+                answer: "42".into(),
+                selected: true,
+                ..Default::default()
+            },
+            transcript_hash: String::new(),
+        };
+        let user_data = end.commitment(&tokens).digest().to_vec();
+        end.answer.document = Payload(nsm.process_attestation(user_data)?);
+        // attested for the stream, not for a plain answer
+        assert!(end.answer.verify_inference_with(nsm.root_certificate()).is_err());
+        anyhow::ensure!(end.verify_transcript_with(&tokens, nsm.root_certificate())?.is_some());
+        // same answer, different chunking
+        assert!(end.verify_transcript_with(&["42".into(), "".into()], nsm.root_certificate()).is_err());
+        assert!(end.verify_transcript_with(&tokens[..1], nsm.root_certificate()).is_err());
+        Ok(())
+    }
 }
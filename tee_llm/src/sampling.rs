@@ -0,0 +1,38 @@
+use llama_cpp::standard_sampler::{SamplerStage, StandardSampler};
+
+pub use llm_types::sampling::*;
+
+/// Builds the sampler of `params`, with `seed` as resolved before.
+pub fn standard_sampler(params: &SamplingParams, seed: u32) -> StandardSampler {
+    let stages = params.stages.iter().cloned().map(sampler_stage).collect();
+    match params.sampler {
+        Sampler::MirostatV2 { tau, eta } => {
+            StandardSampler::new_mirostat_v2(stages, seed, tau, eta)
+        }
+        Sampler::Softmax { min_keep } => StandardSampler::new_softmax(stages, min_keep),
+        // stages make no difference to the most likely token
+        Sampler::Greedy => StandardSampler::new_greedy(),
+    }
+}
+
+fn sampler_stage(stage: Stage) -> SamplerStage {
+    match stage {
+        Stage::RepetitionPenalty {
+            repetition_penalty,
+            frequency_penalty,
+            presence_penalty,
+            last_n,
+        } => SamplerStage::RepetitionPenalty {
+            repetition_penalty,
+            frequency_penalty,
+            presence_penalty,
+            last_n,
+        },
+        Stage::Temperature(t) => SamplerStage::Temperature(t),
+        Stage::TopP(p) => SamplerStage::TopP(p),
+        Stage::MinP(p) => SamplerStage::MinP(p),
+        Stage::TopK(k) => SamplerStage::TopK(k),
+        Stage::Typical(p) => SamplerStage::Typical(p),
+        Stage::TailFree(z) => SamplerStage::TailFree(z),
+    }
+}
@@ -5,8 +5,12 @@ use std::{
     time::Duration,
 };
 
-use common::ordinary_clock::OrdinaryClock;
-use tee_vlc::nitro_clock::{nitro_enclaves_portal_session, NitroEnclavesClock, Update, UpdateOk};
+use bincode::Options as _;
+use common::{ordinary_clock::OrdinaryClock, transport::Address};
+use tee_vlc::nitro_clock::{
+    nitro_enclaves_portal_session, ClockReq, ClockResp, CompactClock, DeltaUpdate,
+    NitroEnclavesClock, Update, UpdateOk,
+};
 use tokio::{
     sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
     time::{sleep, timeout, Instant},
@@ -24,10 +28,14 @@ async fn main() -> anyhow::Result<()> {
     } else {
         None
     };
+    let addr = match args.get(2) {
+        Some(addr) => addr.parse()?,
+        None => Address::vsock(CID, 5006),
+    };
 
     let run_nitro_client = {
         let (update_sender, update_receiver) = unbounded_channel();
-        let (update_ok_sender, mut update_ok_receiver) = unbounded_channel::<UpdateOk<_>>();
+        let (update_ok_sender, mut update_ok_receiver) = unbounded_channel::<ClockResp<_>>();
         tokio::spawn({
             let update_sender = update_sender.clone();
             async move {
@@ -37,8 +45,7 @@ async fn main() -> anyhow::Result<()> {
         });
         (
             tokio::spawn(nitro_enclaves_portal_session(
-                CID,
-                5006,
+                addr,
                 update_receiver,
                 update_ok_sender,
             )),
@@ -86,7 +93,13 @@ async fn main() -> anyhow::Result<()> {
                         )
                         .await?
                     }
-                    println!("{lines}")
+                    println!("{lines}");
+
+                    println!("key, encoding, request_bytes, verify_proof_tee, total_in_tee, net_round");
+                    for size in (0..=16).step_by(2).map(|n| 1 << n) {
+                        delta_bench_session(size, &update_sender, &mut update_ok_receiver, verify)
+                            .await?
+                    }
                 }
 
                 anyhow::Ok(())
@@ -105,11 +118,23 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// The next update replied, an error if it is rejected.
+async fn next_update<C>(
+    update_ok_receiver: &mut UnboundedReceiver<ClockResp<C>>,
+) -> anyhow::Result<UpdateOk<C>> {
+    match update_ok_receiver.recv().await {
+        Some(ClockResp::Updated(update_ok)) => Ok(update_ok),
+        Some(ClockResp::Rejected(err)) => anyhow::bail!("update rejected, {err}"),
+        Some(ClockResp::Policy(seq)) => anyhow::bail!("unexpected policy {seq} applied"),
+        None => anyhow::bail!("missing UpdateOk"),
+    }
+}
+
 async fn bench_session<C: TryFrom<OrdinaryClock> + Clone + Send + Sync + 'static>(
     size: usize,
     num_merged: usize,
-    update_sender: &UnboundedSender<Update<C>>,
-    update_ok_receiver: &mut UnboundedReceiver<UpdateOk<C>>,
+    update_sender: &UnboundedSender<ClockReq<C>>,
+    update_ok_receiver: &mut UnboundedReceiver<ClockResp<C>>,
     verify: impl Fn(C) -> anyhow::Result<()>,
     lines: &mut String,
 ) -> anyhow::Result<()>
@@ -119,10 +144,8 @@ where
     let clock =
         C::try_from(OrdinaryClock((0..size).map(|i| (i as _, 0)).collect())).map_err(Into::into)?;
     let start = Instant::now();
-    update_sender.send(Update(clock, Default::default(), 0))?;
-    let Some((_, clock, elapsed)) = update_ok_receiver.recv().await else {
-        anyhow::bail!("missing UpdateOk")
-    };
+    update_sender.send(Update(clock, Default::default(), 0).into())?;
+    let (_, clock, elapsed) = next_update(update_ok_receiver).await?;
     let net_round = start.elapsed();
     println!(
         "{size}, {num_merged}, {:?}, {:?}, {:?}, {:?}, {:?}, {:?}",
@@ -133,10 +156,8 @@ where
         sleep(Duration::from_millis(100)).await;
         let update = Update(clock.clone(), vec![clock.clone(); num_merged], 0);
         let start = Instant::now();
-        update_sender.send(update)?;
-        let Some((_, clock, elapsed_in_tee)) = update_ok_receiver.recv().await else {
-            anyhow::bail!("missing UpdateOk")
-        };
+        update_sender.send(update.into())?;
+        let (_, clock, elapsed_in_tee) = next_update(update_ok_receiver).await?;
         let elapsed = start.elapsed();
         // eprintln!("{size:8} {num_merged:3} {elapsed:?}");
         println!(
@@ -149,12 +170,52 @@ where
     Ok(())
 }
 
+/// Ticks of a clock of `size` keys sent in full, and as a delta from the clock the enclave
+/// replied last.
+async fn delta_bench_session(
+    size: usize,
+    update_sender: &UnboundedSender<ClockReq<NitroEnclavesClock>>,
+    update_ok_receiver: &mut UnboundedReceiver<ClockResp<NitroEnclavesClock>>,
+    verify: impl Fn(NitroEnclavesClock) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let clock = NitroEnclavesClock::try_from(OrdinaryClock((0..size).map(|i| (i as _, 0)).collect()))?;
+    update_sender.send(Update(clock, Default::default(), 0).into())?;
+    let (_, mut clock, _) = next_update(update_ok_receiver).await?;
+    for encoding in ["full", "delta"] {
+        for _ in 0..5 {
+            sleep(Duration::from_millis(100)).await;
+            let update = if encoding == "full" {
+                Update(clock.clone(), Default::default(), 0).into()
+            } else {
+                DeltaUpdate {
+                    prev: CompactClock::cached(&clock.plain),
+                    merged: Default::default(),
+                    id: 0,
+                }
+                .into()
+            };
+            let request_bytes = bincode::options().serialized_size(&update)?;
+            let start = Instant::now();
+            update_sender.send(update)?;
+            let (_, updated, elapsed_in_tee) = next_update(update_ok_receiver).await?;
+            let elapsed = start.elapsed();
+            println!(
+                "{size}, {encoding}, {request_bytes}, {:?}, {:?}, {:?}",
+                elapsed_in_tee[1], elapsed_in_tee[4], elapsed
+            );
+            verify(updated.clone())?;
+            clock = updated
+        }
+    }
+    Ok(())
+}
+
 async fn stress_bench_session<C: TryFrom<OrdinaryClock> + Clone + Send + Sync + 'static>(
     size: usize,
     num_merged: usize,
     num_concurrent: usize,
-    update_sender: &UnboundedSender<Update<C>>,
-    update_ok_receiver: &mut UnboundedReceiver<UpdateOk<C>>,
+    update_sender: &UnboundedSender<ClockReq<C>>,
+    update_ok_receiver: &mut UnboundedReceiver<ClockResp<C>>,
     lines: &mut String,
 ) -> anyhow::Result<()>
 where
@@ -163,14 +224,17 @@ where
     let clock =
         C::try_from(OrdinaryClock((0..size).map(|i| (i as _, 0)).collect())).map_err(Into::into)?;
     for i in 0..num_concurrent {
-        update_sender.send(Update(clock.clone(), Default::default(), i as _))?;
+        update_sender.send(Update(clock.clone(), Default::default(), i as _).into())?;
     }
     let mut count = 0;
     let close_loops_session = async {
-        while let Some((id, clock, _elapsed)) = update_ok_receiver.recv().await {
+        while let Some(resp) = update_ok_receiver.recv().await {
+            let ClockResp::Updated((id, clock, _elapsed)) = resp else {
+                anyhow::bail!("update rejected")
+            };
             count += 1;
             let update = Update(clock.clone(), vec![clock.clone(); num_merged], id);
-            update_sender.send(update)?
+            update_sender.send(update.into())?
         }
         anyhow::Ok(())
     };
@@ -1,6 +1,16 @@
-use tee_vlc::nitro_clock::NitroEnclavesClock;
+use tee_vlc::nitro_clock::{MergePolicy, NitroEnclavesClock};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    NitroEnclavesClock::run(5006).await
-}
\ No newline at end of file
+    // e.g. `unix:///tmp/vlc.sock` to run outside of an enclave
+    let addr = std::env::args()
+        .nth(1)
+        .unwrap_or("vsock://any:5006".into())
+        .parse()?;
+    // merges clocks of the same enclave image only, if no policy file is given
+    let policy = match std::env::args().nth(2) {
+        Some(path) => MergePolicy::load(path)?,
+        None => MergePolicy::default(),
+    };
+    NitroEnclavesClock::run(addr, policy).await
+}
@@ -1,12 +1,21 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
 use bincode::Options;
 // use std::io;
 // use std::io::Write;
 
 use common::{
-    crypto::core::DigestHash,
+    attestation::{verify_document, AttestationDoc},
+    clock_delta::{genesis_hash, ClockDelta},
+    crypto::canonical::{bincode_digest, CanonicalDigest as _},
     nitro_secure::{HandleFn, NitroSecureModule as NitroSecure},
-    ordinary_clock::{Clock, LamportClock, OrdinaryClock},
+    ordinary_clock::{Clock, KeyId, LamportClock, OrdinaryClock},
+    pcr_policy::PcrPolicy,
+    transport::{self, Address},
     types::Payload,
 };
 use derive_where::derive_where;
@@ -14,12 +23,278 @@ use serde::{Deserialize, Serialize};
 use tokio::{sync::mpsc::{UnboundedReceiver, UnboundedSender}, time::Instant};
 use tracing::*;
 
+use crate::{Clocked, Verify};
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Update<C>(pub C, pub Vec<C>, pub u64);
 
 // feel lazy to define event type for replying
 pub type UpdateOk<C> = (u64, C, Vec<Duration>);
 
+/// What the clock enclave replies, to every request.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClockResp<C> {
+    Updated(UpdateOk<C>),
+    /// The `seq` of the `PolicyUpdate` applied.
+    Policy(u64),
+    /// Why a request was rejected, e.g. a clock merged that is not trusted.
+    Rejected(String),
+}
+
+/// What the clock enclave is asked, see `ClockResp`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClockReq<C> {
+    Update(Update<C>),
+    Policy(PolicyUpdate),
+    Delta(DeltaUpdate),
+}
+
+impl<C> From<Update<C>> for ClockReq<C> {
+    fn from(update: Update<C>) -> Self {
+        Self::Update(update)
+    }
+}
+
+impl<C> From<DeltaUpdate> for ClockReq<C> {
+    fn from(update: DeltaUpdate) -> Self {
+        Self::Delta(update)
+    }
+}
+
+/// Clocks the enclave keeps for `DeltaUpdate`s to refer to, the latest it attested or verified.
+pub const CACHED_CLOCKS: usize = 64;
+
+/// An `Update` with its clocks as deltas from clocks the enclave has, see `common::clock_delta`.
+/// Rejected, as silently as any update, if one of the base clocks is not cached anymore.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeltaUpdate {
+    pub prev: CompactClock,
+    pub merged: Vec<CompactClock>,
+    pub id: u64,
+}
+
+/// A clock of a `DeltaUpdate`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompactClock {
+    pub delta: ClockDelta,
+    /// Not needed, and empty, if the clock is cached by the enclave.
+    pub document: Payload,
+}
+
+impl CompactClock {
+    /// A clock cached by the enclave, by hash only, e.g. the one it replied last.
+    pub fn cached(clock: &OrdinaryClock) -> Self {
+        Self {
+            delta: ClockDelta {
+                base: clock.canonical_digest(),
+                entries: Vec::new(),
+            },
+            document: Default::default(),
+        }
+    }
+
+    /// The changes of `clock` from `base`, a clock cached by the enclave, or all of it if it is
+    /// not an update of `base`.
+    pub fn delta(clock: &NitroEnclavesClock, base: &OrdinaryClock) -> Self {
+        Self {
+            delta: ClockDelta::new(&clock.plain, base)
+                .unwrap_or_else(|| ClockDelta::full(&clock.plain)),
+            document: clock.document.clone(),
+        }
+    }
+
+    pub fn full(clock: &NitroEnclavesClock) -> Self {
+        Self {
+            delta: ClockDelta::full(&clock.plain),
+            document: clock.document.clone(),
+        }
+    }
+}
+
+/// Trusted clocks by SHA-256, dropped oldest first.
+#[derive(Debug)]
+pub struct ClockCache {
+    capacity: usize,
+    genesis: [u8; 32],
+    clocks: HashMap<[u8; 32], OrdinaryClock>,
+    order: VecDeque<[u8; 32]>,
+}
+
+impl ClockCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            genesis: genesis_hash(),
+            clocks: Default::default(),
+            order: Default::default(),
+        }
+    }
+
+    pub fn insert(&mut self, digest: [u8; 32], clock: OrdinaryClock) {
+        if self.clocks.insert(digest, clock).is_some() {
+            return;
+        }
+        self.order.push_back(digest);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.clocks.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.clocks.clear();
+        self.order.clear()
+    }
+
+    /// The clock of `compact`, verified, and cached, if it is not cached already.
+    pub fn resolve(
+        &mut self,
+        compact: CompactClock,
+        root_cert: &[u8],
+        policy: &PcrPolicy,
+    ) -> anyhow::Result<OrdinaryClock> {
+        let CompactClock { delta, document } = compact;
+        let genesis = OrdinaryClock::default();
+        let base = if delta.base == self.genesis {
+            &genesis
+        } else {
+            let Some(base) = self.clocks.get(&delta.base) else {
+                anyhow::bail!("base clock {} not cached", hex::encode(delta.base))
+            };
+            base
+        };
+        if delta.is_empty() && delta.base != self.genesis {
+            return Ok(base.clone());
+        }
+        let plain = delta.apply(base)?;
+        let digest = plain.canonical_digest();
+        if self.clocks.contains_key(&digest) {
+            return Ok(plain);
+        }
+        let clock = NitroEnclavesClock { plain, document };
+        if !clock.plain.is_genesis() {
+            policy.check(&clock.verify_digest(&digest, root_cert)?)?
+        }
+        self.insert(digest, clock.plain.clone());
+        Ok(clock.plain)
+    }
+}
+
+/// A new merge policy, signed by the admin key of the enclave.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyUpdate {
+    pub policy: PcrPolicy,
+    /// Strictly increasing, so that an older update cannot be replayed.
+    pub seq: u64,
+    /// Compact secp256k1 ECDSA signature over the canonical digest of `(policy, seq)`, see
+    /// `common::crypto::canonical`.
+    pub signature: Vec<u8>,
+}
+
+impl PolicyUpdate {
+    fn message(policy: &PcrPolicy, seq: u64) -> secp256k1::Message {
+        secp256k1::Message::from_digest(bincode_digest(&(policy, seq)))
+    }
+
+    pub fn sign(policy: PcrPolicy, seq: u64, admin: &secp256k1::SecretKey) -> Self {
+        let signature = secp256k1::Secp256k1::signing_only()
+            .sign_ecdsa(&Self::message(&policy, seq), admin)
+            .serialize_compact()
+            .to_vec();
+        Self {
+            policy,
+            seq,
+            signature,
+        }
+    }
+
+    pub fn verify(&self, admin: &secp256k1::PublicKey) -> anyhow::Result<()> {
+        let signature = secp256k1::ecdsa::Signature::from_compact(&self.signature)?;
+        secp256k1::Secp256k1::verification_only().verify_ecdsa(
+            &Self::message(&self.policy, self.seq),
+            &signature,
+            admin,
+        )?;
+        Ok(())
+    }
+}
+
+/// The PCRs a clock enclave trusts the clocks it merges with. Without a policy, those are its
+/// own PCR0-2 exactly, so that a cluster on mixed enclave images during a rolling upgrade needs
+/// one trusting all of the images.
+///
+/// Loaded at enclave start from a JSON file, both fields optional:
+///
+/// ```json
+/// { "admin": "<hex secp256k1 public key>", "policy": { "pcrs": { "0": ["<hex>"] } } }
+/// ```
+///
+/// and replaced by `PolicyUpdate`s signed by `admin`, if any.
+#[derive(Debug, Default)]
+pub struct MergePolicy {
+    admin: Option<secp256k1::PublicKey>,
+    /// Sequence number of the last update, and the policy.
+    current: RwLock<(u64, Option<PcrPolicy>)>,
+}
+
+#[derive(Deserialize)]
+struct MergePolicyFile {
+    #[serde(default)]
+    admin: Option<String>,
+    #[serde(default)]
+    policy: Option<PcrPolicy>,
+}
+
+impl MergePolicy {
+    pub fn new(
+        policy: Option<PcrPolicy>,
+        admin: Option<secp256k1::PublicKey>,
+    ) -> anyhow::Result<Self> {
+        if let Some(policy) = &policy {
+            policy.validate()?
+        }
+        Ok(Self {
+            admin,
+            current: RwLock::new((0, policy)),
+        })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let file = serde_json::from_slice::<MergePolicyFile>(&std::fs::read(path)?)?;
+        let admin = match file.admin {
+            Some(admin) => Some(secp256k1::PublicKey::from_slice(&hex::decode(admin)?)?),
+            None => None,
+        };
+        Self::new(file.policy, admin)
+    }
+
+    /// The policy in force, given the enclave's own PCR0-2.
+    pub fn current(&self, pcrs: &[Vec<u8>; 3]) -> PcrPolicy {
+        match &self.current.read().unwrap().1 {
+            Some(policy) => policy.clone(),
+            None => PcrPolicy::exact(pcrs),
+        }
+    }
+
+    pub fn update(&self, update: PolicyUpdate) -> anyhow::Result<()> {
+        let Some(admin) = &self.admin else {
+            anyhow::bail!("no admin key, the merge policy is fixed")
+        };
+        update.verify(admin)?;
+        update.policy.validate()?;
+        let mut current = self.current.write().unwrap();
+        anyhow::ensure!(
+            update.seq > current.0,
+            "stale policy update {}, at {} already",
+            update.seq,
+            current.0
+        );
+        *current = (update.seq, Some(update.policy));
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, derive_more::AsRef, Serialize, Deserialize)]
 #[derive_where(PartialOrd, PartialEq)]
 pub struct NitroEnclavesClock {
@@ -52,32 +327,40 @@ impl Clock for NitroEnclavesClock {
 // inside enclaves image
 #[cfg(feature = "nitro-enclaves")]
 impl NitroEnclavesClock {
-    pub fn verify(
-        &self,
-    ) -> anyhow::Result<Option<aws_nitro_enclaves_nsm_api::api::AttestationDoc>> {
+    pub fn verify(&self) -> anyhow::Result<Option<AttestationDoc>> {
+        self.verify_with(&aws_nitro_enclaves_attestation::AWS_ROOT_CERT[..])
+    }
+}
+
+impl NitroEnclavesClock {
+    /// Same as `verify`, but trusts the given DER root certificate instead of the AWS one, so
+    /// clocks attested by a `MockSecureModule` can be checked as well.
+    pub fn verify_with(&self, root_cert: &[u8]) -> anyhow::Result<Option<AttestationDoc>> {
         if self.plain.is_genesis() {
             return Ok(None);
         }
-        use aws_nitro_enclaves_attestation::{AttestationProcess as _, AWS_ROOT_CERT};
-        use aws_nitro_enclaves_nsm_api::api::AttestationDoc;
-        let document = AttestationDoc::from_bytes(
+        self.verify_digest(&self.plain.canonical_digest(), root_cert)
+            .map(Some)
+    }
+
+    /// Same as `verify_with` of a clock that is not genesis, given the SHA-256 of `plain`.
+    fn verify_digest(&self, digest: &[u8; 32], root_cert: &[u8]) -> anyhow::Result<AttestationDoc> {
+        let document = verify_document(
             &self.document,
-            AWS_ROOT_CERT,
-            std::time::SystemTime::UNIX_EPOCH
-                .elapsed()
-                .unwrap()
-                .as_secs(),
+            root_cert,
+            std::time::SystemTime::UNIX_EPOCH.elapsed()?.as_secs(),
         )?;
-        use DigestHash as _;
         anyhow::ensure!(
-            document.user_data.as_ref().map(|user_data| &***user_data)
-                == Some(&self.plain.sha256().to_fixed_bytes()[..])
+            document.user_data.as_deref().map(|user_data| &user_data[..]) == Some(&digest[..])
         );
-        Ok(Some(document))
+        Ok(document)
     }
 
-    pub fn worker() -> HandleFn {
-        Arc::new(|buf, nsm, pcrs, write_sender| {
+    pub fn worker(policy: Arc<MergePolicy>) -> HandleFn {
+        let cache = Arc::new(Mutex::new(ClockCache::new(CACHED_CLOCKS)));
+        Arc::new(move |buf, nsm, pcrs, write_sender| {
+            let policy = policy.clone();
+            let cache = cache.clone();
             Box::pin(async move {
                 // IO action in tee is severe delay, just debug
                 // println!("Received buffer: {:?}", buf);
@@ -91,8 +374,7 @@ impl NitroEnclavesClock {
 
                     // 1. decode time
                     let start = Instant::now();
-                    let Update(prev, merged, id) = bincode::options()
-                        .deserialize::<Update<NitroEnclavesClock>>(&buf)?;                  
+                    let req = bincode::options().deserialize::<ClockReq<NitroEnclavesClock>>(&buf)?;
                     
                     let elapsed = start.elapsed();
                     timers.push(elapsed);
@@ -101,15 +383,47 @@ impl NitroEnclavesClock {
                     
                     // 2. verify clocks time
                     let start = Instant::now();
-                    for clock in [&prev].into_iter().chain(&merged) {
-                        if let Some(document) = clock.verify()? {
-                            for (i, pcr) in pcrs.iter().enumerate() {
-                                anyhow::ensure!(
-                                    document.pcrs.get(&i).map(|pcr| &**pcr) == Some(pcr)
+                    let (prev, merged, id) = match req {
+                        ClockReq::Update(Update(prev, merged, id)) => {
+                            let policy = policy.current(&pcrs);
+                            for (i, clock) in [&prev].into_iter().chain(&merged).enumerate() {
+                                if let Some(document) = clock.verify_with(nsm.root_certificate())? {
+                                    policy.check(&document).map_err(|err| {
+                                        anyhow::format_err!("clock {i} of update {id} rejected, {err}")
+                                    })?
+                                }
+                            }
+                            let merged = merged.into_iter().map(|clock| clock.plain).collect();
+                            (prev.plain, merged, id)
+                        }
+                        ClockReq::Delta(DeltaUpdate { prev, merged, id }) => {
+                            let policy = policy.current(&pcrs);
+                            let mut cache = cache.lock().unwrap();
+                            let mut resolved = Vec::with_capacity(1 + merged.len());
+                            for (i, clock) in [prev].into_iter().chain(merged).enumerate() {
+                                resolved.push(
+                                    cache
+                                        .resolve(clock, nsm.root_certificate(), &policy)
+                                        .map_err(|err| {
+                                            anyhow::format_err!("clock {i} of update {id} rejected, {err}")
+                                        })?,
                                 )
                             }
+                            let prev = resolved.remove(0);
+                            (prev, resolved, id)
                         }
-                    }
+                        ClockReq::Policy(update) => {
+                            let seq = update.seq;
+                            policy.update(update)?;
+                            // verified under the previous policy
+                            cache.lock().unwrap().clear();
+                            info!("merge policy updated to {seq}");
+                            let buf = bincode::options()
+                                .serialize(&ClockResp::<NitroEnclavesClock>::Policy(seq))?;
+                            write_sender.send(buf)?;
+                            return anyhow::Ok(());
+                        }
+                    };
 
                     let elapsed = start.elapsed();
                     timers.push(elapsed);
@@ -118,9 +432,7 @@ impl NitroEnclavesClock {
 
                     // 3. update clock time
                     let start = Instant::now();
-                    let plain = prev
-                        .plain
-                        .update(merged.iter().map(|clock| &clock.plain), id);
+                    let plain = prev.update(merged.iter(), id);
                     
                     let elapsed = start.elapsed();
                     timers.push(elapsed);
@@ -132,8 +444,9 @@ impl NitroEnclavesClock {
                     // let key_lens = plain.0.len();
                     // relies on the fact that different clocks always hash into different
                     // digests, hopefully true
-                    let user_data = plain.sha256().to_fixed_bytes().to_vec();
-                    let document = nsm.process_attestation(user_data)?;
+                    let digest = plain.canonical_digest();
+                    let document = nsm.process_attestation(digest.to_vec())?;
+                    cache.lock().unwrap().insert(digest, plain.clone());
                     let updated = NitroEnclavesClock {
                         plain,
                         document: Payload(document),
@@ -149,77 +462,194 @@ impl NitroEnclavesClock {
                     // println!("Total once time: {:?}, key is {:?}", elapsed, key_lens);
                     // let _ = io::stdout().flush();
                     
-                    let buf = bincode::options().serialize(&(id, updated, timers))?;
+                    let buf = bincode::options()
+                        .serialize(&ClockResp::Updated((id, updated, timers)))?;
                     write_sender.send(buf)?;
                     Ok(())
                 }
                 .await
                 {
-                    warn!("{err}")
+                    warn!("{err}");
+                    // for the client not to wait for a reply in vain
+                    let rejected = ClockResp::<NitroEnclavesClock>::Rejected(err.to_string());
+                    if let Ok(buf) = bincode::options().serialize(&rejected) {
+                        let _ = write_sender.send(buf);
+                    }
                 }
                 Ok(())
             })
         })
     }
+}
 
-    pub async fn run(port: u32) -> anyhow::Result<()> {
-        let handler: HandleFn = NitroEnclavesClock::worker();
+#[cfg(feature = "nitro-enclaves")]
+impl NitroEnclavesClock {
+    pub async fn run(addr: Address, policy: MergePolicy) -> anyhow::Result<()> {
+        let handler: HandleFn = NitroEnclavesClock::worker(Arc::new(policy));
 
-        NitroSecure::run(port, handler).await
+        NitroSecure::run(addr, handler).await
     }
 }
 
+#[cfg(not(feature = "nitro-enclaves"))]
+impl NitroEnclavesClock {
+    /// Outside of an enclave, clocks are attested by a throwaway `MockSecureModule`.
+    pub async fn run(addr: Address, policy: MergePolicy) -> anyhow::Result<()> {
+        warn!("nitro-enclaves feature is disabled, attesting with a mock secure module");
+        let nsm = Arc::new(common::mock_secure::MockSecureModule::new()?);
+        let handler = NitroEnclavesClock::worker(Arc::new(policy));
+        common::nitro_secure::serve(nsm, addr, handler).await
+    }
+}
 
 pub async fn nitro_enclaves_portal_session(
-    cid: u32,
-    port: u32,
-    mut events: UnboundedReceiver<Update<NitroEnclavesClock>>,
-    sender: UnboundedSender<UpdateOk<NitroEnclavesClock>>,
+    addr: Address,
+    events: UnboundedReceiver<ClockReq<NitroEnclavesClock>>,
+    sender: UnboundedSender<ClockResp<NitroEnclavesClock>>,
 ) -> anyhow::Result<()> {
-    use std::os::fd::AsRawFd;
+    transport::session(transport::connect(&addr).await?, events, sender).await
+}
 
-    use bincode::Options;
-    use nix::sys::socket::{connect, socket, AddressFamily, SockFlag, SockType, VsockAddr};
-    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
-
-    let fd = socket(
-        AddressFamily::Vsock,
-        SockType::Stream,
-        SockFlag::empty(),
-        None,
-    )?;
-    // this one is blocking, but should be instant, hopefully
-    {
-        let _span = tracing::debug_span!("connect").entered();
-        connect(fd.as_raw_fd(), &VsockAddr::new(cid, port))?
-    }
-    let stream = std::os::unix::net::UnixStream::from(fd);
-    stream.set_nonblocking(true)?;
-    let stream = tokio::net::UnixStream::from_std(stream)?;
-    let (mut read_half, mut write_half) = stream.into_split();
-    let write_session = tokio::spawn(async move {
-        while let Some(update) = events.recv().await {
-            let buf = bincode::options().serialize(&update)?;
-            write_half.write_u64_le(buf.len() as _).await?;
-            write_half.write_all(&buf).await?
-        }
-        anyhow::Ok(())
-    });
-    let read_session = tokio::spawn(async move {
-        loop {
-            let len = read_half.read_u64_le().await?;
-            let mut buf = vec![0; len as _];
-            read_half.read_exact(&mut buf).await?;
-            sender.send(bincode::options().deserialize(&buf)?)?
+/// Ticks the clock of a node in the clock enclave, one update at a time, so that every update
+/// is on top of the previous one.
+pub struct ClockClient {
+    key: KeyId,
+    sender: UnboundedSender<ClockReq<NitroEnclavesClock>>,
+    timeout: Duration,
+    /// The latest clock, and the replies of the enclave.
+    state: tokio::sync::Mutex<(
+        NitroEnclavesClock,
+        UnboundedReceiver<ClockResp<NitroEnclavesClock>>,
+    )>,
+}
+
+impl ClockClient {
+    pub fn new(
+        key: KeyId,
+        clock: NitroEnclavesClock,
+        sender: UnboundedSender<ClockReq<NitroEnclavesClock>>,
+        replies: UnboundedReceiver<ClockResp<NitroEnclavesClock>>,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            key,
+            sender,
+            timeout,
+            state: tokio::sync::Mutex::new((clock, replies)),
         }
-        #[allow(unreachable_code)] // for type hinting
-        anyhow::Ok(())
-    });
-    tokio::select! {
-        result = write_session => return result?,
-        result = read_session => result??
     }
-    anyhow::bail!("unreachable")
+
+    /// Starts from `clock`, the latest one of the node, or genesis.
+    pub async fn connect(
+        addr: Address,
+        key: KeyId,
+        clock: NitroEnclavesClock,
+        timeout: Duration,
+    ) -> anyhow::Result<Self> {
+        let stream = transport::connect(&addr).await?;
+        let (sender, events) = tokio::sync::mpsc::unbounded_channel();
+        let (replies_sender, replies) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            if let Err(err) = transport::session(stream, events, replies_sender).await {
+                warn!("clock session closed, {err}")
+            }
+        });
+        Ok(Self::new(key, clock, sender, replies, timeout))
+    }
+
+    pub fn key(&self) -> KeyId {
+        self.key
+    }
+
+    pub async fn current(&self) -> NitroEnclavesClock {
+        self.state.lock().await.0.clone()
+    }
+
+    /// Merges `merged` into the clock and ticks it once, returns the attested result. The clock
+    /// is left as is if the enclave rejects the update, or does not reply within the timeout.
+    ///
+    /// The update is sent as a `DeltaUpdate` from the clock the enclave replied last, and again
+    /// as a full `Update` if the enclave rejects it, e.g. after a restart lost its cache.
+    pub async fn update(
+        &self,
+        merged: Vec<NitroEnclavesClock>,
+    ) -> anyhow::Result<NitroEnclavesClock> {
+        let mut state = self.state.lock().await;
+        let (clock, replies) = &mut *state;
+        let expected = clock
+            .plain
+            .update(merged.iter().map(|clock| &clock.plain), self.key);
+        let delta = DeltaUpdate {
+            prev: CompactClock::cached(&clock.plain),
+            merged: merged
+                .iter()
+                .map(|merged| CompactClock::delta(merged, &clock.plain))
+                .collect(),
+            id: self.key,
+        };
+        let updated = match self.request(delta.into(), &expected, replies).await? {
+            Ok(updated) => updated,
+            Err(err) => {
+                debug!("delta update rejected, sending it in full, {err}");
+                let update = Update(clock.clone(), merged, self.key).into();
+                self.request(update, &expected, replies)
+                    .await?
+                    .map_err(|err| anyhow::format_err!("clock update rejected, {err}"))?
+            }
+        };
+        *clock = updated.clone();
+        Ok(updated)
+    }
+
+    /// Sends an update and waits for the clock it is `expected` to result in, or its rejection.
+    async fn request(
+        &self,
+        req: ClockReq<NitroEnclavesClock>,
+        expected: &OrdinaryClock,
+        replies: &mut UnboundedReceiver<ClockResp<NitroEnclavesClock>>,
+    ) -> anyhow::Result<Result<NitroEnclavesClock, String>> {
+        self.sender
+            .send(req)
+            .map_err(|_| anyhow::format_err!("clock session closed"))?;
+        tokio::time::timeout(self.timeout, async {
+            loop {
+                match replies.recv().await {
+                    None => anyhow::bail!("clock session closed"),
+                    // updates are sent one at a time, the rejection is of this one unless the
+                    // previous one timed out
+                    Some(ClockResp::Rejected(err)) => return Ok(Err(err)),
+                    // otherwise the late reply of an update that timed out
+                    Some(ClockResp::Updated((_, updated, _))) if updated.plain == *expected => {
+                        return Ok(Ok(updated))
+                    }
+                    Some(ClockResp::Updated(_) | ClockResp::Policy(_)) => {}
+                }
+            }
+        })
+        .await
+        .map_err(|_| anyhow::format_err!("clock update timed out"))?
+    }
+}
+
+/// What the clocks of other nodes are trusted by outside of the enclaves, e.g. by an operator
+/// before merging them: the root certificate (DER) of their attestations, and the PCRs of the
+/// enclaves that attested them. Without the PCRs, no clock is trusted but genesis.
+#[derive(Debug, Clone)]
+pub struct ClockTrust {
+    pub root_cert: Vec<u8>,
+    pub policy: Option<PcrPolicy>,
+}
+
+impl<M: Send + Sync + 'static> Verify<ClockTrust> for Clocked<M, NitroEnclavesClock> {
+    fn verify_clock(&self, _: usize, trust: &ClockTrust) -> anyhow::Result<()> {
+        let Some(document) = self.clock.verify_with(&trust.root_cert)? else {
+            return Ok(());
+        };
+        let Some(policy) = &trust.policy else {
+            anyhow::bail!("no PCR policy to trust the clock by")
+        };
+        policy.check(&document)
+    }
 }
 
 #[cfg(feature = "nitro-enclaves")]
@@ -235,3 +665,336 @@ pub mod impls {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bincode::Options;
+    use common::{
+        mock_secure::{MockSecureModule, PCR_LENGTH},
+        nitro_secure::{HandleFn, SecureModule},
+        ordinary_clock::OrdinaryClock,
+        pcr_policy::PcrPolicy,
+    };
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::{
+        ClockClient, ClockReq, ClockResp, ClockTrust, CompactClock, DeltaUpdate, MergePolicy,
+        NitroEnclavesClock, PolicyUpdate, Update, UpdateOk,
+    };
+    use crate::{Clocked, Verify as _};
+
+    async fn request(
+        nsm: Arc<dyn SecureModule>,
+        policy: &Arc<MergePolicy>,
+        req: ClockReq<NitroEnclavesClock>,
+    ) -> anyhow::Result<Option<UpdateOk<NitroEnclavesClock>>> {
+        request_to(&NitroEnclavesClock::worker(policy.clone()), nsm, req).await
+    }
+
+    /// Same as `request`, to a worker that keeps its cache of clocks. None if the request is
+    /// rejected, or not replied to.
+    async fn request_to(
+        worker: &HandleFn,
+        nsm: Arc<dyn SecureModule>,
+        req: ClockReq<NitroEnclavesClock>,
+    ) -> anyhow::Result<Option<UpdateOk<NitroEnclavesClock>>> {
+        Ok(match reply_of(worker, nsm, req).await? {
+            Some(ClockResp::Updated(update_ok)) => Some(update_ok),
+            Some(ClockResp::Policy(_) | ClockResp::Rejected(_)) | None => None,
+        })
+    }
+
+    async fn reply_of(
+        worker: &HandleFn,
+        nsm: Arc<dyn SecureModule>,
+        req: ClockReq<NitroEnclavesClock>,
+    ) -> anyhow::Result<Option<ClockResp<NitroEnclavesClock>>> {
+        let pcrs = [0, 1, 2].map(|i| nsm.describe_pcr(i).unwrap());
+        let (write_sender, mut write_receiver) = unbounded_channel();
+        let buf = bincode::options().serialize(&req)?;
+        worker(buf, nsm, pcrs, write_sender).await?;
+        Ok(match write_receiver.try_recv() {
+            Ok(buf) => Some(bincode::options().deserialize(&buf)?),
+            Err(_) => None,
+        })
+    }
+
+    async fn update(
+        nsm: Arc<dyn SecureModule>,
+        update: Update<NitroEnclavesClock>,
+    ) -> anyhow::Result<Option<UpdateOk<NitroEnclavesClock>>> {
+        request(nsm, &Default::default(), update.into()).await
+    }
+
+    fn pcrs(base: u8) -> impl Iterator<Item = (u16, Vec<u8>)> {
+        (0..3).map(move |i| (i, vec![base + i as u8; PCR_LENGTH]))
+    }
+    #[tokio::test]
+    async fn update_and_verify() -> anyhow::Result<()> {
+        let nsm = Arc::new(MockSecureModule::new()?);
+        let genesis = NitroEnclavesClock::try_from(OrdinaryClock::default())?;
+        let Some((id, clock, _)) = update(nsm.clone(), Update(genesis, vec![], 1)).await? else {
+            anyhow::bail!("missing UpdateOk")
+        };
+        assert_eq!(id, 1);
+        anyhow::ensure!(clock.verify_with(nsm.root_certificate())?.is_some());
+
+        let Some((_, merged, _)) =
+            update(nsm.clone(), Update(clock.clone(), vec![clock.clone()], 2)).await?
+        else {
+            anyhow::bail!("missing UpdateOk")
+        };
+        assert!(merged > clock);
+        anyhow::ensure!(merged.verify_with(nsm.root_certificate())?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_foreign_clock() -> anyhow::Result<()> {
+        let nsm = Arc::new(MockSecureModule::new()?);
+        let genesis = NitroEnclavesClock::try_from(OrdinaryClock::default())?;
+        let Some((_, clock, _)) = update(nsm.clone(), Update(genesis, vec![], 1)).await? else {
+            anyhow::bail!("missing UpdateOk")
+        };
+
+        // same measurements, untrusted CA
+        let other = Arc::new(MockSecureModule::new()?);
+        let worker = NitroEnclavesClock::worker(Default::default());
+        let rejected = reply_of(&worker, other.clone(), Update(clock.clone(), vec![], 1).into());
+        assert!(matches!(rejected.await?, Some(ClockResp::Rejected(_))));
+        assert!(update(other, Update(clock.clone(), vec![], 1)).await?.is_none());
+        // clock that does not match its attestation
+        let mut tampered = clock.clone();
+        tampered.plain.0.insert(1, 42);
+        assert!(update(nsm.clone(), Update(tampered, vec![], 1)).await?.is_none());
+        // trusted CA, different enclave image
+        let upgraded = Arc::new(MockSecureModule::with_pcrs(pcrs(1))?);
+        let Some((_, upgraded_clock, _)) = update(
+            upgraded.clone(),
+            Update(NitroEnclavesClock::try_from(OrdinaryClock::default())?, vec![], 2),
+        )
+        .await?
+        else {
+            anyhow::bail!("missing UpdateOk")
+        };
+        assert!(update(nsm, Update(upgraded_clock, vec![], 1)).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rolling_upgrade() -> anyhow::Result<()> {
+        let policy_of = |images: &[u8]| {
+            let mut policy = PcrPolicy::default();
+            for (index, value) in images.iter().flat_map(|base| pcrs(*base)) {
+                policy
+                    .pcrs
+                    .entry(index as _)
+                    .or_default()
+                    .push(hex::encode(value))
+            }
+            policy
+        };
+        let secp = secp256k1::Secp256k1::new();
+        let (admin, admin_public) = secp.generate_keypair(&mut rand::thread_rng());
+        // trusts the previous image only, not the one of `nsm`
+        let policy = Arc::new(MergePolicy::new(Some(policy_of(&[2])), Some(admin_public))?);
+        let nsm = Arc::new(MockSecureModule::with_pcrs(pcrs(0))?);
+
+        let genesis = NitroEnclavesClock::try_from(OrdinaryClock::default())?;
+        let genesis = Update(genesis, vec![], 1).into();
+        let Some((_, clock, _)) = request(nsm.clone(), &policy, genesis).await? else {
+            anyhow::bail!("missing UpdateOk")
+        };
+        let merge = || ClockReq::from(Update(clock.clone(), vec![clock.clone()], 2));
+        assert!(request(nsm.clone(), &policy, merge()).await?.is_none());
+
+        let signed = PolicyUpdate::sign(policy_of(&[2, 0]), 1, &admin);
+        let forged = PolicyUpdate {
+            seq: 2,
+            ..signed.clone()
+        };
+        assert!(policy.update(forged).is_err());
+        assert!(MergePolicy::default().update(signed.clone()).is_err());
+        let worker = NitroEnclavesClock::worker(policy.clone());
+        let applied = reply_of(&worker, nsm.clone(), ClockReq::Policy(signed.clone())).await?;
+        assert!(matches!(applied, Some(ClockResp::Policy(1))));
+        assert_eq!(policy.current(&Default::default()), policy_of(&[2, 0]));
+        assert!(request(nsm.clone(), &policy, merge()).await?.is_some());
+
+        // replayed
+        let replayed = reply_of(&worker, nsm.clone(), ClockReq::Policy(signed.clone())).await?;
+        assert!(matches!(replayed, Some(ClockResp::Rejected(_))));
+        assert!(policy.update(signed).is_err());
+        // the upgrade is done, the previous image is not trusted anymore
+        policy.update(PolicyUpdate::sign(policy_of(&[0]), 2, &admin))?;
+        assert!(request(nsm, &policy, merge()).await?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delta_update() -> anyhow::Result<()> {
+        let nsm = Arc::new(MockSecureModule::new()?);
+        let worker = NitroEnclavesClock::worker(Default::default());
+        let genesis = NitroEnclavesClock::try_from(OrdinaryClock::default())?;
+        let Some((_, first, _)) =
+            request_to(&worker, nsm.clone(), Update(genesis.clone(), vec![], 1).into()).await?
+        else {
+            anyhow::bail!("missing UpdateOk")
+        };
+        // attested by the enclave before, and not by this worker
+        let Some((_, other, _)) = update(nsm.clone(), Update(genesis, vec![], 2)).await? else {
+            anyhow::bail!("missing UpdateOk")
+        };
+
+        let delta = |merged| DeltaUpdate {
+            prev: CompactClock::cached(&first.plain),
+            merged,
+            id: 1,
+        };
+        let full = CompactClock::full(&other);
+        let Some((_, merged, _)) =
+            request_to(&worker, nsm.clone(), delta(vec![full.clone()]).into()).await?
+        else {
+            anyhow::bail!("missing UpdateOk")
+        };
+        assert!(merged > first && merged > other);
+        assert_eq!(merged.plain.get(&1), Some(&2));
+        anyhow::ensure!(merged.verify_with(nsm.root_certificate())?.is_some());
+        // a delta from the clock just replied, without its attestation
+        let ticked = DeltaUpdate {
+            prev: CompactClock::cached(&merged.plain),
+            merged: vec![],
+            id: 1,
+        };
+        let Some((_, ticked, _)) = request_to(&worker, nsm.clone(), ticked.into()).await? else {
+            anyhow::bail!("missing UpdateOk")
+        };
+        assert_eq!(ticked.plain.get(&1), Some(&3));
+
+        // entries that do not match the attestation
+        let mut tampered = other.clone();
+        tampered.plain.0.insert(2, 42);
+        let tampered = CompactClock::delta(&tampered, &OrdinaryClock::default());
+        assert!(request_to(&worker, nsm.clone(), delta(vec![tampered]).into()).await?.is_none());
+        // a base the enclave does not have
+        let worker = NitroEnclavesClock::worker(Default::default());
+        assert!(request_to(&worker, nsm.clone(), delta(vec![full]).into()).await?.is_none());
+        Ok(())
+    }
+
+    /// A client of an enclave served by `nsm`, in process.
+    fn client_of(nsm: Arc<dyn SecureModule>, key: u64) -> anyhow::Result<ClockClient> {
+        let genesis = NitroEnclavesClock::try_from(OrdinaryClock::default())?;
+        Ok(client_from(nsm, key, genesis, Default::default()))
+    }
+
+    /// Same as `client_of`, starting from `clock`, with the kinds of the requests the enclave got
+    /// pushed to `kinds`.
+    fn client_from(
+        nsm: Arc<dyn SecureModule>,
+        key: u64,
+        clock: NitroEnclavesClock,
+        kinds: Arc<std::sync::Mutex<Vec<&'static str>>>,
+    ) -> ClockClient {
+        let (sender, mut events) = unbounded_channel::<ClockReq<NitroEnclavesClock>>();
+        let (replies_sender, replies) = unbounded_channel();
+        tokio::spawn(async move {
+            let worker = NitroEnclavesClock::worker(Default::default());
+            while let Some(req) = events.recv().await {
+                kinds.lock().unwrap().push(match req {
+                    ClockReq::Update(_) => "update",
+                    ClockReq::Delta(_) => "delta",
+                    ClockReq::Policy(_) => "policy",
+                });
+                if let Some(reply) = reply_of(&worker, nsm.clone(), req).await? {
+                    replies_sender.send(reply)?
+                }
+            }
+            anyhow::Ok(())
+        });
+        // longer than any of the tests takes
+        ClockClient::new(key, clock, sender, replies, std::time::Duration::from_secs(60))
+    }
+
+    #[tokio::test]
+    async fn client_ticks_in_order() -> anyhow::Result<()> {
+        let nsm = Arc::new(MockSecureModule::new()?);
+        let client = client_of(nsm.clone(), 7)?;
+        let first = client.update(vec![]).await?;
+        let second = client.update(vec![]).await?;
+        assert_eq!(second.plain.get(&7), Some(&2));
+        assert!(second > first);
+        anyhow::ensure!(second.verify_with(nsm.root_certificate())?.is_some());
+
+        // ticked by another node of the same enclave image
+        let other = client_of(nsm.clone(), 8)?.update(vec![]).await?;
+        let merged = client.update(vec![other.clone()]).await?;
+        assert!(merged > other && merged > second);
+        assert_eq!(client.current().await, merged);
+
+        // attested by an untrusted CA, rejected at once, the clock is left as is; of a key of its
+        // own, as the same clock attested by a trusted one is cached, and trusted by digest
+        let foreign = Arc::new(MockSecureModule::new()?);
+        let foreign = client_of(foreign, 9)?.update(vec![]).await?;
+        let rejected = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            client.update(vec![foreign]),
+        );
+        assert!(rejected.await?.is_err());
+        assert_eq!(client.current().await, merged);
+        assert!(client.update(vec![]).await? > merged);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_sends_deltas() -> anyhow::Result<()> {
+        let nsm = Arc::new(MockSecureModule::new()?);
+        let kinds = Arc::<std::sync::Mutex<Vec<_>>>::default();
+        let genesis = NitroEnclavesClock::try_from(OrdinaryClock::default())?;
+        let client = client_from(nsm.clone(), 7, genesis, kinds.clone());
+        client.update(vec![]).await?;
+        let ticked = client.update(vec![]).await?;
+        assert_eq!(*kinds.lock().unwrap(), ["delta", "delta"]);
+
+        // an enclave that does not have the clock, e.g. restarted since
+        kinds.lock().unwrap().clear();
+        let client = client_from(nsm.clone(), 7, ticked, kinds.clone());
+        let restarted = client.update(vec![]).await?;
+        assert_eq!(restarted.plain.get(&7), Some(&3));
+        client.update(vec![]).await?;
+        assert_eq!(*kinds.lock().unwrap(), ["delta", "update", "delta"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn trust_peer_clock() -> anyhow::Result<()> {
+        let nsm = Arc::new(MockSecureModule::new()?);
+        let clock = client_of(nsm.clone(), 8)?.update(vec![]).await?;
+        let clocked = |clock: &NitroEnclavesClock| Clocked {
+            clock: clock.clone(),
+            inner: (),
+        };
+        let mut trust = ClockTrust {
+            root_cert: nsm.root_certificate().to_vec(),
+            policy: None,
+        };
+        // no enclave image trusted yet
+        assert!(clocked(&clock).verify_clock(0, &trust).is_err());
+        let genesis = NitroEnclavesClock::try_from(OrdinaryClock::default())?;
+        clocked(&genesis).verify_clock(0, &trust)?;
+
+        let own = [0, 1, 2].map(|i| nsm.describe_pcr(i).unwrap());
+        trust.policy = Some(PcrPolicy::exact(&own));
+        clocked(&clock).verify_clock(0, &trust)?;
+        let mut tampered = clock.clone();
+        tampered.plain.0.insert(8, 42);
+        assert!(clocked(&tampered).verify_clock(0, &trust).is_err());
+        // another enclave image
+        let other: Vec<_> = pcrs(1).map(|(_, value)| value).collect();
+        trust.policy = Some(PcrPolicy::exact(&other.try_into().unwrap()));
+        assert!(clocked(&clock).verify_clock(0, &trust).is_err());
+        Ok(())
+    }
+}